@@ -1,12 +1,21 @@
-use rand::{Rng, SeedableRng};
+use rand::Rng;
 use std::{
-    collections::BTreeMap,
-    io::{Read, Write},
+    cell::Cell,
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    io::{self, Cursor, Read, Write},
     net::{Shutdown, TcpStream},
-    time::Duration,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 use crate::event::{AppEvent, Event, EventHandler};
+use crate::e2e::{self, Identity, KeyCheck};
+use crate::session::{self, SavedSession};
+use crate::sshkey;
+use chrono::{Local, NaiveDate};
+use ed25519_dalek::SigningKey;
 use log::{error, info};
+use sha2::{Digest, Sha256};
 use ratatui::{
     DefaultTerminal,
     crossterm::event::{KeyCode, KeyEvent, KeyModifiers},
@@ -14,52 +23,405 @@ use ratatui::{
 use socket2::{Socket, Domain, Type};
 use uuid::Uuid;
 
-use::common_bork::MessageType;
+use::common_bork::{ALL_ROOMS, Extension, ExtensionRegistry, ExtendedMessageType, Frame, FrameError, HistoryEntry,
+    LOBBY_ROOM_ID, MessageFlags, NoticeSeverity, PresenceStatus, ReactionAction, ReactionCount, RoomRole, SearchHit, read_frame};
+use::common_bork::extension::{Ping, Pong};
+use::common_bork::frame::{MAX_CHUNK_LEN, MAX_HISTORY_BATCH, PUBLIC_KEY_LEN, validate_code, validate_email, validate_file_name,
+    validate_message, validate_password, validate_query, validate_reaction, validate_room_name, validate_status, validate_topic,
+    validate_username};
 
 
 const SERVER_PORT: u16 = 6556;
-//const SERVER_ADDRESS:&'static str = "164.90.146.27";
-const SERVER_ADDRESS: &'static str = "0.0.0.0";
+//const SERVER_ADDRESS:&str = "164.90.146.27";
+const SERVER_ADDRESS: &str = "0.0.0.0";
+// how long to wait before reconnecting when the server didn't say, and the most we'll back off to
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+// how often to measure round-trip time, when the server understands PING
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+// while the user keeps typing, tell the server again this often; others forget us if we go quiet
+// for TYPING_TIMEOUT
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+// what the (b)ork key reacts with
+const BORK: &str = ":bork:";
+// how long without a keystroke before we go away by ourselves; BORK_AWAY_AFTER overrides it, 0 turns it off
+const AWAY_AFTER: Duration = Duration::from_secs(300);
+// the server decides how big an upload may be; this only stops us reading something huge into memory
+const MAX_UPLOAD_SIZE: u64 = 64 * 1024 * 1024;
+// hits per SEARCHRESULTS page
+const SEARCH_PAGE: u16 = 50;
+// how many mentions of us the Mentions pane remembers
+const MENTIONS_KEPT: usize = 50;
+
+/// Whether keystrokes drive the app or go into the message box
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputMode {
+    #[default]
+    Normal,
+    Editing,
+}
+
+/// How we get the user's attention when someone mentions them; set by BORK_NOTIFY
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Notify {
+    #[default]
+    Bell,
+    Desktop, // an OSC 9 notification, for terminals that pass them on to the desktop
+    Off,
+}
+
+/// What a row in the chat pane is, which decides how it's drawn
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LineKind {
+    #[default]
+    Chat,
+    Notice(u8), // NoticeSeverity
+    Direct, // an end-to-end encrypted direct message; username reads "sender → recipient"
+}
+
+/// One message in the chat pane
+#[derive(Clone, Debug, Default)]
+pub struct ChatLine {
+    pub kind:       LineKind,
+    pub message_id: Option<u64>, // assigned by the server; None for notices and unacknowledged messages
+    pub timestamp:  Option<u64>, // seconds since the Unix epoch, UTC
+    pub nonce:      Option<u64>, // set on our own messages until the server's SENTACK arrives
+    pub edited:     bool,
+    pub deleted:    bool,        // text is empty; drawn as a placeholder
+    pub reactions:  Vec<ReactionCount>,
+    pub own_reactions: Vec<String>, // the reactions we've added, as far as this session knows
+    pub reply_count: u32,
+    pub mentions_me: bool,
+    pub username:   String,
+    pub text:       String,
+    pub attachment: Option<Attachment>,
+}
+
+/// A file carried by a message
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub file_name:  String,
+    pub size:       u64,
+    pub sha256:     [u8; 32],
+}
+
+/// A room we're a member of, what it's about, and how far behind we are in it
+#[derive(Clone, Debug, Default)]
+pub struct Room {
+    pub name:       String,
+    pub last_read:  u64, // message_id of the newest message we've seen
+    pub unread:     u32,
+    pub is_public:  bool,
+    pub topic:      String,
+    pub description: String,
+    pub role:       Option<u8>, // our RoomRole, once the server has said
+}
+
+/// The search overlay: what's being looked for, and what's turned up so far
+#[derive(Debug, Default)]
+pub struct SearchView {
+    pub query:      String, // as typed, filters and all
+    pub searched:   String, // the query the hits are for
+    pub search_id:  u64,    // of the newest SEARCH; answers to any other are stale
+    pub hits:       Vec<SearchHit>,
+    pub selected:   usize,
+    pub pending:    bool,
+    pub exhausted:  bool,   // the last page came back short
+    pub error:      Option<String>, // what's wrong with the query, if anything
+}
+
+/// A message that mentions us, as the Mentions pane lists it
+#[derive(Clone, Debug)]
+pub struct Mention {
+    pub message_id: u64,
+    pub parent_id:  u64, // 0 unless it's a reply
+    pub timestamp:  Option<u64>,
+    pub username:   String,
+    pub text:       String,
+}
+
+/// Tab completion of an @name in the input box, while the user keeps pressing Tab
+#[derive(Debug)]
+pub struct Completion {
+    pub at:         usize, // where the name starts in the input, just after the @
+    pub candidates: Vec<String>,
+    pub index:      usize,
+}
+
+/// Messages the server held for us while we were offline, shown over the chat pane until dismissed
+#[derive(Debug, Default)]
+pub struct AwayView {
+    pub entries:    Vec<SearchHit>, // oldest first
+    pub selected:   usize,
+}
+
+/// A file we asked the server for, arriving a CHUNK at a time
+#[derive(Debug)]
+pub struct Download {
+    pub dir:        PathBuf,    // where it gets saved
+    pub offered:    Option<Attachment>, // set once the server's OFFER arrives
+    pub data:       Vec<u8>,
+}
+
+impl ChatLine {
+    pub fn notice(severity: u8, text: String) -> Self {
+        Self { kind: LineKind::Notice(severity), text, ..Default::default() }
+    }
+
+    /// Sent, but not yet acknowledged by the server
+    pub fn is_pending(&self) -> bool {
+        self.nonce.is_some()
+    }
+}
+
+impl From<HistoryEntry> for ChatLine {
+    fn from(entry: HistoryEntry) -> Self {
+        Self {
+            message_id: Some(entry.message_id),
+            timestamp: Some(entry.timestamp),
+            edited: entry.flags & MessageFlags::EDITED != 0,
+            deleted: entry.flags & MessageFlags::DELETED != 0,
+            reply_count: entry.reply_count,
+            username: entry.username,
+            text: entry.message_text,
+            ..Default::default()
+        }
+    }
+}
+
+/// The thread open in the side panel: replies to one message in the chat pane
+#[derive(Clone, Debug)]
+pub struct ThreadView {
+    pub parent_id:  u64,
+    pub replies:    Vec<ChatLine>,
+}
+
+/// What another user (or we) last said about their availability
+#[derive(Clone, Debug, Default)]
+pub struct Presence {
+    pub status: u8, // PresenceStatus
+    pub text:   String,
+}
 
 /// Application.
 #[derive(Debug)]
 pub struct App {
     // TODO: we can probably re-use the User struct from the server
     pub active_users:       BTreeMap<String, Uuid>,
+    pub arrived:            Option<Mention>, // the last message from someone else to arrive as it was sent
+    pub auto_away:          bool,      // we went away because of idleness, and come back on the next key
+    pub away:               Option<AwayView>,
+    pub away_after:         Option<Duration>,
+    pub changed_keys:       BTreeMap<String, (Uuid, [u8; PUBLIC_KEY_LEN])>, // keys waiting on /trust, by username
+    pub chat_height:        Cell<u16>, // rows in the chat pane at the last draw
+    pub completion:         Option<Completion>,
     pub connected:          bool,
+    pub divider_after:      Option<u64>, // the "new messages" line goes after this message_id
+    pub downloads:          BTreeMap<u64, Download>, // by message_id
+    pub editing_message:    Option<u64>, // message_id the input box will replace, rather than send anew
     pub events:             EventHandler,
-    pub inbuffer:           Vec<u8>,   //TODO: should be a list of rows to use as message buffer
+    pub extensions:         ExtensionRegistry<App>,
+    pub fingerprint_wanted: BTreeSet<String>, // users whose key to show once the server sends it
+    pub history_exhausted:  bool,
+    pub history_pending:    bool,
+    pub identity:           Option<Identity>, // our key pair for direct messages, once we've made one
+    pub inbuffer:           Vec<u8>,   // bytes received but not yet decoded into a frame
+    pub input:              String,
+    pub input_mode:         InputMode,
+    pub jump_to:            Option<u64>, // message_id to scroll to once history reaches back that far
+    pub last_input:         Instant,   // the user's last keystroke
+    pub last_ping:          Option<(u64, Instant)>, // nonce and send time of the newest PING
+    pub latency:            Option<Duration>,
+    pub login_key:          Option<SigningKey>, // what a KEYLOGIN will sign its CHALLENGE with
+    pub mentions:           Vec<Mention>, // of us, oldest first
+    pub notify:             Notify,
+    pub peer_keys:          BTreeMap<String, (Uuid, [u8; PUBLIC_KEY_LEN])>, // identity keys by username
+    pub pending_dms:        BTreeMap<String, Vec<String>>, // direct messages waiting on a peer's key
+    pub presence:           BTreeMap<Uuid, Presence>, // everyone online, ourselves included
+    pub reconnect_at:       Option<Instant>, // set after the server announced a shutdown
+    pub reconnect_delay:    Duration,
+    pub room_id:            u64,
+    pub room_wanted:        Option<String>, // a room we've asked to create or join, to show once we're in it
+    pub rooms:              BTreeMap<u64, Room>,
     pub running:            bool,
+    pub scroll_offset:      u16,       // how many rows the chat pane is scrolled up from the bottom
+    pub scrollback:         Vec<ChatLine>,
+    pub search:             Option<SearchView>,
+    pub selected:           Option<u64>, // message_id that reactions and threads apply to; None means the newest
     pub server_port:        u16,
     pub server_address:     String,
+    pub session:            Option<SavedSession>, // what we log back in with, once the server gave us one
+    pub server_extensions:  Vec<u64>,  // extended types the server advertised in CAPABILITIES
     pub server_major_ver:   u16,
     pub server_minor_ver:   u16,
     pub server_subminor_ver:u16,
+    pub status:             Presence, // ours, as we want it; sent again after reconnecting
     pub tcpstream:          TcpStream,
+    pub thread:             Option<ThreadView>,
+    pub typing_sent:        Option<Instant>, // when we last told the server we're typing
+    pub typing_users:       BTreeMap<Uuid, Instant>, // who else is typing, and when to stop believing it
     pub username:           String,
     pub user_uuid:          Uuid,
+    pub welcome:            String,
 }
 
 impl Default for App {
     fn default() -> Self {
         Self {
             active_users: BTreeMap::new(),
+            arrived: None,
+            auto_away: false,
+            away: None,
+            away_after: away_after(),
+            changed_keys: BTreeMap::new(),
+            chat_height: Cell::new(0),
+            completion: None,
             connected: false,
+            divider_after: None,
+            downloads: BTreeMap::new(),
+            editing_message: None,
             events: EventHandler::new(),
+            extensions: client_extensions(),
+            fingerprint_wanted: BTreeSet::new(),
+            history_exhausted: false,
+            history_pending: false,
+            identity: None,
             inbuffer: Vec::new(),
+            input: String::new(),
+            input_mode: InputMode::Normal,
+            jump_to: None,
+            last_input: Instant::now(),
+            last_ping: None,
+            latency: None,
+            login_key: None,
+            mentions: Vec::new(),
+            notify: notify(),
+            peer_keys: BTreeMap::new(),
+            pending_dms: BTreeMap::new(),
+            presence: BTreeMap::new(),
+            reconnect_at: None,
+            reconnect_delay: RECONNECT_DELAY,
+            room_id: LOBBY_ROOM_ID,
+            room_wanted: None,
+            rooms: BTreeMap::new(),
             running: true,
+            scroll_offset: 0,
+            scrollback: Vec::new(),
+            search: None,
+            selected: None,
             server_port: 0,
             server_address: String::new(),
+            session: None,
+            server_extensions: Vec::new(),
             server_major_ver: 0,
             server_minor_ver: 0,
             server_subminor_ver: 0,
+            status: Presence::default(),
             tcpstream: TcpStream::from(Socket::new(Domain::IPV4, Type::STREAM, None).unwrap()),
+            thread: None,
+            typing_sent: None,
+            typing_users: BTreeMap::new(),
             username: String::new(),
             user_uuid: Uuid::new_v4(),
+            welcome: String::new(),
         }
     }
 }
 
+// what saved sessions are filed under
+fn server_key() -> String {
+    format!("{}:{}", SERVER_ADDRESS, SERVER_PORT)
+}
+
+fn away_after() -> Option<Duration> {
+    let Ok(value) = env::var("BORK_AWAY_AFTER") else {
+        return Some(AWAY_AFTER);
+    };
+    match value.parse::<u64>() {
+        Ok(0) => None,
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            error!("BORK_AWAY_AFTER must be a number of seconds, not {:?}; using {:?}", value, AWAY_AFTER);
+            Some(AWAY_AFTER)
+        }
+    }
+}
+
+fn notify() -> Notify {
+    match env::var("BORK_NOTIFY").as_deref() {
+        Err(_) | Ok("bell") => Notify::Bell,
+        Ok("desktop") => Notify::Desktop,
+        Ok("off") => Notify::Off,
+        Ok(value) => {
+            error!("BORK_NOTIFY must be bell, desktop or off, not {:?}; using bell", value);
+            Notify::Bell
+        }
+    }
+}
+
+// rows a message takes in the chat pane: its text, and a row of reaction badges if it has any
+fn line_rows(line: &ChatLine) -> u16 {
+    let rows = line.text.lines().count().max(1) + usize::from(!line.reactions.is_empty());
+    u16::try_from(rows).unwrap_or(u16::MAX)
+}
+
+// "from:alice since:2026-10-01 build broken" becomes a SEARCH for "build broken" with those filters.
+// Dates are local, and until: includes the day it names.
+fn search_frame(query: &str, search_id: u64, before_id: u64) -> Result<Frame, String> {
+    let day = |value: &str| {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+            .and_then(|midnight| midnight.and_local_timezone(Local).earliest())
+            .map(|midnight| midnight.timestamp() as u64)
+            .ok_or_else(|| format!("{:?} isn't a date like 2026-10-19", value))
+    };
+    let (mut room_id, mut username, mut since, mut until) = (0, String::new(), 0, 0);
+    let mut words = Vec::new();
+    for word in query.split_whitespace() {
+        match word.split_once(':') {
+            Some(("from", name)) => username = String::from(name),
+            Some(("in", room)) => room_id = room.parse().map_err(|_| format!("{:?} isn't a room ID", room))?,
+            Some(("since", date)) => since = day(date)?,
+            Some(("until", date)) => until = day(date)? + 24 * 60 * 60,
+            _ => words.push(word),
+        }
+    }
+    if words.is_empty() {
+        return Err(String::from("search for at least one word"));
+    }
+    if !username.is_empty() {
+        validate_username(&username).map_err(|e| e.to_string())?;
+    }
+    let query = words.join(" ");
+    validate_query(&query).map_err(|e| e.to_string())?;
+    Ok(Frame::Search {
+        search_id,
+        room_id,
+        username,
+        since,
+        until,
+        before_id,
+        limit: SEARCH_PAGE,
+        query,
+    })
+}
+
+fn client_extensions() -> ExtensionRegistry<App> {
+    let mut registry = ExtensionRegistry::new();
+    registry.register(|ping: Ping, app: &mut App| {
+        app.send_frame(Pong { nonce: ping.nonce }.to_frame());
+    });
+    registry.register(|pong: Pong, app: &mut App| {
+        // a stale PONG (one we've since re-pinged past) says nothing about the current round trip
+        if let Some((nonce, sent)) = app.last_ping
+            && nonce == pong.nonce {
+            app.latency = Some(sent.elapsed());
+        }
+    });
+    registry
+}
+
 impl App {
     /// Constructs a new instance of [`App`].
     pub fn new() -> Self {
@@ -72,14 +434,31 @@ impl App {
             terminal.draw(|frame| frame.render_widget(&self, frame.area()))?;
             match self.events.next().await? {
                 Event::Tick => self.tick(),
-                Event::Crossterm(event) => match event {
-                    crossterm::event::Event::Key(key_event) => self.handle_key_events(key_event)?,
-                    _ => {}
-                },
+                Event::Crossterm(event) => {
+                    if let crossterm::event::Event::Key(key_event) = event {
+                        self.handle_key_events(key_event)?
+                    }
+                }
                 Event::App(app_event) => match app_event {
-                    AppEvent::ConnectServer => self.connect_to_server(SERVER_ADDRESS, SERVER_PORT),
-                    AppEvent::DisconnectServer => self.disconnect_server(),
+                    AppEvent::ConnectServer => {
+                        self.reconnect_at = None;
+                        self.connect_to_server(SERVER_ADDRESS, SERVER_PORT);
+                    }
+                    AppEvent::DisconnectServer => {
+                        self.reconnect_at = None;
+                        self.disconnect_server();
+                    }
                     AppEvent::JoinUser => self.join_user(),
+                    AppEvent::RequestHistory => self.request_history(),
+                    AppEvent::SendChat => self.send_chat(),
+                    AppEvent::EditLast => self.edit_last(),
+                    AppEvent::DeleteLast => self.delete_last(),
+                    AppEvent::React(reaction) => self.react(&reaction),
+                    AppEvent::ToggleThread => self.toggle_thread(),
+                    AppEvent::CyclePresence => self.cycle_presence(),
+                    AppEvent::Search => self.run_search(false),
+                    AppEvent::SearchMore => self.run_search(true),
+                    AppEvent::JumpToHit => self.jump_to_hit(),
                     AppEvent::Quit => self.quit(),
                 },
             }
@@ -89,14 +468,102 @@ impl App {
 
     /// Handles the key events and updates the state of [`App`].
     pub fn handle_key_events(&mut self, key_event: KeyEvent) -> color_eyre::Result<()> {
+        if key_event.code == KeyCode::Char('c') && key_event.modifiers == KeyModifiers::CONTROL {
+            self.events.send(AppEvent::Quit);
+            return Ok(());
+        }
+        self.last_input = Instant::now();
+        if self.auto_away {
+            self.auto_away = false;
+            self.set_presence(PresenceStatus::ONLINE, self.status.text.clone());
+        }
+        if key_event.code == KeyCode::Char('f') && key_event.modifiers == KeyModifiers::CONTROL {
+            self.search = Some(SearchView::default());
+            return Ok(());
+        }
+        if let Some(search) = &mut self.search {
+            match key_event.code {
+                KeyCode::Esc => self.search = None,
+                KeyCode::Enter if search.query == search.searched && !search.hits.is_empty() => {
+                    self.events.send(AppEvent::JumpToHit)
+                }
+                KeyCode::Enter => self.events.send(AppEvent::Search),
+                KeyCode::Up => search.selected = search.selected.saturating_sub(1),
+                KeyCode::Down if search.selected + 1 < search.hits.len() => search.selected += 1,
+                // off the end of what we have; fetch the next page
+                KeyCode::Down if !search.exhausted && !search.pending => self.events.send(AppEvent::SearchMore),
+                KeyCode::Backspace => {
+                    search.query.pop();
+                }
+                KeyCode::Char(c) => search.query.push(c),
+                _ => {}
+            }
+            return Ok(());
+        }
+        if let Some(away) = &mut self.away {
+            match key_event.code {
+                KeyCode::Esc => self.away = None,
+                KeyCode::Enter => {
+                    let away = self.away.take().unwrap_or_default();
+                    if let Some(hit) = away.entries.get(away.selected) {
+                        self.show_hit(hit);
+                    }
+                }
+                KeyCode::Up => away.selected = away.selected.saturating_sub(1),
+                KeyCode::Down => away.selected = (away.selected + 1).min(away.entries.len().saturating_sub(1)),
+                _ => {}
+            }
+            return Ok(());
+        }
+        if self.input_mode == InputMode::Editing {
+            if key_event.code != KeyCode::Tab {
+                self.completion = None;
+            }
+            match key_event.code {
+                KeyCode::Esc => {
+                    self.stop_typing();
+                    self.input_mode = InputMode::Normal;
+                    if self.editing_message.take().is_some() {
+                        self.input.clear();
+                    }
+                }
+                KeyCode::Enter => self.events.send(AppEvent::SendChat),
+                KeyCode::Tab => self.complete_mention(),
+                KeyCode::Backspace => {
+                    self.input.pop();
+                    if self.input.is_empty() {
+                        self.stop_typing();
+                    }
+                }
+                KeyCode::Char(c) => {
+                    self.input.push(c);
+                    self.start_typing();
+                }
+                _ => {}
+            }
+            return Ok(());
+        }
         match key_event.code {
+            KeyCode::Esc if self.thread.is_some() => self.events.send(AppEvent::ToggleThread),
             KeyCode::Esc | KeyCode::Char('q') => self.events.send(AppEvent::Quit),
-            KeyCode::Char('c' | 'C') if key_event.modifiers == KeyModifiers::CONTROL => {
-                self.events.send(AppEvent::Quit)
-            }
+            KeyCode::Char('t' | 'T') => self.events.send(AppEvent::ToggleThread),
+            KeyCode::Char('[') => self.select(-1),
+            KeyCode::Char(']') => self.select(1),
             KeyCode::Char('c' | 'C') => self.events.send(AppEvent::ConnectServer),
             KeyCode::Char('d' | 'D') => self.events.send(AppEvent::DisconnectServer),
             KeyCode::Char('j' | 'J') => self.events.send(AppEvent::JoinUser),
+            KeyCode::Char('e' | 'E') => self.events.send(AppEvent::EditLast),
+            KeyCode::Char('x' | 'X') => self.events.send(AppEvent::DeleteLast),
+            KeyCode::Char('b' | 'B') => self.events.send(AppEvent::React(String::from(BORK))),
+            KeyCode::Char('p' | 'P') => self.events.send(AppEvent::CyclePresence),
+            KeyCode::Char('m' | 'M') => self.next_mention(),
+            KeyCode::Char('i' | 'I') | KeyCode::Enter => self.input_mode = InputMode::Editing,
+            KeyCode::Up => self.scroll_up(1),
+            KeyCode::PageUp => self.scroll_up(self.chat_height.get().max(1)),
+            KeyCode::Down => self.scroll_offset = self.scroll_offset.saturating_sub(1),
+            KeyCode::PageDown => {
+                self.scroll_offset = self.scroll_offset.saturating_sub(self.chat_height.get().max(1))
+            }
             _ => {}
         }
         Ok(())
@@ -104,135 +571,1546 @@ impl App {
 
     pub fn tick(&mut self) {
         if self.connected {
-            let mut pbuf = [0u8];
-            let peeklen = match self.tcpstream.peek(&mut pbuf) {
-                Err(_) => {
-                    0
-                },
-                Ok(v) => v,
-            };
-
-            if peeklen > 0 {
-                self.read_incomming();
+            self.read_incomming();
+            self.ping();
+            let now = Instant::now();
+            self.typing_users.retain(|_, until| *until > now);
+            // do-not-disturb is a choice; only plain online slides into away
+            if self.status.status == PresenceStatus::ONLINE
+                && self.away_after.is_some_and(|after| self.last_input.elapsed() >= after) {
+                self.auto_away = true;
+                self.set_presence(PresenceStatus::AWAY, self.status.text.clone());
             }
+            self.mark_read();
+        } else if self.reconnect_at.is_some_and(|at| Instant::now() >= at) {
+            self.reconnect();
         }
     }
 
+    // everything in the chat pane counts as read while its newest message is in view, unless we've
+    // wandered off
+    fn mark_read(&mut self) {
+        if self.scroll_offset > 0 || self.auto_away || self.search.is_some() || self.away.is_some() {
+            return;
+        }
+        let Some(newest) = self.scrollback.iter().rev().find_map(|line| line.message_id) else {
+            return;
+        };
+        let room_id = self.room_id;
+        let Some(room) = self.rooms.get_mut(&room_id).filter(|room| room.last_read < newest) else {
+            return;
+        };
+        room.last_read = newest;
+        room.unread = 0;
+        self.send_frame(Frame::MarkRead { room_id, message_id: newest });
+    }
+
+    /// Where in the scrollback the "new messages" line goes, if anywhere
+    pub fn divider_index(&self) -> Option<usize> {
+        let after = self.divider_after?;
+        self.scrollback.iter().position(|line| line.message_id.is_some_and(|id| id > after))
+    }
+
+    // measure round-trip time every PING_INTERVAL, if the server can answer
+    fn ping(&mut self) {
+        if !self.connected || !self.server_extensions.contains(&ExtendedMessageType::PING) {
+            return;
+        }
+        if self.last_ping.is_some_and(|(_, sent)| sent.elapsed() < PING_INTERVAL) {
+            return;
+        }
+        let nonce = rand::rng().random();
+        self.last_ping = Some((nonce, Instant::now()));
+        self.send_frame(Ping { nonce }.to_frame());
+    }
+
+    // debounced: only the first keystroke, then one every TYPING_REFRESH, reaches the server
+    fn start_typing(&mut self) {
+        if !self.connected || self.username.is_empty() || self.editing_message.is_some() {
+            return;
+        }
+        if self.typing_sent.is_some_and(|sent| sent.elapsed() < TYPING_REFRESH) {
+            return;
+        }
+        self.typing_sent = Some(Instant::now());
+        self.send_frame(Frame::Typing { room_id: self.room_id, user_id: self.user_uuid, typing: true });
+    }
+
+    fn stop_typing(&mut self) {
+        if self.typing_sent.take().is_some() && self.connected {
+            self.send_frame(Frame::Typing { room_id: self.room_id, user_id: self.user_uuid, typing: false });
+        }
+    }
+
+    /// Tell the server (and so everyone) how available we are
+    fn set_presence(&mut self, status: u8, text: String) {
+        self.status = Presence { status, text };
+        if self.connected && !self.username.is_empty() {
+            self.send_frame(Frame::Presence { user_id: self.user_uuid, status, status_text: self.status.text.clone() });
+        }
+    }
+
+    // online -> away -> do not disturb -> online
+    pub fn cycle_presence(&mut self) {
+        let status = match self.status.status {
+            PresenceStatus::ONLINE => PresenceStatus::AWAY,
+            PresenceStatus::AWAY => PresenceStatus::DO_NOT_DISTURB,
+            _ => PresenceStatus::ONLINE,
+        };
+        self.auto_away = false;
+        self.set_presence(status, self.status.text.clone());
+    }
+
+    /// Names of the other users typing right now, in name order
+    pub fn typing_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.typing_users.keys()
+            .filter(|&&uuid| uuid != self.user_uuid)
+            .map(|&uuid| self.username_for(uuid))
+            .collect();
+        names.sort();
+        names
+    }
+
     /// Set running to false to quit the application.
     pub fn quit(&mut self) {
         self.running = false;
     }
 
-    pub fn connect_to_server(&mut self, ip: &str, port: u16) {
+    pub fn connect_to_server(&mut self, ip: &str, port: u16) -> bool {
         self.server_address = ip.into();
         self.server_port = port;
         let address = format!("{}:{}", ip, port);
-        let stream = TcpStream::connect(&address).map_err(|err|{
-            error!("Couldn't connect. Error was: {}", err);
-        });
-        self.tcpstream = stream.unwrap();
+        self.tcpstream = match TcpStream::connect(&address) {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Couldn't connect. Error was: {}", err);
+                return false;
+            }
+        };
         let one_hundred_millis = Some(Duration::from_millis(1));
         self.tcpstream.set_read_timeout(one_hundred_millis)
             .expect("set_read_timeout call failed");
         self.connected = true;
+        self.inbuffer.clear();
 
         info!("Connected to server {}:{}", ip, port);
+        true
+    }
+
+    // try to get back on after a server shutdown, as the same user; back off while it's still down
+    fn reconnect(&mut self) {
+        if self.connect_to_server(SERVER_ADDRESS, SERVER_PORT) {
+            self.reconnect_at = None;
+            self.reconnect_delay = RECONNECT_DELAY;
+            match &self.session {
+                Some(saved) => self.send_frame(Frame::Resume { token: saved.token.clone() }),
+                None if !self.username.is_empty() => self.send_frame(Frame::Join { username: self.username.clone() }),
+                None => (),
+            }
+        } else {
+            self.reconnect_delay = (self.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+            self.reconnect_at = Some(Instant::now() + self.reconnect_delay);
+            info!("reconnect failed; trying again in {:?}", self.reconnect_delay);
+        }
     }
 
     pub fn disconnect_server(&mut self) {
-        match self.tcpstream.shutdown(Shutdown::Both) {
-            Err(e) => error!("failed to shutdown TCPStream, with Err: {}", e),
-            _ => ()
-        };
+        if let Err(e) = self.tcpstream.shutdown(Shutdown::Both) {
+            error!("failed to shutdown TCPStream, with Err: {}", e);
+        }
         self.server_major_ver = 0;
         self.server_minor_ver = 0;
         self.server_subminor_ver = 0;
         self.server_address = String::from("");
         self.connected = false;
+        // whatever's left of a frame can't be finished on a new connection
+        self.inbuffer.clear();
+        self.active_users.clear();
+        self.presence.clear();
+        self.rooms.clear();
+        self.room_wanted = None;
+        self.divider_after = None;
+        self.history_pending = false;
+        self.downloads.clear();
+        self.typing_sent = None;
+        self.typing_users.clear();
+        self.server_extensions.clear();
+        self.last_ping = None;
+        self.latency = None;
+        self.login_key = None;
+        self.pending_dms.clear();
+        self.changed_keys.clear();
+        self.fingerprint_wanted.clear();
         info!("disconnected");
     }
 
-    // create a fake username with random number (Guest1234) for now
+    /// Log out for good: the server revokes our token and we forget it, so the next join starts afresh
+    pub fn logout(&mut self) {
+        if !self.connected || self.username.is_empty() {
+            return;
+        }
+        self.send_frame(Frame::Leave);
+        session::forget(&server_key());
+        self.session = None;
+        self.identity = None;
+        self.username.clear();
+        // whoever logs in next may not be in the room we were looking at
+        self.room_id = LOBBY_ROOM_ID;
+        self.disconnect_server();
+    }
+
+    // pick up where a saved session left off; failing that, create a fake username with random
+    // number (Guest1234) for now
     pub fn join_user(&mut self) {
-        let mut message: Vec<u8> = Vec::new();
+        if let Some(saved) = session::load(&server_key()) {
+            self.username = saved.username.clone();
+            self.send_frame(Frame::Resume { token: saved.token.clone() });
+            self.session = Some(saved);
+            return;
+        }
         let mut rng = rand::rng();
         let fakeuser = format!("Guest{}", rng.random_range(1..=1000));
-        let uname_len:u16 = u16::try_from(fakeuser.chars().count()).unwrap();
-        message.push(MessageType::JOIN);
-        message.extend(uname_len.to_le_bytes());
-        message.extend(fakeuser.as_bytes());
+        self.username = fakeuser.clone();
+        self.send_frame(Frame::Join { username: fakeuser });
+    }
+
+    pub fn send_chat(&mut self) {
+        if !self.connected || self.input.trim().is_empty() {
+            return;
+        }
+        if let Err(e) = validate_message(&self.input) {
+            error!("not sending message: {}", e);
+            return;
+        }
+        // these work before joining too; they're how a user with a password gets in
+        if self.account_command() {
+            return;
+        }
+        if self.username.is_empty() || self.room_command() {
+            return;
+        }
+        if let Some(text) = self.input.strip_prefix("/status") {
+            // "/status" on its own clears it
+            let text = text.trim().to_string();
+            if let Err(e) = validate_status(&text) {
+                error!("not setting status: {}", e);
+                return;
+            }
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.set_presence(self.status.status, text);
+            return;
+        }
+        if let Some(email) = self.input.strip_prefix("/register ") {
+            let email = email.trim().to_string();
+            if let Err(e) = validate_email(&email) {
+                self.tell(NoticeSeverity::WARNING, format!("not registering: {}", e));
+                return;
+            }
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.send_frame(Frame::Register { email });
+            return;
+        }
+        if let Some(code) = self.input.strip_prefix("/verify ") {
+            let code = code.trim().to_string();
+            if let Err(e) = validate_code(&code) {
+                self.tell(NoticeSeverity::WARNING, format!("not verifying: {}", e));
+                return;
+            }
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.send_frame(Frame::Verify { code });
+            return;
+        }
+        if let Some(passwords) = self.input.strip_prefix("/password ") {
+            // "/password <new>" sets one for the first time; changing one needs the old one first
+            let (old_password, new_password) = match passwords.split_whitespace().collect::<Vec<_>>()[..] {
+                [new] => (String::new(), new.to_string()),
+                [old, new] => (old.to_string(), new.to_string()),
+                _ => {
+                    self.tell(NoticeSeverity::WARNING, String::from("not changing password: /password [old password] <new password>"));
+                    return;
+                }
+            };
+            if let Err(e) = validate_password(&old_password).and(validate_password(&new_password)) {
+                self.tell(NoticeSeverity::WARNING, format!("not changing password: {}", e));
+                return;
+            }
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.send_frame(Frame::ChangePassword { old_password, new_password });
+            return;
+        }
+        if let command @ ("/addkey" | "/removekey") = self.input.trim() {
+            let Some(key) = sshkey::load() else {
+                return;
+            };
+            let public_key = key.verifying_key().to_bytes();
+            let (frame, doing) = match command {
+                "/addkey" => (Frame::AddKey { public_key }, "adding"),
+                _ => (Frame::RemoveKey { public_key }, "removing"),
+            };
+            info!("{} key {}", doing, sshkey::fingerprint(&public_key));
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.send_frame(frame);
+            return;
+        }
+        if let Some(rest) = self.input.strip_prefix("/dm ") {
+            let Some((recipient, text)) = rest.trim_start().split_once(' ') else {
+                error!("usage: /dm <username> <message>");
+                return;
+            };
+            let (recipient, text) = (recipient.to_string(), text.trim().to_string());
+            if recipient == self.username || text.is_empty() {
+                error!("not sending a direct message: it needs someone else to go to, and something to say");
+                return;
+            }
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.direct_message(recipient, text);
+            return;
+        }
+        if let Some(username) = self.input.strip_prefix("/trust ") {
+            let username = username.trim().to_string();
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.trust(&username);
+            return;
+        }
+        if let Some(username) = self.input.strip_prefix("/fingerprint") {
+            // "/fingerprint" on its own shows ours
+            let username = username.trim().to_string();
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.show_fingerprint(username);
+            return;
+        }
+        if self.input.trim() == "/logout" {
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.logout();
+            return;
+        }
+        if let Some(query) = self.input.strip_prefix("/search ") {
+            self.search = Some(SearchView { query: query.trim().to_string(), ..Default::default() });
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.run_search(false);
+            return;
+        }
+        if let Some(path) = self.input.strip_prefix("/upload ") {
+            let path = PathBuf::from(path.trim());
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.upload(&path);
+            return;
+        }
+        if let Some(dir) = self.input.strip_prefix("/save") {
+            // "/save" on its own saves to BORK_DOWNLOAD_DIR
+            let dir = match dir.trim() {
+                "" => env::var("BORK_DOWNLOAD_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from(".")),
+                dir => PathBuf::from(dir),
+            };
+            self.input.clear();
+            self.input_mode = InputMode::Normal;
+            self.save(dir);
+            return;
+        }
+        if let Some(reaction) = self.input.strip_prefix("/react ") {
+            let reaction = reaction.trim().to_string();
+            self.input.clear();
+            self.react(&reaction);
+            return;
+        }
+        self.stop_typing();
+        let message_text = std::mem::take(&mut self.input);
+        if let Some(message_id) = self.editing_message.take() {
+            self.send_frame(Frame::EditMsg { message_id, message_text });
+            self.input_mode = InputMode::Normal;
+            return;
+        }
+        let nonce = rand::rng().random();
+        // show it straight away; SENTACK fills in the id and time
+        let line = ChatLine {
+            nonce: Some(nonce),
+            username: self.username.clone(),
+            text: message_text.clone(),
+            ..Default::default()
+        };
+        // with a thread open, what we write goes into it
+        let parent_id = match &mut self.thread {
+            Some(thread) => {
+                thread.replies.push(line);
+                thread.parent_id
+            }
+            None => {
+                self.scrollback.push(line);
+                0
+            }
+        };
+        self.send_frame(Frame::ChatMsg {
+            message_id: 0,
+            timestamp: 0,
+            sender_id: self.user_uuid,
+            room_id: self.room_id,
+            nonce,
+            parent_id,
+            message_text,
+        });
+        self.scroll_offset = 0;
+        // whoever's talking has caught up
+        self.divider_after = None;
+    }
+
+    // Our key pair, made and published now if this account doesn't have one here yet: that's opting in
+    // to direct messages
+    fn ensure_identity(&mut self) -> Option<&Identity> {
+        if self.identity.is_none() {
+            let identity = e2e::create_identity(&server_key(), &self.username)?;
+            self.send_frame(Frame::PublishKey { public_key: identity.public_key });
+            self.identity = Some(identity);
+        }
+        self.identity.as_ref()
+    }
+
+    // Send `text` to `recipient` once the server has told us their key. It's asked for every time, so a
+    // key that has changed is noticed before anything is encrypted for the old one.
+    fn direct_message(&mut self, recipient: String, text: String) {
+        if self.ensure_identity().is_none() {
+            return;
+        }
+        self.pending_dms.entry(recipient.clone()).or_default().push(text);
+        self.send_frame(Frame::KeyRequest { username: recipient });
+    }
+
+    // encrypt `text` for `recipient`, whose key we've just been sent
+    fn send_direct(&mut self, recipient: &str, text: &str) {
+        let (Some(identity), Some(&(recipient_id, peer_key))) = (&self.identity, self.peer_keys.get(recipient)) else {
+            return;
+        };
+        let Some((nonce, ciphertext)) = identity.encrypt(&peer_key, &self.user_uuid, &recipient_id, text) else {
+            return;
+        };
+        let frame = Frame::DirectMsg {
+            message_id: 0,
+            timestamp: 0,
+            sender_id: Uuid::nil(),
+            username: String::new(),
+            recipient_id,
+            sender_key: identity.public_key,
+            recipient_key: peer_key,
+            nonce,
+            ciphertext,
+        };
+        self.send_frame(frame);
+    }
+
+    // our own key's fingerprint (making the key if need be), or `username`'s, for comparing with theirs
+    // some other way
+    fn show_fingerprint(&mut self, username: String) {
+        if username.is_empty() || username == self.username {
+            let Some(identity) = self.ensure_identity() else {
+                return;
+            };
+            let text = format!("your key fingerprint is {}", e2e::fingerprint(&identity.public_key));
+            self.tell(NoticeSeverity::INFO, text);
+            return;
+        }
+        // always asked afresh, so what's shown is what the server has now
+        self.fingerprint_wanted.insert(username.clone());
+        self.send_frame(Frame::KeyRequest { username });
+    }
+
+    // /login <username> <password>, /keylogin <username>, /forgot <username> and
+    // /reset <username> <code> <new password>; true if the input was one of them, sent or not, so that a
+    // password never goes out as chat
+    fn account_command(&mut self) -> bool {
+        let words: Vec<&str> = self.input.split_whitespace().collect();
+        let frame = match words[..] {
+            ["/login", username, password] => {
+                // a failed login leaves no session behind, so trying again is fine
+                if self.session.is_some() {
+                    self.tell(NoticeSeverity::WARNING, format!("not logging in: already joined as {}; /logout first", self.username));
+                    return true;
+                }
+                if let Err(e) = validate_username(username).and(validate_password(password)) {
+                    self.tell(NoticeSeverity::WARNING, format!("not logging in: {}", e));
+                    return true;
+                }
+                self.username = username.to_string();
+                Frame::Login { username: username.to_string(), password: password.to_string() }
+            }
+            ["/keylogin", username] => {
+                if self.session.is_some() {
+                    self.tell(NoticeSeverity::WARNING, format!("not logging in: already joined as {}; /logout first", self.username));
+                    return true;
+                }
+                if let Err(e) = validate_username(username) {
+                    self.tell(NoticeSeverity::WARNING, format!("not logging in: {}", e));
+                    return true;
+                }
+                let Some(key) = sshkey::load() else {
+                    self.tell(NoticeSeverity::WARNING, String::from("not logging in: couldn't load your key; the log says why"));
+                    return true;
+                };
+                let public_key = key.verifying_key().to_bytes();
+                info!("logging in as {} with key {}", username, sshkey::fingerprint(&public_key));
+                self.username = username.to_string();
+                self.login_key = Some(key);
+                Frame::KeyLogin { username: username.to_string(), public_key }
+            }
+            ["/forgot", username] => {
+                if let Err(e) = validate_username(username) {
+                    self.tell(NoticeSeverity::WARNING, format!("not asking for a reset code: {}", e));
+                    return true;
+                }
+                Frame::ResetRequest { username: username.to_string() }
+            }
+            ["/reset", username, code, new_password] => {
+                if let Err(e) = validate_username(username).and(validate_code(code)).and(validate_password(new_password)) {
+                    self.tell(NoticeSeverity::WARNING, format!("not resetting password: {}", e));
+                    return true;
+                }
+                Frame::ResetPassword {
+                    username: username.to_string(),
+                    code: code.to_string(),
+                    new_password: new_password.to_string(),
+                }
+            }
+            [command, ..] if ["/login", "/keylogin", "/forgot", "/reset"].contains(&command) => {
+                self.tell(NoticeSeverity::WARNING, String::from("usage: /login <username> <password>, /keylogin <username>, \
+                    /forgot <username>, /reset <username> <code> <new password>"));
+                return true;
+            }
+            _ => return false,
+        };
+        self.input.clear();
+        self.input_mode = InputMode::Normal;
+        self.send_frame(frame);
+        true
+    }
+
+    // /create [private] <room>, /join <room>, /room <room>, /invite <username>, /topic [topic],
+    // /describe [description] and /roomrole <username> moderator|member; true if the input was one of them
+    fn room_command(&mut self) -> bool {
+        let input = self.input.trim().to_string();
+        let (command, rest) = input.split_once(' ').unwrap_or((input.as_str(), ""));
+        let rest = rest.trim();
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let room_id = self.room_id;
+        let (topic, description) = self.rooms.get(&room_id)
+            .map(|room| (room.topic.clone(), room.description.clone()))
+            .unwrap_or_default();
+        let frame = match (command, &words[..]) {
+            ("/create", [room_name] | ["private", room_name]) => {
+                if let Err(e) = validate_room_name(room_name) {
+                    error!("not creating a room: {}", e);
+                    return true;
+                }
+                self.room_wanted = Some(room_name.to_string());
+                Frame::CreateRoom { room_name: room_name.to_string(), is_public: words.len() == 1 }
+            }
+            ("/join", [room_name]) => {
+                if let Err(e) = validate_room_name(room_name) {
+                    error!("not joining a room: {}", e);
+                    return true;
+                }
+                self.room_wanted = Some(room_name.to_string());
+                Frame::JoinRoom { room_name: room_name.to_string() }
+            }
+            ("/room", [room_name]) => {
+                let Some(&room_id) = self.rooms.iter()
+                    .find(|(_, room)| room.name.eq_ignore_ascii_case(room_name))
+                    .map(|(room_id, _)| room_id) else {
+                    error!("not switching rooms: you aren't in {}; /join it first", room_name);
+                    return true;
+                };
+                self.input.clear();
+                self.input_mode = InputMode::Normal;
+                self.switch_room(room_id);
+                return true;
+            }
+            ("/invite", [username]) => {
+                if let Err(e) = validate_username(username) {
+                    error!("not inviting: {}", e);
+                    return true;
+                }
+                Frame::Invite { room_id, room_name: String::new(), username: username.to_string() }
+            }
+            ("/topic", []) => {
+                self.input.clear();
+                self.input_mode = InputMode::Normal;
+                self.show_topic();
+                return true;
+            }
+            ("/topic", _) | ("/describe", _) => {
+                let (topic, description) = match command {
+                    "/topic" => (rest.to_string(), description),
+                    // "/describe" on its own clears it
+                    _ => (topic, rest.to_string()),
+                };
+                if let Err(e) = validate_topic(&topic, &description) {
+                    error!("not setting the topic: {}", e);
+                    return true;
+                }
+                Frame::SetTopic { room_id, topic, description }
+            }
+            ("/roomrole", [username, role @ ("moderator" | "member")]) => {
+                let room_role = match *role {
+                    "moderator" => RoomRole::MODERATOR,
+                    _ => RoomRole::MEMBER,
+                };
+                Frame::RoomRole { room_id, user_id: Uuid::nil(), username: username.to_string(), room_role }
+            }
+            ("/create" | "/join" | "/room" | "/invite" | "/roomrole", _) => {
+                error!("usage: /create [private] <room>, /join <room>, /room <room>, /invite <username>, \
+                        /roomrole <username> moderator|member");
+                return true;
+            }
+            _ => return false,
+        };
+        self.input.clear();
+        self.input_mode = InputMode::Normal;
+        self.send_frame(frame);
+        true
+    }
+
+    // show `room_id` in the chat pane instead, from its newest messages
+    fn switch_room(&mut self, room_id: u64) {
+        self.stop_typing();
+        self.room_id = room_id;
+        self.scrollback.clear();
+        self.scroll_offset = 0;
+        self.thread = None;
+        self.selected = None;
+        self.editing_message = None;
+        self.typing_users.clear();
+        self.divider_after = self.rooms.get(&room_id)
+            .filter(|room| room.unread > 0)
+            .map(|room| room.last_read);
+        self.history_pending = false;
+        self.history_exhausted = false;
+        self.request_history();
+    }
+
+    // what the current room is about, as a notice
+    fn show_topic(&mut self) {
+        let Some(room) = self.rooms.get(&self.room_id) else {
+            return;
+        };
+        let topic = match room.topic.as_str() {
+            "" => format!("{} has no topic", room.name),
+            topic => format!("{}: {}", room.name, topic),
+        };
+        let description = room.description.clone();
+        self.tell(NoticeSeverity::INFO, topic);
+        if !description.is_empty() {
+            self.tell(NoticeSeverity::INFO, description);
+        }
+    }
+
+    // Tab after "@gu" fills in the first user whose name starts that way, and each further Tab swaps in
+    // the next one
+    fn complete_mention(&mut self) {
+        if let Some(completion) = &mut self.completion {
+            completion.index = (completion.index + 1) % completion.candidates.len();
+        } else {
+            let Some(at) = self.input.rfind('@') else {
+                return;
+            };
+            // the same rule the server mentions by: not straight after a letter or digit
+            if self.input[..at].chars().next_back().is_some_and(char::is_alphanumeric) {
+                return;
+            }
+            let prefix = self.input[at + 1..].to_lowercase();
+            let candidates: Vec<String> = self.active_users.keys()
+                .filter(|name| **name != self.username && name.to_lowercase().starts_with(&prefix))
+                .cloned()
+                .collect();
+            if candidates.is_empty() {
+                return;
+            }
+            self.completion = Some(Completion { at: at + 1, candidates, index: 0 });
+        }
+        let Some(completion) = &self.completion else {
+            return;
+        };
+        self.input.truncate(completion.at);
+        self.input.push_str(&completion.candidates[completion.index]);
+        self.input.push(' ');
+    }
+
+    // the newest message of ours the server knows about, and hasn't been deleted, in the thread if one
+    // is open
+    fn last_own_message(&self) -> Option<&ChatLine> {
+        let lines = match &self.thread {
+            Some(thread) => &thread.replies,
+            None => &self.scrollback,
+        };
+        lines.iter().rev().find(|line| {
+            line.kind == LineKind::Chat && line.username == self.username && line.message_id.is_some() && !line.deleted
+        })
+    }
+
+    // every message we're showing, in the chat pane and the thread panel
+    fn lines_mut(&mut self) -> impl Iterator<Item = &mut ChatLine> {
+        let replies = self.thread.iter_mut().flat_map(|thread| thread.replies.iter_mut());
+        self.scrollback.iter_mut().chain(replies)
+    }
+
+    fn line_mut(&mut self, message_id: u64) -> Option<&mut ChatLine> {
+        self.lines_mut().find(|line| line.message_id == Some(message_id))
+    }
+
+    /// The message reactions and threads apply to: the selected one, or else the newest
+    pub fn target_message(&self) -> Option<u64> {
+        self.selected.or_else(|| {
+            self.scrollback.iter().rev()
+                .find(|line| line.kind == LineKind::Chat && line.message_id.is_some() && !line.deleted)
+                .and_then(|line| line.message_id)
+        })
+    }
+
+    // a local notice, for things only we need to hear about
+    fn tell(&mut self, severity: u8, text: String) {
+        self.scrollback.push(ChatLine::notice(severity, text));
+        self.scroll_offset = 0;
+    }
+
+    /// Send a file to the current room: an OFFER, its CHUNKs, then COMPLETE
+    pub fn upload(&mut self, path: &Path) {
+        if !self.connected || self.username.is_empty() {
+            return;
+        }
+        let file_name = path.file_name().and_then(|name| name.to_str()).map(String::from).unwrap_or_default();
+        if let Err(e) = validate_file_name(&file_name) {
+            self.tell(NoticeSeverity::WARNING, format!("can't upload {}: {}", path.display(), e));
+            return;
+        }
+        let data = match fs::metadata(path) {
+            Ok(metadata) if metadata.len() > MAX_UPLOAD_SIZE => Err(String::from("it's too big")),
+            Ok(_) => fs::read(path).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                self.tell(NoticeSeverity::WARNING, format!("can't upload {}: {}", path.display(), e));
+                return;
+            }
+        };
+        let attachment = Attachment { file_name: file_name.clone(), size: data.len() as u64, sha256: Sha256::digest(&data).into() };
+        // shown straight away, like a chat message; SENTACK answers the transfer ID
+        let transfer_id = rand::rng().random();
+        self.scrollback.push(ChatLine {
+            nonce: Some(transfer_id),
+            username: self.username.clone(),
+            text: file_name.clone(),
+            attachment: Some(attachment.clone()),
+            ..Default::default()
+        });
+        self.scroll_offset = 0;
+        self.send_frame(Frame::Offer {
+            transfer_id,
+            room_id: self.room_id,
+            size: attachment.size,
+            sha256: attachment.sha256,
+            file_name,
+        });
+        for (index, chunk) in data.chunks(MAX_CHUNK_LEN).enumerate() {
+            let offset = (index * MAX_CHUNK_LEN) as u64;
+            self.send_frame(Frame::Chunk { transfer_id, offset, data: chunk.to_vec() });
+        }
+        self.send_frame(Frame::Complete { transfer_id });
+    }
+
+    // the selected message's file, or the newest file in the chat pane if the selection hasn't one
+    fn target_attachment(&self) -> Option<u64> {
+        let has_file = |line: &&ChatLine| line.attachment.is_some() && !line.deleted && line.message_id.is_some();
+        self.scrollback.iter()
+            .filter(has_file)
+            .find(|line| self.selected.is_some() && line.message_id == self.selected)
+            .or_else(|| self.scrollback.iter().rev().find(has_file))
+            .and_then(|line| line.message_id)
+    }
+
+    /// Ask the server for a file, to be saved in `dir` once it's all here
+    pub fn save(&mut self, dir: PathBuf) {
+        if !self.connected {
+            return;
+        }
+        let Some(message_id) = self.target_attachment() else {
+            self.tell(NoticeSeverity::WARNING, String::from("there's no file to save"));
+            return;
+        };
+        self.downloads.insert(message_id, Download { dir, offered: None, data: Vec::new() });
+        self.send_frame(Frame::FileRequest { message_id });
+    }
+
+    // a download is all here: check it, then write it out without clobbering anything already there
+    fn finish_download(&mut self, message_id: u64) {
+        let Some(Download { dir, offered: Some(offered), data }) = self.downloads.remove(&message_id) else {
+            return;
+        };
+        if data.len() as u64 != offered.size || Sha256::digest(&data)[..] != offered.sha256 {
+            self.tell(NoticeSeverity::WARNING, format!("couldn't save {}: it arrived damaged", offered.file_name));
+            return;
+        }
+        let mut path = dir.join(&offered.file_name);
+        if path.exists() {
+            path = dir.join(format!("{}-{}", message_id, offered.file_name));
+        }
+        match fs::write(&path, &data) {
+            Ok(()) => self.tell(NoticeSeverity::INFO, format!("saved {}", path.display())),
+            Err(e) => self.tell(NoticeSeverity::WARNING, format!("couldn't save {}: {}", path.display(), e)),
+        }
+    }
 
-        self.tcpstream.write_all(&message).map_err(|err| {
-            error!("Could not send Join message to server. Err: {}", err);
+    /// Send the overlay's query: a new search, or the next page of the current one
+    pub fn run_search(&mut self, more: bool) {
+        let connected = self.connected;
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        if !connected {
+            search.error = Some(String::from("not connected"));
+            return;
+        }
+        let before_id = match more {
+            true => search.hits.last().map_or(0, |hit| hit.entry.message_id),
+            false => 0,
+        };
+        let frame = match search_frame(&search.query, rand::rng().random(), before_id) {
+            Ok(frame) => frame,
+            Err(e) => {
+                search.error = Some(e);
+                return;
+            }
+        };
+        if let Frame::Search { search_id, .. } = frame {
+            search.search_id = search_id;
+        }
+        if !more {
+            search.searched = search.query.clone();
+            search.hits.clear();
+            search.selected = 0;
+            search.exhausted = false;
+        }
+        search.error = None;
+        search.pending = true;
+        self.send_frame(frame);
+    }
+
+    // close the overlay and scroll to the selected hit
+    fn jump_to_hit(&mut self) {
+        let Some(search) = self.search.take() else {
+            return;
+        };
+        if let Some(hit) = search.hits.get(search.selected) {
+            self.show_hit(hit);
+        }
+    }
+
+    // scroll to a message found outside the chat pane; a reply is found through the message it replies to
+    fn show_hit(&mut self, hit: &SearchHit) {
+        if hit.room_id != self.room_id {
+            if !self.rooms.contains_key(&hit.room_id) {
+                self.tell(NoticeSeverity::WARNING, String::from("that message is in a room you aren't in"));
+                return;
+            }
+            // the jump happens once the room's history arrives
+            self.switch_room(hit.room_id);
+        }
+        self.jump_to = Some(match hit.entry.parent_id {
+            0 => hit.entry.message_id,
+            parent_id => parent_id,
+        });
+        self.jump();
+    }
+
+    // jump to the newest mention of us older than the selected message, or back round to the newest;
+    // a reply is found through the message it replies to
+    fn next_mention(&mut self) {
+        let target = |mention: &Mention| match mention.parent_id {
+            0 => mention.message_id,
+            parent_id => parent_id,
+        };
+        let before = self.selected.unwrap_or(u64::MAX);
+        let Some(mention) = self.mentions.iter().rev()
+            .find(|mention| target(mention) < before)
+            .or(self.mentions.last()) else {
+            return;
+        };
+        self.jump_to = Some(target(mention));
+        self.jump();
+    }
+
+    // a message we're showing, as a Mention; the thread panel's replies belong to its parent
+    fn mention_for(&self, message_id: u64) -> Option<Mention> {
+        let (parent_id, line) = match self.scrollback.iter().find(|line| line.message_id == Some(message_id)) {
+            Some(line) => (0, line),
+            None => {
+                let thread = self.thread.as_ref()?;
+                (thread.parent_id, thread.replies.iter().find(|line| line.message_id == Some(message_id))?)
+            }
+        };
+        Some(Mention {
+            message_id,
+            parent_id,
+            timestamp: line.timestamp,
+            username: line.username.clone(),
+            text: line.text.clone(),
+        })
+    }
+
+    // someone just mentioned us: ring the bell, or raise a desktop notification, as BORK_NOTIFY says.
+    // Do-not-disturb means it, whichever of our sessions asked for it.
+    fn alert(&self, mention: &Mention) {
+        let account = self.presence.get(&self.user_uuid).map(|p| p.status);
+        if self.status.status == PresenceStatus::DO_NOT_DISTURB || account == Some(PresenceStatus::DO_NOT_DISTURB) {
+            return;
+        }
+        let signal = match self.notify {
+            Notify::Off => return,
+            Notify::Bell => String::from("\x07"),
+            Notify::Desktop => {
+                // a control character would end the sequence early
+                let text: String = format!("{}: {}", mention.username, mention.text).chars()
+                    .map(|c| if c.is_control() { ' ' } else { c })
+                    .take(200)
+                    .collect();
+                format!("\x1b]9;{}\x07", text)
+            }
+        };
+        let mut stdout = io::stdout();
+        if let Err(e) = stdout.write_all(signal.as_bytes()).and_then(|()| stdout.flush()) {
+            error!("couldn't send a notification: {}", e);
+        }
+    }
+
+    // scroll `jump_to` into the middle of the chat pane, paging in older history until it turns up
+    fn jump(&mut self) {
+        let Some(message_id) = self.jump_to else {
+            return;
+        };
+        if let Some(index) = self.scrollback.iter().position(|line| line.message_id == Some(message_id)) {
+            self.jump_to = None;
+            self.selected = Some(message_id);
+            let below: u16 = self.scrollback[index..].iter().map(line_rows).sum();
+            let max_offset = self.chat_row_count().saturating_sub(self.chat_height.get());
+            self.scroll_offset = below.saturating_sub(self.chat_height.get() / 2).min(max_offset);
+        } else if self.history_exhausted {
+            self.jump_to = None;
+            self.tell(NoticeSeverity::WARNING, String::from("couldn't find that message in the history"));
+        } else {
+            self.request_history();
+        }
+    }
+
+    // move the selection `step` messages older (negative) or newer; moving past the newest clears it
+    fn select(&mut self, step: isize) {
+        let ids: Vec<u64> = self.scrollback.iter()
+            .filter(|line| line.kind == LineKind::Chat && !line.deleted)
+            .filter_map(|line| line.message_id)
+            .collect();
+        let current = self.selected
+            .and_then(|id| ids.iter().position(|&i| i == id))
+            .unwrap_or(ids.len());
+        let next = current.saturating_add_signed(step);
+        self.selected = ids.get(next).copied();
+    }
+
+    /// Open the thread for the target message in the side panel, or close the one that's open
+    pub fn toggle_thread(&mut self) {
+        if self.thread.take().is_some() {
+            return;
+        }
+        if !self.connected || self.username.is_empty() {
+            return;
+        }
+        let Some(parent_id) = self.target_message() else {
+            return;
+        };
+        self.thread = Some(ThreadView { parent_id, replies: Vec::new() });
+        self.send_frame(Frame::Thread { parent_id });
+    }
+
+    /// Put our last message in the input box; sending it then replaces the original
+    pub fn edit_last(&mut self) {
+        if !self.connected {
+            return;
+        }
+        if let Some((message_id, text)) = self.last_own_message().map(|line| (line.message_id, line.text.clone())) {
+            self.editing_message = message_id;
+            self.input = text;
+            self.input_mode = InputMode::Editing;
+        }
+    }
+
+    pub fn delete_last(&mut self) {
+        if !self.connected {
+            return;
+        }
+        if let Some(message_id) = self.last_own_message().and_then(|line| line.message_id) {
+            self.send_frame(Frame::DeleteMsg { message_id });
+        }
+    }
+
+    /// Toggle `reaction` on the target message
+    pub fn react(&mut self, reaction: &str) {
+        if !self.connected || self.username.is_empty() {
+            return;
+        }
+        if let Err(e) = validate_reaction(reaction) {
+            error!("not reacting: {}", e);
+            return;
+        }
+        let Some(line) = self.target_message().and_then(|id| self.line_mut(id)) else {
+            return;
+        };
+        let action = match line.own_reactions.iter().position(|r| r == reaction) {
+            Some(index) => {
+                line.own_reactions.remove(index);
+                ReactionAction::REMOVE
+            }
+            None => {
+                line.own_reactions.push(reaction.to_string());
+                ReactionAction::ADD
+            }
+        };
+        let message_id = line.message_id.unwrap_or_default();
+        self.send_frame(Frame::Reaction { message_id, action, reaction: reaction.to_string() });
+    }
+
+    /// Ask for the page of history just before the oldest message we have
+    pub fn request_history(&mut self) {
+        if !self.connected || self.history_pending || self.history_exhausted {
+            return;
+        }
+        let before_id = self.scrollback.iter()
+            .find_map(|line| line.message_id)
+            .unwrap_or(0);
+        self.history_pending = true;
+        self.send_frame(Frame::History {
+            room_id: self.room_id,
+            before_id,
+            limit: MAX_HISTORY_BATCH as u16,
+        });
+    }
+
+    fn send_frame(&mut self, frame: Frame) {
+        self.tcpstream.write_all(&frame.encode()).map_err(|err| {
+            error!("Could not send message type {} to server. Err: {}", frame.message_type(), err);
         }).ok();
         self.tcpstream.flush().ok();
     }
 
-    pub fn read_incomming(&mut self){
-        // read message type; handle one message based on that type
-        // first byte is the message type per protocol -- read it, handle based on type
-        let mut mtype = [0u8];
-        match self.tcpstream.read_exact(&mut mtype) {
-            Err(e) => error!("failed to read message type, with Err: {}", e),
-            _ => ()
+    fn scroll_up(&mut self, rows: u16) {
+        let max_offset = self.chat_row_count().saturating_sub(self.chat_height.get());
+        self.scroll_offset = self.scroll_offset.saturating_add(rows).min(max_offset);
+        if self.scroll_offset == max_offset {
+            // hit the top of what we have; fetch the page before it
+            self.events.send(AppEvent::RequestHistory);
         }
-        info!("received message type {}", mtype[0]);
-        match mtype[0] {
-            MessageType::VERSION => {
-                let mut major = [0u8;2];
-                let mut minor = [0u8;2];
-                let mut subminor = [0u8;2];
-                match self.tcpstream.read_exact(&mut major) {
-                    Err(e) => error!("Failed to read major version with Err: {}", e),
-                    _ => ()
+    }
+
+    /// Total rows the chat pane would need to show everything, unscrolled
+    pub fn chat_row_count(&self) -> u16 {
+        let welcome = self.welcome.lines().count();
+        let chat: usize = self.scrollback.iter().map(|line| usize::from(line_rows(line))).sum();
+        let divider = usize::from(self.divider_index().is_some());
+        u16::try_from(welcome + chat + divider).unwrap_or(u16::MAX)
+    }
+
+    /// Pull whatever the server has sent into `inbuffer`, then handle every complete frame in it.
+    /// A frame may arrive over several ticks, so a partial one is left in the buffer for next time.
+    pub fn read_incomming(&mut self){
+        let mut chunk = [0u8; 4096];
+        // a server going away says why first; handle what it sent before hanging up
+        let mut closed = false;
+        loop {
+            match self.tcpstream.read(&mut chunk) {
+                Ok(0) => {
+                    info!("server closed the connection");
+                    closed = true;
+                    break;
                 }
-                match self.tcpstream.read_exact(&mut minor) {
-                    Err(e) => error!("Failed to read minor version with Err: {}", e),
-                    _ => ()
+                Ok(n) => self.inbuffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => {
+                    error!("failed to read from server, with Err: {}", e);
+                    self.disconnect_server();
+                    return;
                 }
+            }
+        }
 
-                match self.tcpstream.read_exact(&mut subminor) {
-                    Err(e) => error!("Failed to read subminor version with Err: {}", e),
-                    _ => ()
+        loop {
+            let mut cursor = Cursor::new(&self.inbuffer[..]);
+            let frame = match read_frame(&mut cursor) {
+                Ok(frame) => frame,
+                Err(FrameError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => {
+                    // the stream is out of sync with the protocol; nothing more can be trusted
+                    error!("failed to read message from server, with Err: {}", e);
+                    self.disconnect_server();
+                    return;
                 }
+            };
+            let consumed = cursor.position() as usize;
+            self.inbuffer.drain(..consumed);
+            self.handle_frame(frame);
+        }
+        if closed && self.connected {
+            self.disconnect_server();
+        }
+    }
 
-                self.server_major_ver = u16::from_le_bytes(major);
-                self.server_minor_ver = u16::from_le_bytes(minor);
-                self.server_subminor_ver = u16::from_le_bytes(subminor);
+    fn handle_frame(&mut self, frame: Frame) {
+        info!("received message type {}", frame.message_type());
+        match frame {
+            Frame::Version { major_rev, minor_rev, subminor_rev } => {
+                self.server_major_ver = major_rev;
+                self.server_minor_ver = minor_rev;
+                self.server_subminor_ver = subminor_rev;
+            }
+            Frame::Welcome { welcome_msg } => {
+                self.welcome = welcome_msg;
             }
-            MessageType::WELCOME => {
-                // this message type has variable length, so, we determine that length
-                // and read that many bytes
-                let mut len = [0u8;2];
-                match self.tcpstream.read_exact(&mut len){
-                    Err(e) => error!("failed to read Welcome message length with Err: {}", e),
-                    _ => ()
+            Frame::Capabilities { extensions } => {
+                self.server_extensions = extensions;
+                let ours = self.extensions.capabilities();
+                self.send_frame(Frame::Capabilities { extensions: ours });
+            }
+            Frame::Extended { extended_type, payload } => {
+                // handlers get the whole app, registry included, so lend it out for the call
+                let extensions = std::mem::take(&mut self.extensions);
+                match extensions.dispatch(extended_type, &payload, self) {
+                    Ok(true) => (),
+                    Ok(false) => info!("skipping unknown extended type {}", extended_type),
+                    Err(e) => error!("bad extended type {} from server: {}", extended_type, e),
                 }
-                let len:u16 = u16::from_le_bytes(len);
-                let mut wm_buf = vec![0; len as usize];
-                match self.tcpstream.read_exact(&mut wm_buf){
-                    Err(e) => error!("failed to read Welcome message content with Err: {}", e),
-                    _ => ()
+                self.extensions = extensions;
+            }
+            Frame::Userjoined { user_id, username } => {
+                let own = username == self.username;
+                self.active_users.insert(username, user_id);
+                self.presence.insert(user_id, Presence::default());
+                if own {
+                    self.user_uuid = user_id;
+                    // back after a reconnect; the server only remembers us as plain online
+                    let Presence { status, text } = self.status.clone();
+                    if status != PresenceStatus::ONLINE || !text.is_empty() {
+                        self.set_presence(status, text);
+                    }
                 }
-                self.inbuffer.extend_from_slice(&wm_buf[0..]);
             }
-            MessageType::USERJOINED => {
-                let mut user_uuid = [0u8;16];
-                let mut namelen = [0u8;2];
-
-                match self.tcpstream.read_exact(&mut user_uuid[..]){
-                    Err(e) => error!("Failed to read UUID from USERJOINED message with Err: {}", e),
-                    _ => ()
+            Frame::Userleft { user_id } => {
+                self.active_users.retain(|_, uuid| *uuid != user_id);
+                self.presence.remove(&user_id);
+            }
+            Frame::Presence { user_id, status, status_text } => {
+                if user_id == self.user_uuid {
+                    // each of our sessions keeps its own status, but the text belongs to the account,
+                    // and another session may have changed it
+                    self.status.text = status_text.clone();
+                }
+                self.presence.insert(user_id, Presence { status, text: status_text });
+            }
+            Frame::Typing { room_id, user_id, typing } if room_id == self.room_id => {
+                if typing {
+                    self.typing_users.insert(user_id, Instant::now() + TYPING_TIMEOUT);
+                } else {
+                    self.typing_users.remove(&user_id);
+                }
+            }
+            Frame::ChatMsg { message_id, timestamp, sender_id, room_id, parent_id, message_text, .. } => {
+                // whoever just sent something has stopped typing it
+                self.typing_users.remove(&sender_id);
+                if parent_id != 0
+                    && let Some(parent) = self.line_mut(parent_id) {
+                    parent.reply_count += 1;
+                }
+                // our own messages come back too, after their SENTACK already put them in place
+                if self.line_mut(message_id).is_some() {
+                    return;
+                }
+                let line = ChatLine {
+                    message_id: Some(message_id),
+                    timestamp: Some(timestamp),
+                    username: self.username_for(sender_id),
+                    text: message_text,
+                    ..Default::default()
+                };
+                if sender_id != self.user_uuid {
+                    self.arrived = Some(Mention {
+                        message_id,
+                        parent_id,
+                        timestamp: Some(timestamp),
+                        username: line.username.clone(),
+                        text: line.text.clone(),
+                    });
+                }
+                if parent_id == 0 && sender_id != self.user_uuid {
+                    let away = self.scroll_offset > 0 || self.auto_away;
+                    let here = room_id == self.room_id;
+                    if let Some(room) = self.rooms.get_mut(&room_id) {
+                        room.unread += 1;
+                        // mark where we were for when we're back
+                        if here && away && self.divider_after.is_none() {
+                            self.divider_after = Some(room.last_read);
+                        }
+                    }
+                }
+                // other rooms' messages are there when we switch to them
+                if room_id != self.room_id {
+                    return;
+                }
+                // replies only show in their thread, and only while it's open
+                match &mut self.thread {
+                    _ if parent_id == 0 => self.scrollback.push(line),
+                    Some(thread) if thread.parent_id == parent_id => thread.replies.push(line),
+                    _ => (),
+                }
+            }
+            Frame::ThreadBatch { parent_id, entries } => {
+                if let Some(thread) = self.thread.as_mut().filter(|thread| thread.parent_id == parent_id) {
+                    thread.replies = entries.into_iter().map(ChatLine::from).collect();
+                }
+            }
+            Frame::EditMsg { message_id, message_text } => {
+                if let Some(mention) = self.mentions.iter_mut().find(|mention| mention.message_id == message_id) {
+                    mention.text = message_text.clone();
+                }
+                if let Some(line) = self.line_mut(message_id) {
+                    line.text = message_text;
+                    line.edited = true;
+                }
+                // an edit can mention us where the message didn't before
+                self.arrived = self.mention_for(message_id).filter(|mention| mention.username != self.username);
+            }
+            Frame::DeleteMsg { message_id } => {
+                self.mentions.retain(|mention| mention.message_id != message_id);
+                if let Some(line) = self.line_mut(message_id) {
+                    line.text.clear();
+                    line.deleted = true;
+                    line.mentions_me = false;
+                    line.reactions.clear();
+                    line.own_reactions.clear();
+                }
+            }
+            Frame::Mentions { message_id, user_ids } => {
+                let mentions_me = user_ids.contains(&self.user_uuid);
+                if let Some(line) = self.line_mut(message_id) {
+                    line.mentions_me = mentions_me;
+                }
+                let known = self.mentions.iter().position(|mention| mention.message_id == message_id);
+                match (known, mentions_me) {
+                    (Some(index), false) => {
+                        self.mentions.remove(index);
+                    }
+                    (None, true) => {
+                        // news if it's only just been sent (or edited); otherwise it's from history
+                        let mention = match self.arrived.take_if(|arrived| arrived.message_id == message_id) {
+                            Some(mention) => {
+                                self.alert(&mention);
+                                mention
+                            }
+                            None => match self.mention_for(message_id) {
+                                Some(mention) => mention,
+                                None => return,
+                            },
+                        };
+                        let index = self.mentions.partition_point(|known| known.message_id < message_id);
+                        self.mentions.insert(index, mention);
+                        if self.mentions.len() > MENTIONS_KEPT {
+                            self.mentions.remove(0);
+                        }
+                    }
+                    _ => (),
                 }
-                match self.tcpstream.read_exact(&mut namelen[..]) {
-                    Err(e) => error!("Failed to read username length from USERJOINED message with Err: {}", e),
-                    _ => ()
+            }
+            Frame::Reactions { message_id, reactions } => {
+                if let Some(line) = self.line_mut(message_id) {
+                    // whatever isn't on the message any more can't be ours either
+                    line.own_reactions.retain(|own| reactions.iter().any(|r| &r.reaction == own));
+                    line.reactions = reactions;
+                }
+            }
+            Frame::Attachment { message_id, size, sha256, file_name } => {
+                if let Some(line) = self.line_mut(message_id) {
+                    line.attachment = Some(Attachment { file_name, size, sha256 });
+                }
+            }
+            Frame::Offer { transfer_id, size, sha256, file_name, .. } => {
+                if let Some(download) = self.downloads.get_mut(&transfer_id) {
+                    download.offered = Some(Attachment { file_name, size, sha256 });
+                }
+            }
+            Frame::Chunk { transfer_id, offset, data } => {
+                let Some(download) = self.downloads.get_mut(&transfer_id) else {
+                    return;
+                };
+                let fits = download.offered.as_ref()
+                    .is_some_and(|offered| download.data.len() as u64 + data.len() as u64 <= offered.size);
+                if offset == download.data.len() as u64 && fits {
+                    download.data.extend(data);
+                } else {
+                    self.downloads.remove(&transfer_id);
+                    self.tell(NoticeSeverity::WARNING, String::from("couldn't save the file: the server sent it garbled"));
+                }
+            }
+            Frame::Complete { transfer_id } => self.finish_download(transfer_id),
+            Frame::SentAck { nonce, message_id, timestamp } => {
+                if let Some(line) = self.lines_mut().find(|line| line.nonce == Some(nonce)) {
+                    line.message_id = Some(message_id);
+                    line.timestamp = Some(timestamp);
+                    line.nonce = None;
+                }
+            }
+            // the server replays every room's history on JOIN; only the one we're looking at is kept
+            Frame::HistoryBatch { room_id, entries } if room_id == self.room_id => {
+                self.load_history(entries);
+                self.jump();
+            }
+            Frame::Unread { room_id, room_name, last_read, unread_count } => {
+                if room_id == self.room_id && unread_count > 0 {
+                    self.divider_after = Some(last_read);
+                }
+                let wanted = self.room_wanted.take_if(|wanted| wanted.eq_ignore_ascii_case(&room_name)).is_some();
+                let room = self.rooms.entry(room_id).or_default();
+                room.name = room_name;
+                room.last_read = last_read;
+                room.unread = unread_count;
+                // a room we've just created or joined
+                if wanted {
+                    self.switch_room(room_id);
+                }
+            }
+            Frame::RoomInfo { room_id, room_name, is_public, topic, description } => {
+                let room = self.rooms.entry(room_id).or_default();
+                room.name = room_name;
+                room.is_public = is_public;
+                room.topic = topic;
+                room.description = description;
+            }
+            Frame::RoomRole { room_id, user_id, username, room_role } => {
+                let Some(room) = self.rooms.get_mut(&room_id) else {
+                    return;
+                };
+                let who = match user_id == self.user_uuid {
+                    true => {
+                        // ours comes with each room as we log in; only a change is news
+                        let before = room.role.replace(room_role);
+                        if before.is_none_or(|before| before == room_role) {
+                            return;
+                        }
+                        String::from("you're")
+                    }
+                    false => format!("{} is", username),
+                };
+                let role = match room_role {
+                    RoomRole::OWNER => "the owner",
+                    RoomRole::MODERATOR => "a moderator",
+                    _ => "a member",
+                };
+                let text = format!("{} now {} of {}", who, role, room.name);
+                self.tell(NoticeSeverity::INFO, text);
+            }
+            Frame::Invite { room_name, username, .. } => {
+                let text = format!("{} invited you to {}; /join {} to go in", username, room_name, room_name);
+                self.tell(NoticeSeverity::INFO, text);
+            }
+            Frame::MarkRead { room_id, message_id } => {
+                // another of our connections has read further
+                let username = self.username.clone();
+                let unread = match room_id == self.room_id {
+                    true => self.scrollback.iter()
+                        .filter(|line| line.message_id.is_some_and(|id| id > message_id) && line.username != username)
+                        .count() as u32,
+                    false => 0,
+                };
+                if let Some(room) = self.rooms.get_mut(&room_id).filter(|room| room.last_read < message_id) {
+                    room.last_read = message_id;
+                    room.unread = unread;
+                }
+            }
+            Frame::Session { token, .. } if token.is_empty() => {
+                // the token we tried has expired or been revoked; log in afresh
+                info!("couldn't resume our session; joining as {} instead", self.username);
+                session::forget(&server_key());
+                self.session = None;
+                if !self.username.is_empty() {
+                    self.send_frame(Frame::Join { username: self.username.clone() });
+                }
+            }
+            Frame::Challenge { nonce } => {
+                // only ever sent in answer to our KEYLOGIN
+                match self.login_key.take() {
+                    Some(key) => self.send_frame(Frame::ChallengeResponse { signature: sshkey::sign_challenge(&key, &nonce) }),
+                    None => info!("got a challenge we didn't ask for; ignoring"),
+                }
+            }
+            Frame::Session { token, expires } => {
+                let saved = SavedSession { server: server_key(), username: self.username.clone(), token, expires };
+                session::save(&saved);
+                self.session = Some(saved);
+                // an account that has sent direct messages from here keeps its key published
+                self.identity = e2e::load_identity(&server_key(), &self.username);
+                if let Some(identity) = &self.identity {
+                    let public_key = identity.public_key;
+                    self.send_frame(Frame::PublishKey { public_key });
+                }
+            }
+            Frame::IdentityKey { user_id, username, public_key } => {
+                let pending = self.pending_dms.remove(&username).unwrap_or_default();
+                let wanted = self.fingerprint_wanted.remove(&username);
+                let Some(public_key) = public_key.filter(|_| !user_id.is_nil()) else {
+                    let text = match user_id.is_nil() {
+                        true => format!("there's no user called {}", username),
+                        false => format!("{} hasn't turned on direct messages yet; sending one, or /fingerprint, does", username),
+                    };
+                    self.tell(NoticeSeverity::WARNING, text);
+                    return;
+                };
+                if !self.check_key(&username, user_id, &public_key, wanted) {
+                    // nothing goes to a changed key until the user has checked it and said /trust
+                    self.peer_keys.remove(&username);
+                    self.pending_dms.entry(username).or_default().extend(pending);
+                    return;
+                }
+                self.peer_keys.insert(username.clone(), (user_id, public_key));
+                for text in pending {
+                    self.send_direct(&username, &text);
                 }
-                let username = String::from_utf8(vec![0u8; u16::from_le_bytes(namelen) as usize]).unwrap();
-                let user_uuid = Uuid::from_u128(u128::from_le_bytes(user_uuid));
-                self.active_users.insert(username, Uuid::from(user_uuid));
+            }
+            Frame::DirectMsg { message_id, timestamp, sender_id, username, recipient_id, sender_key, recipient_key, nonce, ciphertext } => {
+                let own = sender_id == self.user_uuid;
+                let (peer_id, peer_key, own_key) = match own {
+                    true => (recipient_id, recipient_key, sender_key),
+                    false => (sender_id, sender_key, recipient_key),
+                };
+                let peer = match own {
+                    true => self.peer_keys.iter()
+                        .find(|(_, (uuid, _))| *uuid == recipient_id)
+                        .map(|(name, _)| name.clone())
+                        .unwrap_or_else(|| self.username_for(recipient_id)),
+                    false => username.clone(),
+                };
+                if !own {
+                    self.check_key(&peer, peer_id, &peer_key, false);
+                }
+                let text = match &self.identity {
+                    Some(identity) if identity.public_key == own_key =>
+                        identity.decrypt(&peer_key, &sender_id, &recipient_id, &nonce, &ciphertext)
+                            .unwrap_or_else(|| String::from("[couldn't decrypt this message; it may have been tampered with]")),
+                    _ => String::from("[encrypted for a key this client doesn't have]"),
+                };
+                if !own {
+                    let mention = Mention { message_id, parent_id: 0, timestamp: Some(timestamp), username: username.clone(), text: text.clone() };
+                    self.alert(&mention);
+                }
+                let username = match own {
+                    true => format!("{} → {}", self.username, peer),
+                    false => format!("{} → {}", username, self.username),
+                };
+                self.scrollback.push(ChatLine { kind: LineKind::Direct, timestamp: Some(timestamp), username, text, ..Default::default() });
+            }
+            Frame::Queued { entries } => {
+                // there may be more than fits in one
+                self.away.get_or_insert_default().entries.extend(entries);
+            }
+            Frame::SearchResults { search_id, hits } => {
+                if let Some(search) = self.search.as_mut().filter(|search| search.search_id == search_id) {
+                    search.pending = false;
+                    search.exhausted = hits.len() < usize::from(SEARCH_PAGE);
+                    search.hits.extend(hits);
+                }
+            }
+            Frame::Notice { severity, room_id, notice_text }
+                if room_id == ALL_ROOMS || room_id == self.room_id => {
+                self.scrollback.push(ChatLine::notice(severity, notice_text));
+            }
+            Frame::ServerShutdown { restart_eta, reason } => {
+                let text = match restart_eta {
+                    0 => format!("shutting down: {}", reason),
+                    eta => format!("shutting down: {} (back in about {}s)", reason, eta),
+                };
+                self.scrollback.push(ChatLine::notice(NoticeSeverity::CRITICAL, text));
+                self.disconnect_server();
+                self.reconnect_delay = match restart_eta {
+                    0 => RECONNECT_DELAY,
+                    eta => Duration::from_secs(eta.into()),
+                };
+                self.reconnect_at = Some(Instant::now() + self.reconnect_delay);
             }
             _ => ()
         }
     }
+
+    // a batch we asked for is an older page; one we didn't is the replay the server sends on JOIN
+    fn load_history(&mut self, entries: Vec<HistoryEntry>) {
+        let lines = entries.into_iter().map(ChatLine::from);
+        if self.history_pending {
+            self.history_pending = false;
+            let lines: Vec<ChatLine> = lines.collect();
+            if lines.is_empty() {
+                self.history_exhausted = true;
+            }
+            self.scrollback.splice(0..0, lines);
+        } else {
+            self.scrollback = lines.collect();
+            self.history_exhausted = false;
+            self.scroll_offset = 0;
+        }
+    }
+
+    // compare `public_key` with what we've seen for `username` before, and say so if it changed; or say
+    // what it is if it's new, or `always`. False if it changed, in which case it's kept aside for /trust.
+    fn check_key(&mut self, username: &str, user_id: Uuid, public_key: &[u8; PUBLIC_KEY_LEN], always: bool) -> bool {
+        let fingerprint = e2e::fingerprint(public_key);
+        let (severity, text) = match e2e::remember_key(&server_key(), &user_id, public_key) {
+            KeyCheck::Changed(old) => {
+                self.changed_keys.insert(username.to_string(), (user_id, *public_key));
+                self.tell(NoticeSeverity::WARNING, format!(
+                    "{}'s key has changed, from {} to {}. If they didn't set up a new client, check it with them \
+                     some other way; direct messages to them wait until you /trust {}.",
+                    username, e2e::fingerprint(&old), fingerprint, username));
+                return false;
+            }
+            KeyCheck::New => (NoticeSeverity::INFO, format!("{}'s key fingerprint is {}", username, fingerprint)),
+            KeyCheck::Same if always => (NoticeSeverity::INFO, format!("{}'s key fingerprint is {}", username, fingerprint)),
+            KeyCheck::Same => return true,
+        };
+        self.tell(severity, text);
+        true
+    }
+
+    // accept `username`'s changed key, and send whatever direct messages were waiting on it
+    fn trust(&mut self, username: &str) {
+        let Some((user_id, public_key)) = self.changed_keys.remove(username) else {
+            let text = format!("{}'s key hasn't changed; /fingerprint {} shows it", username, username);
+            self.tell(NoticeSeverity::WARNING, text);
+            return;
+        };
+        if !e2e::trust_key(&server_key(), &user_id, &public_key) {
+            self.changed_keys.insert(username.to_string(), (user_id, public_key));
+            return;
+        }
+        let text = format!("trusting {}'s new key, {}", username, e2e::fingerprint(&public_key));
+        self.tell(NoticeSeverity::INFO, text);
+        self.peer_keys.insert(username.to_string(), (user_id, public_key));
+        for text in self.pending_dms.remove(username).unwrap_or_default() {
+            self.send_direct(username, &text);
+        }
+    }
+
+    fn username_for(&self, user_id: Uuid) -> String {
+        self.active_users.iter()
+            .find(|(_, uuid)| **uuid == user_id)
+            .map(|(name, _)| name.clone())
+            .unwrap_or_else(|| String::from("unknown"))
+    }
 }
@@ -0,0 +1,304 @@
+// End-to-end encryption for direct messages. Each user has an X25519 identity key pair per server: the
+// secret half stays in $XDG_CONFIG_HOME/bork-bork/identity, readable only by its owner, and the public
+// half is published through the server. Two users' keys agree on a shared secret, which HKDF turns
+// into the ChaCha20-Poly1305 key for their conversation, so the server only ever sees ciphertext.
+//
+// Everyone else's keys are remembered the first time we see them, in known_keys, so that a key that
+// changes can be pointed out: it may be a new install, or it may be someone in the middle. A changed key
+// isn't used, or remembered in place of the old one, until the user says to trust it.
+//
+// Both files hold one line per key: server, user, then the key in hex, separated by tabs.
+
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use common_bork::frame::{DM_NONCE_LEN, PUBLIC_KEY_LEN};
+use hkdf::Hkdf;
+use log::{error, info};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::session::{config_file, create_private};
+
+// mixed into every conversation key, so it can't collide with a key derived for anything else
+const KEY_INFO: &[u8] = b"BorkBork direct message";
+
+/// Our key pair for one account on one server
+pub struct Identity {
+    secret:         StaticSecret,
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+}
+
+impl std::fmt::Debug for Identity {
+    // never the secret
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Identity({})", fingerprint(&self.public_key))
+    }
+}
+
+/// What we've seen of someone's key before
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyCheck {
+    New,
+    Same,
+    Changed([u8; PUBLIC_KEY_LEN]), // what it used to be
+}
+
+/// `username`'s identity on `server`, if this client has made one
+pub fn load_identity(server: &str, username: &str) -> Option<Identity> {
+    let secret: [u8; 32] = read_lines("identity").into_iter()
+        .find(|(s, u, _)| s == server && u == username)
+        .map(|(_, _, key)| key)?;
+    Some(Identity::from_secret(secret))
+}
+
+/// `username`'s identity on `server`, made and saved now if there isn't one yet
+pub fn create_identity(server: &str, username: &str) -> Option<Identity> {
+    if let Some(identity) = load_identity(server, username) {
+        return Some(identity);
+    }
+    let secret: [u8; 32] = rand::rng().random();
+    let mut lines = read_lines("identity");
+    lines.push((server.to_string(), username.to_string(), secret));
+    if !write_lines("identity", &lines) {
+        return None;
+    }
+    let identity = Identity::from_secret(secret);
+    info!("made a new identity key for {} on {}: {}", username, server, fingerprint(&identity.public_key));
+    Some(identity)
+}
+
+/// Compare `public_key` with what we've seen for `user_id` on `server` before, remembering it if it's
+/// the first. A changed key is only reported; the old one is kept until trust_key replaces it.
+pub fn remember_key(server: &str, user_id: &Uuid, public_key: &[u8; PUBLIC_KEY_LEN]) -> KeyCheck {
+    let user = user_id.to_string();
+    match read_lines("known_keys").iter().find(|(s, u, _)| s == server && *u == user) {
+        None => {
+            trust_key(server, user_id, public_key);
+            KeyCheck::New
+        }
+        Some((_, _, known)) if known == public_key => KeyCheck::Same,
+        Some((_, _, known)) => KeyCheck::Changed(*known),
+    }
+}
+
+/// Remember `public_key` as `user_id`'s on `server`, in place of whatever it was before
+pub fn trust_key(server: &str, user_id: &Uuid, public_key: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    let user = user_id.to_string();
+    let mut lines = read_lines("known_keys");
+    lines.retain(|(s, u, _)| !(s == server && *u == user));
+    lines.push((server.to_string(), user, *public_key));
+    write_lines("known_keys", &lines)
+}
+
+/// A short, readable digest of a public key, for two people to compare some other way than through the
+/// server: the first 16 bytes of its SHA-256, in groups of four hex digits
+pub fn fingerprint(public_key: &[u8; PUBLIC_KEY_LEN]) -> String {
+    let digest = Sha256::digest(public_key);
+    digest[..16].chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Identity {
+    fn from_secret(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public_key = PublicKey::from(&secret).to_bytes();
+        Identity { secret, public_key }
+    }
+
+    /// Encrypt `text` for the conversation with `peer_key`. The sender and recipient are bound into it,
+    /// so the server can't pass it off as coming from or going to anyone else.
+    pub fn encrypt(&self, peer_key: &[u8; PUBLIC_KEY_LEN], sender_id: &Uuid, recipient_id: &Uuid, text: &str)
+        -> Option<([u8; DM_NONCE_LEN], Vec<u8>)> {
+
+        let nonce: [u8; DM_NONCE_LEN] = rand::rng().random();
+        let aad = [sender_id.as_bytes().as_slice(), recipient_id.as_bytes()].concat();
+        let payload = Payload { msg: text.as_bytes(), aad: &aad };
+        match self.cipher(peer_key)?.encrypt(&Nonce::from(nonce), payload) {
+            Ok(ciphertext) => Some((nonce, ciphertext)),
+            Err(err) => {
+                error!("couldn't encrypt a direct message: {}", err);
+                None
+            }
+        }
+    }
+
+    /// The text of a message in the conversation with `peer_key`; None if it doesn't decrypt, which means
+    /// it was tampered with or wasn't for us
+    pub fn decrypt(
+        &self,
+        peer_key: &[u8; PUBLIC_KEY_LEN],
+        sender_id: &Uuid,
+        recipient_id: &Uuid,
+        nonce: &[u8; DM_NONCE_LEN],
+        ciphertext: &[u8]) -> Option<String> {
+
+        let aad = [sender_id.as_bytes().as_slice(), recipient_id.as_bytes()].concat();
+        let payload = Payload { msg: ciphertext, aad: &aad };
+        let text = self.cipher(peer_key)?.decrypt(&Nonce::from(*nonce), payload).ok()?;
+        String::from_utf8(text).ok()
+    }
+
+    // the conversation key: HKDF over our shared secret, with both public keys, smaller first, so each
+    // side derives the same one. None for a peer key that would make the secret guessable.
+    fn cipher(&self, peer_key: &[u8; PUBLIC_KEY_LEN]) -> Option<ChaCha20Poly1305> {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(*peer_key));
+        if !shared.was_contributory() {
+            error!("refusing a weak identity key: {}", fingerprint(peer_key));
+            return None;
+        }
+        let (first, second) = match self.public_key <= *peer_key {
+            true => (&self.public_key, peer_key),
+            false => (peer_key, &self.public_key),
+        };
+        let info = [KEY_INFO, first, second].concat();
+        let mut key = [0u8; 32];
+        // 32 bytes is always a length HKDF-SHA256 can give
+        Hkdf::<Sha256>::new(None, shared.as_bytes()).expand(&info, &mut key).expect("32-byte HKDF output");
+        Some(ChaCha20Poly1305::new(&Key::from(key)))
+    }
+}
+
+fn read_lines(name: &str) -> Vec<(String, String, [u8; 32])> {
+    let Some(contents) = config_file(name).and_then(|path| fs::read_to_string(path).ok()) else {
+        return Vec::new();
+    };
+    contents.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let server = fields.next()?.to_string();
+            let user = fields.next()?.to_string();
+            Some((server, user, from_hex(fields.next()?)?))
+        })
+        .collect()
+}
+
+fn write_lines(name: &str, lines: &[(String, String, [u8; 32])]) -> bool {
+    let Some(path) = config_file(name) else {
+        error!("nowhere to save {}: neither XDG_CONFIG_HOME nor HOME is set", name);
+        return false;
+    };
+    let contents: String = lines.iter()
+        .map(|(server, user, key)| format!("{}\t{}\t{}\n", server, user, to_hex(key)))
+        .collect();
+    match create_private(&path).and_then(|mut file| file.write_all(contents.as_bytes())) {
+        Ok(()) => true,
+        Err(err) => {
+            error!("couldn't save {}: {}", path.display(), err);
+            false
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<[u8; 32]> {
+    if text.len() != 64 {
+        return None;
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alice() -> Identity {
+        Identity::from_secret([1; 32])
+    }
+
+    fn bob() -> Identity {
+        Identity::from_secret([2; 32])
+    }
+
+    fn ids() -> (Uuid, Uuid) {
+        (Uuid::from_u128(1), Uuid::from_u128(2))
+    }
+
+    #[test]
+    fn round_trip_between_two_identities() {
+        let (alice, bob) = (alice(), bob());
+        let (alice_id, bob_id) = ids();
+        let (nonce, ciphertext) = alice.encrypt(&bob.public_key, &alice_id, &bob_id, "woof ☃").unwrap();
+        assert_ne!(&ciphertext[..], "woof ☃".as_bytes());
+        assert_eq!(bob.decrypt(&alice.public_key, &alice_id, &bob_id, &nonce, &ciphertext).as_deref(), Some("woof ☃"));
+        // the sender can read back what they sent
+        assert_eq!(alice.decrypt(&bob.public_key, &alice_id, &bob_id, &nonce, &ciphertext).as_deref(), Some("woof ☃"));
+    }
+
+    #[test]
+    fn tampering_is_caught() {
+        let (alice, bob) = (alice(), bob());
+        let (alice_id, bob_id) = ids();
+        let (nonce, ciphertext) = alice.encrypt(&bob.public_key, &alice_id, &bob_id, "woof").unwrap();
+        for i in 0..ciphertext.len() {
+            let mut tampered = ciphertext.clone();
+            tampered[i] ^= 1;
+            assert_eq!(bob.decrypt(&alice.public_key, &alice_id, &bob_id, &nonce, &tampered), None);
+        }
+        let mut other_nonce = nonce;
+        other_nonce[0] ^= 1;
+        assert_eq!(bob.decrypt(&alice.public_key, &alice_id, &bob_id, &other_nonce, &ciphertext), None);
+    }
+
+    #[test]
+    fn sender_and_recipient_are_bound_in() {
+        let (alice, bob) = (alice(), bob());
+        let (alice_id, bob_id) = ids();
+        let (nonce, ciphertext) = alice.encrypt(&bob.public_key, &alice_id, &bob_id, "woof").unwrap();
+        assert_eq!(bob.decrypt(&alice.public_key, &bob_id, &alice_id, &nonce, &ciphertext), None);
+        assert_eq!(bob.decrypt(&alice.public_key, &alice_id, &Uuid::from_u128(3), &nonce, &ciphertext), None);
+    }
+
+    #[test]
+    fn only_the_peer_can_read_it() {
+        let (alice, bob) = (alice(), bob());
+        let eve = Identity::from_secret([3; 32]);
+        let (alice_id, bob_id) = ids();
+        let (nonce, ciphertext) = alice.encrypt(&bob.public_key, &alice_id, &bob_id, "woof").unwrap();
+        assert_eq!(eve.decrypt(&alice.public_key, &alice_id, &bob_id, &nonce, &ciphertext), None);
+    }
+
+    #[test]
+    fn remember_key_notices_a_change_until_trusted() {
+        let dir = std::env::temp_dir().join(format!("borkbork-e2e-{}", Uuid::new_v4()));
+        // SAFETY: no other test reads or writes the environment
+        unsafe { std::env::set_var("XDG_CONFIG_HOME", &dir) };
+        let (server, user) = ("example.org:6556", Uuid::from_u128(7));
+        let (old, new) = (alice().public_key, bob().public_key);
+
+        assert_eq!(remember_key(server, &user, &old), KeyCheck::New);
+        assert_eq!(remember_key(server, &user, &old), KeyCheck::Same);
+        assert_eq!(remember_key(server, &user, &new), KeyCheck::Changed(old));
+        // still the old key until it's trusted
+        assert_eq!(remember_key(server, &user, &new), KeyCheck::Changed(old));
+        assert_eq!(remember_key(server, &user, &old), KeyCheck::Same);
+        // the same user on another server is someone else
+        assert_eq!(remember_key("example.net:6556", &user, &new), KeyCheck::New);
+
+        assert!(trust_key(server, &user, &new));
+        assert_eq!(remember_key(server, &user, &new), KeyCheck::Same);
+        assert_eq!(remember_key(server, &user, &old), KeyCheck::Changed(new));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_are_stable_and_short() {
+        let alice_print = fingerprint(&alice().public_key);
+        assert_eq!(alice_print, fingerprint(&alice().public_key));
+        assert_eq!(alice_print.split(' ').count(), 8);
+        assert_ne!(alice_print, fingerprint(&bob().public_key));
+        assert_eq!(from_hex(&to_hex(&alice().public_key)), Some(alice().public_key));
+    }
+}
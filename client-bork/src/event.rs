@@ -23,6 +23,16 @@ pub enum AppEvent {
     DisconnectServer,
     ConnectServer,
     JoinUser,
+    RequestHistory,
+    SendChat,
+    EditLast,
+    DeleteLast,
+    React(String),
+    ToggleThread,
+    CyclePresence,
+    Search,
+    SearchMore,
+    JumpToHit,
     Quit,
 }
 
@@ -33,6 +43,12 @@ pub struct EventHandler {
     receiver: mpsc::UnboundedReceiver<Event>,
 }
 
+impl Default for EventHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EventHandler {
     pub fn new() -> Self {
         let (sender, receiver) = mpsc::unbounded_channel();
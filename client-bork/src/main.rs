@@ -4,7 +4,11 @@ use crate::app::App;
 use log::{info, error, LevelFilter};
 
 pub mod app;
+pub mod e2e;
 pub mod event;
+pub mod markup;
+pub mod session;
+pub mod sshkey;
 pub mod ui;
 
 #[tokio::main]
@@ -0,0 +1,214 @@
+// Markdown-lite: the little formatting chat messages get. Messages are stored and sent exactly as
+// typed; this only decides how they're drawn.
+//
+//     *bold*  _italic_  `code`  https://links
+//     ```lang
+//     a code block, drawn in a box with its spacing kept
+//     ```
+
+/// How one line of a message is drawn. There's always one per line of text, so a message takes the
+/// same number of rows however it's formatted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Row<'a> {
+    Text(&'a str),
+    CodeStart(&'a str), // the opening ``` line, with whatever label follows it
+    Code(&'a str),
+    CodeEnd,
+}
+
+/// A piece of one line of text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Inline<'a> {
+    Plain(&'a str),
+    Bold(&'a str),
+    Italic(&'a str),
+    Code(&'a str),
+    Url(&'a str),
+}
+
+const FENCE: &str = "```";
+// punctuation that ends a sentence rather than a link
+const URL_TRAILERS: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '\'', '"'];
+
+/// Split a message into rows: text, and code blocks fenced by lines starting ```. A block nobody
+/// closed runs to the end of the message.
+pub fn rows(text: &str) -> Vec<Row<'_>> {
+    let mut rows = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        let fence = line.trim_start().strip_prefix(FENCE);
+        rows.push(match (in_code, fence) {
+            (false, Some(label)) if !label.contains(FENCE) => {
+                in_code = true;
+                Row::CodeStart(label.trim())
+            }
+            (true, Some(rest)) if rest.trim().is_empty() => {
+                in_code = false;
+                Row::CodeEnd
+            }
+            (true, _) => Row::Code(line),
+            (false, _) => Row::Text(line),
+        });
+    }
+    rows
+}
+
+/// Split one line of text into its plain, emphasised, code and link pieces. Markers only count where
+/// they can't be part of a word, so snake_case and 2*3*4 stay as they are; a marker with no partner is
+/// just text.
+pub fn inline(line: &str) -> Vec<Inline<'_>> {
+    let mut pieces = Vec::new();
+    let mut plain = 0; // start of the plain text not yet pushed
+    let mut at = 0;
+    while at < line.len() {
+        let rest = &line[at..];
+        let found = if let Some(code) = rest.strip_prefix(FENCE) {
+            // ```code``` on one line is inline code too
+            code.find(FENCE).map(|end| (Inline::Code(&code[..end]), end + 2 * FENCE.len()))
+        } else if let Some(code) = rest.strip_prefix('`') {
+            code.find('`').filter(|&end| end > 0).map(|end| (Inline::Code(&code[..end]), end + 2))
+        } else if rest.starts_with("http://") || rest.starts_with("https://") {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let url = rest[..end].trim_end_matches(URL_TRAILERS);
+            Some((Inline::Url(url), url.len()))
+        } else if rest.starts_with(['*', '_']) && opens(line, at) {
+            emphasis(line, at)
+        } else {
+            None
+        };
+        match found {
+            Some((piece, len)) => {
+                if plain < at {
+                    pieces.push(Inline::Plain(&line[plain..at]));
+                }
+                pieces.push(piece);
+                at += len;
+                plain = at;
+            }
+            None => at += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    if plain < line.len() {
+        pieces.push(Inline::Plain(&line[plain..]));
+    }
+    pieces
+}
+
+// a * or _ at `at` can open emphasis: it doesn't follow a letter or digit, and something other than
+// space comes after it
+fn opens(line: &str, at: usize) -> bool {
+    let before = line[..at].chars().next_back();
+    let after = line[at + 1..].chars().next();
+    !before.is_some_and(char::is_alphanumeric) && after.is_some_and(|c| !c.is_whitespace())
+}
+
+// the emphasis opened at `at`, and how many bytes it takes up with its markers: it closes at the next
+// matching marker that follows something other than space and isn't followed by a letter or digit
+fn emphasis(line: &str, at: usize) -> Option<(Inline<'_>, usize)> {
+    let marker = line[at..].chars().next()?;
+    let body = &line[at + 1..];
+    let end = body.match_indices(marker).map(|(index, _)| index).find(|&index| {
+        index > 0
+            && !body[..index].ends_with(char::is_whitespace)
+            && !body[index + 1..].starts_with(char::is_alphanumeric)
+    })?;
+    let text = &body[..end];
+    let piece = if marker == '*' { Inline::Bold(text) } else { Inline::Italic(text) };
+    Some((piece, end + 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Inline::*;
+
+    #[test]
+    fn rows_fence_code_blocks() {
+        let text = "look:\n```rust\nlet x = 1;\n\n```\ndone";
+        assert_eq!(rows(text), [
+            Row::Text("look:"),
+            Row::CodeStart("rust"),
+            Row::Code("let x = 1;"),
+            Row::Code(""),
+            Row::CodeEnd,
+            Row::Text("done"),
+        ]);
+    }
+
+    #[test]
+    fn rows_run_an_unclosed_block_to_the_end() {
+        assert_eq!(rows("```\nfn main() {\n    ```not a fence"), [
+            Row::CodeStart(""),
+            Row::Code("fn main() {"),
+            Row::Code("    ```not a fence"),
+        ]);
+    }
+
+    #[test]
+    fn rows_leave_one_line_code_as_text() {
+        assert_eq!(rows("```x``` is inline"), [Row::Text("```x``` is inline")]);
+    }
+
+    #[test]
+    fn rows_keep_one_row_per_line() {
+        let text = "a\n```\nb\n```\n```\nc";
+        assert_eq!(rows(text).len(), text.lines().count());
+    }
+
+    #[test]
+    fn inline_emphasis_and_code() {
+        assert_eq!(inline("*bold* and _italic_ and `code`"), [
+            Bold("bold"),
+            Plain(" and "),
+            Italic("italic"),
+            Plain(" and "),
+            Code("code"),
+        ]);
+        assert_eq!(inline("say ```x `y` z```"), [Plain("say "), Code("x `y` z")]);
+    }
+
+    #[test]
+    fn inline_leaves_markers_inside_words_alone() {
+        assert_eq!(inline("snake_case_name"), [Plain("snake_case_name")]);
+        assert_eq!(inline("2*3*4"), [Plain("2*3*4")]);
+        assert_eq!(inline("a * b * c"), [Plain("a * b * c")]);
+        assert_eq!(inline("*bold *"), [Plain("*bold *")]);
+    }
+
+    #[test]
+    fn inline_treats_unpartnered_markers_as_text() {
+        assert_eq!(inline("*oops"), [Plain("*oops")]);
+        assert_eq!(inline("an ` alone"), [Plain("an ` alone")]);
+        assert_eq!(inline("``"), [Plain("``")]);
+    }
+
+    #[test]
+    fn emphasis_closes_at_a_marker_ending_a_word() {
+        assert_eq!(inline("*a*b* c"), [Bold("a*b"), Plain(" c")]);
+        assert_eq!(emphasis("_x_y_", 0), Some((Italic("x_y"), 5)));
+        assert_eq!(emphasis("*x", 0), None);
+    }
+
+    #[test]
+    fn inline_urls_drop_trailing_punctuation() {
+        assert_eq!(inline("see https://example.com/a."), [
+            Plain("see "),
+            Url("https://example.com/a"),
+            Plain("."),
+        ]);
+        assert_eq!(inline("(http://example.com/?q=1)!"), [
+            Plain("("),
+            Url("http://example.com/?q=1"),
+            Plain(")!"),
+        ]);
+        assert_eq!(inline("https://example.com/*not*bold"), [Url("https://example.com/*not*bold")]);
+    }
+
+    #[test]
+    fn inline_handles_multibyte_text() {
+        assert_eq!(inline("héllo *wörld* ☃"), [Plain("héllo "), Bold("wörld"), Plain(" ☃")]);
+        assert_eq!(inline("é*x*"), [Plain("é*x*")]);
+        assert_eq!(inline("日本_語_"), [Plain("日本_語_")]);
+        assert_eq!(inline("→ `ü`"), [Plain("→ "), Code("ü")]);
+    }
+}
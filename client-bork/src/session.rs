@@ -0,0 +1,115 @@
+// The session token the server hands us when we log in, kept on disk so that reconnecting, or starting
+// the client again later, can RESUME rather than log in from scratch. A token is as good as a password
+// while it lasts, so the file is only readable by its owner.
+//
+// One line per server: server, username, token and expiry, separated by tabs.
+
+use log::{error, info};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A login the server will let us resume
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedSession {
+    pub server:     String, // host:port the token is good for
+    pub username:   String,
+    pub token:      String,
+    pub expires:    u64,    // unix time
+}
+
+/// The session saved for `server`, unless it has expired
+pub fn load(server: &str) -> Option<SavedSession> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs());
+    read_all().into_iter().find(|saved| saved.server == server && saved.expires > now)
+}
+
+/// Remember `session`, in place of whatever was saved for its server
+pub fn save(session: &SavedSession) {
+    let mut sessions = read_all();
+    sessions.retain(|saved| saved.server != session.server);
+    sessions.push(session.clone());
+    write_all(&sessions);
+}
+
+/// Forget the session saved for `server`
+pub fn forget(server: &str) {
+    let mut sessions = read_all();
+    let count = sessions.len();
+    sessions.retain(|saved| saved.server != server);
+    if sessions.len() != count {
+        write_all(&sessions);
+    }
+}
+
+// $XDG_CONFIG_HOME/bork-bork/session, or ~/.config/bork-bork/session
+fn path() -> Option<PathBuf> {
+    config_file("session")
+}
+
+/// `name` in the client's config directory: $XDG_CONFIG_HOME/bork-bork, or ~/.config/bork-bork
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("bork-bork").join(name))
+}
+
+fn read_all() -> Vec<SavedSession> {
+    let Some(contents) = path().and_then(|path| fs::read_to_string(path).ok()) else {
+        return Vec::new();
+    };
+    contents.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            Some(SavedSession {
+                server: fields.next()?.to_string(),
+                username: fields.next()?.to_string(),
+                token: fields.next()?.to_string(),
+                expires: fields.next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+fn write_all(sessions: &[SavedSession]) {
+    let Some(path) = path() else {
+        error!("nowhere to save the session token: neither XDG_CONFIG_HOME nor HOME is set");
+        return;
+    };
+    let contents: String = sessions.iter()
+        .map(|saved| format!("{}\t{}\t{}\t{}\n", saved.server, saved.username, saved.token, saved.expires))
+        .collect();
+    let written = create_private(&path).and_then(|mut file| file.write_all(contents.as_bytes()));
+    match written {
+        Ok(()) => info!("saved session tokens to {}", path.display()),
+        Err(err) => error!("couldn't save session tokens to {}: {}", path.display(), err),
+    }
+}
+
+/// Open `path` for writing from scratch, readable by nobody but us; the directory too
+pub fn create_private(path: &PathBuf) -> std::io::Result<fs::File> {
+    let mut dir = fs::DirBuilder::new();
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        dir.mode(0o700);
+        options.mode(0o600);
+    }
+    if let Some(parent) = path.parent() {
+        dir.recursive(true).create(parent)?;
+    }
+    let file = options.open(path)?;
+    // mode only applies to a file we create; one left by something else gets tightened
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
@@ -0,0 +1,54 @@
+// The ed25519 key the client logs in with: the private half of an OpenSSH key pair, as ssh-keygen
+// writes it, so whatever key the user already has for SSH will do. It's BORK_SSH_KEY if that's set, or
+// ~/.ssh/id_ed25519. Keys protected by a passphrase can't be read.
+
+use common_bork::frame::{CHALLENGE_CONTEXT, NONCE_LEN, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use ed25519_dalek::{Signer, SigningKey};
+use log::error;
+use ssh_key::{HashAlg, PrivateKey};
+use ssh_key::public::{Ed25519PublicKey, KeyData};
+use std::env;
+use std::path::PathBuf;
+
+/// Read the key, or log why it couldn't be
+pub fn load() -> Option<SigningKey> {
+    let Some(path) = path() else {
+        error!("no key to use: neither BORK_SSH_KEY nor HOME is set");
+        return None;
+    };
+    let key = match PrivateKey::read_openssh_file(&path) {
+        Ok(key) => key,
+        Err(err) => {
+            error!("couldn't read the key in {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    if key.is_encrypted() {
+        error!("the key in {} has a passphrase, which isn't supported; point BORK_SSH_KEY at one without", path.display());
+        return None;
+    }
+    match key.key_data().ed25519() {
+        Some(keypair) => Some(SigningKey::from_bytes(&keypair.private.to_bytes())),
+        None => {
+            error!("the key in {} isn't an ed25519 key", path.display());
+            None
+        }
+    }
+}
+
+/// The answer to a CHALLENGE that sent `nonce`
+pub fn sign_challenge(key: &SigningKey, nonce: &[u8; NONCE_LEN]) -> [u8; SIGNATURE_LEN] {
+    key.sign(&[CHALLENGE_CONTEXT, &nonce[..]].concat()).to_bytes()
+}
+
+/// The fingerprint ssh-keygen -l would show for `public_key` (SHA256:...), to tell keys apart by
+pub fn fingerprint(public_key: &[u8; PUBLIC_KEY_LEN]) -> String {
+    KeyData::Ed25519(Ed25519PublicKey(*public_key)).fingerprint(HashAlg::Sha256).to_string()
+}
+
+fn path() -> Option<PathBuf> {
+    match env::var_os("BORK_SSH_KEY").filter(|path| !path.is_empty()) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from(env::var_os("HOME")?).join(".ssh").join("id_ed25519")),
+    }
+}
@@ -3,12 +3,379 @@ use ratatui::symbols::border;
 use ratatui::{
     buffer::Buffer,
     layout::{Alignment, Constraint, Direction, Layout, Rect},
-    style::{Color, Stylize},
+    style::{Color, Style, Stylize},
     text::{Line, Span, Text},
-    widgets::{Block, Borders, BorderType, Paragraph, Widget},
+    widgets::{Block, Borders, BorderType, Clear, Paragraph, Widget},
 };
 
-use crate::app::App;
+use chrono::{DateTime, Local};
+use common_bork::{NoticeSeverity, PresenceStatus, SearchHit};
+use common_bork::mention::find_mentions;
+
+use crate::app::{App, AwayView, ChatLine, InputMode, LineKind, Presence, SearchView};
+use crate::markup::{self, Inline, Row};
+
+// what a tab in a code block is drawn as
+const TAB: &str = "    ";
+// most mentions the Mentions pane has room for
+const MENTIONS_SHOWN: usize = 5;
+
+// HH:MM in the user's timezone
+fn local_time(timestamp: u64) -> String {
+    match DateTime::from_timestamp(timestamp as i64, 0) {
+        Some(utc) => utc.with_timezone(&Local).format("%H:%M").to_string(),
+        None => String::from("--:--"),
+    }
+}
+
+// 512 B, 3.4 KiB, 12.0 MiB
+fn human_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+// the middle `percent` of `area`, each way
+// the input box as shown: passwords typed into a command come out as stars
+fn masked_input(input: &str) -> String {
+    // how many words, the command included, come before the secret ones
+    let shown_words = match input.split(' ').next() {
+        Some("/login") => 2,
+        Some("/password") => 1,
+        Some("/reset") => 3,
+        _ => return input.to_string(),
+    };
+    input.split_inclusive(' ')
+        .enumerate()
+        .map(|(index, word)| match index < shown_words {
+            true => word.to_string(),
+            false => word.chars().map(|c| if c == ' ' { c } else { '*' }).collect(),
+        })
+        .collect()
+}
+
+fn centered(area: Rect, percent_x: u16, percent_y: u16) -> Rect {
+    let width = area.width * percent_x / 100;
+    let height = area.height * percent_y / 100;
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    }
+}
+
+// the search overlay: the query being typed, how the search went, then one row per hit
+fn search_lines(search: &SearchView) -> Vec<Line<'_>> {
+    let mut lines = vec![
+        Line::from(vec!["Search: ".gray().bold(), Span::raw(search.query.as_str()), "_".slow_blink()]),
+        Line::from(" from:<user> in:<room> since:<yyyy-mm-dd> until:<yyyy-mm-dd>").dark_gray(),
+    ];
+    let status = match &search.error {
+        Some(error) => Line::from(error.as_str()).yellow(),
+        None if search.pending && search.hits.is_empty() => Line::from("searching…").dark_gray().italic(),
+        None if search.searched.is_empty() => Line::default(),
+        None if search.hits.is_empty() => Line::from("no matches").dark_gray().italic(),
+        None => Line::from(match (search.hits.len(), search.exhausted) {
+            (1, true) => String::from("1 match"),
+            (count, true) => format!("{} matches", count),
+            (count, false) => format!("{}+ matches", count),
+        }).dark_gray(),
+    };
+    lines.push(status);
+    for (index, hit) in search.hits.iter().enumerate() {
+        lines.push(hit_line(hit, index == search.selected));
+    }
+    lines
+}
+
+// the "while you were away" overlay: how much there is, then one row per message
+fn away_lines(away: &AwayView) -> Vec<Line<'_>> {
+    let count = match away.entries.len() {
+        1 => String::from("1 message mentioned you while you were away"),
+        count => format!("{} messages mentioned you while you were away", count),
+    };
+    let mut lines = vec![Line::from(count).dark_gray()];
+    for (index, hit) in away.entries.iter().enumerate() {
+        lines.push(hit_line(hit, index == away.selected));
+    }
+    lines
+}
+
+// one row for a message found outside the chat pane: its time, who said it, and its first line
+fn hit_line(hit: &SearchHit, selected: bool) -> Line<'_> {
+    let entry = &hit.entry;
+    let mut name = format!("{}: ", entry.username).cyan().bold();
+    if selected {
+        name = name.reversed();
+    }
+    Line::from(vec![
+        format!("{} ", local_time(entry.timestamp)).dark_gray(),
+        name,
+        Span::raw(entry.message_text.lines().next().unwrap_or("")),
+    ])
+}
+
+// how a presence reads and looks, and where it sorts: the reachable first, the away last
+fn presence_style(status: u8) -> (&'static str, Style, u8) {
+    match status {
+        PresenceStatus::AWAY => ("Away", Style::new().yellow(), 2),
+        PresenceStatus::DO_NOT_DISTURB => ("Do not disturb", Style::new().red(), 1),
+        _ => ("Online", Style::new().green(), 0),
+    }
+}
+
+// one row of a message's text, with every @name of someone we know picked out; our own stands out most
+fn text_spans<'a>(text: &'a str, style: Style, names: &[&str], own: &str) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    let mut end = 0;
+    for (range, name) in find_mentions(text, names) {
+        spans.push(Span::styled(&text[end..range.start], style));
+        let mention = match name == own {
+            true => style.yellow().bold().reversed(),
+            false => style.cyan().bold(),
+        };
+        spans.push(Span::styled(&text[range.clone()], mention));
+        end = range.end;
+    }
+    spans.push(Span::styled(&text[end..], style));
+    spans
+}
+
+// a message's text, formatted, as the spans of each of its rows. Code blocks are boxed, with their
+// fences as the top and bottom of the box, so there's still one row per line of text.
+fn formatted<'a>(text: &'a str, style: Style, code_style: Style, names: &[&str], own: &str) -> Vec<Vec<Span<'a>>> {
+    let rows = markup::rows(text);
+    // each box is as wide as its widest line
+    let mut widths = Vec::new();
+    for row in &rows {
+        match row {
+            Row::CodeStart(label) => widths.push(label.chars().count() + 3),
+            Row::Code(line) => if let Some(width) = widths.last_mut() {
+                let columns: usize = line.chars().map(|c| if c == '\t' { TAB.len() } else { 1 }).sum();
+                *width = (*width).max(columns + 1);
+            },
+            _ => (),
+        }
+    }
+    let mut widths = widths.into_iter();
+    let mut width = 0;
+    rows.into_iter().map(|row| match row {
+        Row::Text(line) => markup::inline(line).into_iter().flat_map(|piece| match piece {
+            Inline::Plain(text) => text_spans(text, style, names, own),
+            Inline::Bold(text) => text_spans(text, style.bold(), names, own),
+            Inline::Italic(text) => text_spans(text, style.italic(), names, own),
+            Inline::Code(text) => vec![Span::styled(text, code_style)],
+            Inline::Url(url) => vec![Span::styled(url, style.blue().underlined())],
+        }).collect(),
+        Row::CodeStart(label) => {
+            width = widths.next().unwrap_or(0);
+            let top = match label {
+                "" => format!("┌{}", "─".repeat(width + 1)),
+                label => format!("┌─ {} {}", label, "─".repeat(width - label.chars().count() - 2)),
+            };
+            vec![top.dark_gray()]
+        }
+        // tabs would be lost; spacing is the point of a code block
+        Row::Code(line) => vec!["│ ".dark_gray(), Span::styled(line.replace('\t', TAB), code_style)],
+        Row::CodeEnd => vec![format!("└{}", "─".repeat(width + 1)).dark_gray()],
+    }).collect()
+}
+
+// the rows one message takes up: the message itself, then its reaction badges. `names` are the users
+// whose @mentions get picked out, and `own` is ours.
+fn message_lines<'a>(chat_line: &'a ChatLine, selected: bool, names: &[&str], own: &str) -> Vec<Line<'a>> {
+    if let LineKind::Notice(severity) = chat_line.kind {
+        // notices come from the server, not a user; set them apart from chat
+        let style = match severity {
+            NoticeSeverity::CRITICAL => Style::new().red().bold(),
+            NoticeSeverity::WARNING => Style::new().yellow().bold(),
+            _ => Style::new().magenta().italic(),
+        };
+        let mut text_lines = chat_line.text.lines();
+        let mut lines = vec![Line::styled(format!("*** {}", text_lines.next().unwrap_or("")), style)];
+        lines.extend(text_lines.map(|l| Line::styled(format!("    {}", l), style)));
+        return lines;
+    }
+    let time = match chat_line.timestamp {
+        Some(timestamp) => format!("{} ", local_time(timestamp)),
+        None => String::from("--:-- "),
+    };
+    let mut name = match chat_line.kind {
+        LineKind::Direct => format!("[dm] {}: ", chat_line.username).magenta().bold(),
+        _ => format!("{}: ", chat_line.username).cyan().bold(),
+    };
+    if selected {
+        name = name.reversed();
+    }
+    if chat_line.deleted {
+        return vec![vec![time.dark_gray(), name, "message deleted".dark_gray().italic()].into()];
+    }
+    // messages the server hasn't acknowledged yet are dimmed, and have no time to show; messages that
+    // mention us are hard to miss
+    let text_style = match chat_line {
+        line if line.is_pending() => Style::new().dark_gray(),
+        line if line.mentions_me => Style::new().yellow(),
+        _ => Style::new(),
+    };
+    let mut lines: Vec<Line> = match &chat_line.attachment {
+        Some(attachment) => {
+            let file = format!("[file] {} ({})", attachment.file_name, human_size(attachment.size));
+            let file_style = if chat_line.is_pending() { Style::new().dark_gray() } else { Style::new().blue() };
+            vec![vec![time.dark_gray(), name, Span::styled(file, file_style)].into()]
+        }
+        None => {
+            let code_style = if chat_line.is_pending() { text_style } else { Style::new().green() };
+            let mut rows = formatted(&chat_line.text, text_style, code_style, names, own).into_iter();
+            let mut spans = vec![time.dark_gray(), name];
+            spans.extend(rows.next().unwrap_or_default());
+            let mut lines: Vec<Line> = vec![spans.into()];
+            lines.extend(rows.map(|row| {
+                let mut spans = vec![Span::raw("    ")];
+                spans.extend(row);
+                Line::from(spans)
+            }));
+            lines
+        }
+    };
+    if let Some(last) = lines.last_mut() {
+        if chat_line.edited {
+            last.push_span(" (edited)".dark_gray());
+        }
+        match chat_line.reply_count {
+            0 => (),
+            1 => last.push_span(" [1 reply]".blue()),
+            n => last.push_span(format!(" [{} replies]", n).blue()),
+        }
+    }
+    if !chat_line.reactions.is_empty() {
+        // one badge per reaction; ours stand out
+        let mut badges = vec![Span::raw("      ")];
+        for reaction in &chat_line.reactions {
+            let badge = format!("[{} {}]", reaction.reaction, reaction.count);
+            badges.push(match chat_line.own_reactions.contains(&reaction.reaction) {
+                true => badge.yellow(),
+                false => badge.dark_gray(),
+            });
+            badges.push(Span::raw(" "));
+        }
+        lines.push(badges.into());
+    }
+    lines
+}
+
+impl App {
+    /// Every row of the chat pane: the welcome banner, then the scrollback, oldest first
+    fn chat_lines(&self) -> Vec<Line<'_>> {
+        let mut lines: Vec<Line> = self.welcome.lines().map(Line::from).collect();
+        let divider = self.divider_index();
+        let names = self.user_names();
+        for (index, chat_line) in self.scrollback.iter().enumerate() {
+            if divider == Some(index) {
+                lines.push(Line::from("──── new messages ────").red().centered());
+            }
+            let selected = self.selected.is_some() && chat_line.message_id == self.selected;
+            lines.extend(message_lines(chat_line, selected, &names, &self.username));
+        }
+        lines
+    }
+
+    // everyone an @mention can be picked out for
+    fn user_names(&self) -> Vec<&str> {
+        self.active_users.keys().map(String::as_str).collect()
+    }
+
+    /// The Mentions pane: the newest messages that mention us, newest first
+    fn mention_lines(&self) -> Vec<Line<'_>> {
+        self.mentions.iter().rev().map(|mention| {
+            let time = match mention.timestamp {
+                Some(timestamp) => format!("{} ", local_time(timestamp)),
+                None => String::from("--:-- "),
+            };
+            Line::from(vec![
+                time.dark_gray(),
+                format!("{}: ", mention.username).cyan().bold(),
+                Span::raw(mention.text.lines().next().unwrap_or("")),
+            ])
+        }).collect()
+    }
+
+    /// The Rooms pane: every room we're in, with how much we haven't read
+    fn room_lines(&self) -> Vec<Line<'_>> {
+        self.rooms.iter().map(|(&room_id, room)| {
+            let mut name = Span::raw(room.name.as_str());
+            if room_id == self.room_id {
+                name = name.bold();
+            }
+            let mut line = Line::from(name);
+            if !room.is_public {
+                line.push_span(" (private)".dark_gray());
+            }
+            if room.unread > 0 {
+                line.push_span(format!(" ({})", room.unread).yellow().bold());
+            }
+            line
+        }).collect()
+    }
+
+    /// The Users pane: one row per user, grouped by presence, then by name
+    fn user_lines(&self) -> Vec<Line<'_>> {
+        let unknown = Presence::default();
+        let mut users: Vec<(&String, &Presence)> = self.active_users.iter()
+            .map(|(name, uuid)| (name, self.presence.get(uuid).unwrap_or(&unknown)))
+            .collect();
+        // active_users is in name order already, and the sort is stable
+        users.sort_by_key(|(_, presence)| presence_style(presence.status).2);
+        users.into_iter().map(|(name, presence)| {
+            let (_, style, _) = presence_style(presence.status);
+            let mut line = Line::from(Span::styled(name.clone(), style));
+            if !presence.text.is_empty() {
+                line.push_span(format!(" {}", presence.text).dark_gray().italic());
+            }
+            line
+        }).collect()
+    }
+
+    /// "Guest42 is typing…" and friends, for the row under the chat pane
+    fn typing_line(&self) -> Line<'_> {
+        let text = match self.typing_names().as_slice() {
+            [] => return Line::default(),
+            [one] => format!(" {} is typing…", one),
+            [one, two] => format!(" {} and {} are typing…", one, two),
+            [one, two, three] => format!(" {}, {} and {} are typing…", one, two, three),
+            _ => String::from(" several people are typing…"),
+        };
+        Line::from(text).dark_gray().italic()
+    }
+
+    /// Rows of the thread panel: the message that started it, then its replies
+    fn thread_lines(&self) -> Vec<Line<'_>> {
+        let mut lines = Vec::new();
+        let Some(thread) = &self.thread else {
+            return lines;
+        };
+        let names = self.user_names();
+        if let Some(parent) = self.scrollback.iter().find(|line| line.message_id == Some(thread.parent_id)) {
+            lines.extend(message_lines(parent, false, &names, &self.username));
+        }
+        lines.push(Line::from("────").dark_gray());
+        if thread.replies.is_empty() {
+            lines.push(Line::from("no replies yet").dark_gray().italic());
+        }
+        for reply in &thread.replies {
+            lines.extend(message_lines(reply, false, &names, &self.username));
+        }
+        lines
+    }
+}
 
 impl Widget for &App {
     fn render(self, area: Rect, buf: &mut Buffer) {
@@ -20,15 +387,25 @@ impl Widget for &App {
                 Constraint::Percentage(100),
             ])
             .split(area);
+        // an open thread takes the Users pane's place, with more room
+        let side_width = if self.thread.is_some() { 40 } else { 20 };
         let inner_layout = Layout::default()
             .direction(Direction::Horizontal)
             .constraints([
-                Constraint::Percentage(80),
-                Constraint::Percentage(20),
+                Constraint::Percentage(100 - side_width),
+                Constraint::Percentage(side_width),
             ])
             .split(outer_layout[2]);
         let title = Line::from(" BorkBork ");
-        let footer = Line::from( "Press (q) or (Ctrl+c) to quit ");
+        let footer = match self.input_mode {
+            InputMode::Normal => Line::from(
+                " (c)onnect | (j)oin | (i) write | (e)dit/(x) delete last | (b)ork | ([/]) select | (t)hread | (p)resence | (Ctrl+f) search | (d)isconnect | (q)uit "),
+            InputMode::Editing if self.editing_message.is_some() =>
+                Line::from(" (Enter) save edit | (Esc) cancel edit | (Ctrl+c) quit "),
+            InputMode::Editing if self.thread.is_some() =>
+                Line::from(" (Enter) reply in thread | (Esc) stop writing | (Ctrl+c) quit "),
+            InputMode::Editing => Line::from(" (Enter) send | (Tab) complete @name | (Esc) stop writing | (Ctrl+c) quit "),
+        };
         let header_block = Block::bordered()
             .title(title.centered())
             .title_bottom(footer.centered())
@@ -36,22 +413,55 @@ impl Widget for &App {
 
         let status_line:Line = vec![
             " Sever: ".gray().bold(),
-            format!("{}", self.server_address).cyan(),
+            self.server_address.clone().cyan(),
             " | ".into(),
             "Username: ".gray().bold(),
-            "Guest".cyan(),
+            self.username.clone().cyan(),
             " | ".into(),
             "Status: ".gray().bold(),
-            {if self.connected == true {"Online".green()} else {"Offline".red()}},
+            match self.reconnect_at {
+                _ if self.connected => "Online".green(),
+                Some(at) => format!(
+                    "Reconnecting in {}s",
+                    at.saturating_duration_since(std::time::Instant::now()).as_secs()
+                ).yellow(),
+                None => "Offline".red(),
+            },
+            " | ".into(),
+            "Presence: ".gray().bold(),
+            {
+                let (label, style, _) = presence_style(self.status.status);
+                match self.auto_away {
+                    true => Span::styled(format!("{} (idle)", label), style),
+                    false => Span::styled(label, style),
+                }
+            },
             " | ".into(),
             " Server Version: ".gray().bold(),
             format!("{}.{}.{}", self.server_major_ver, self.server_minor_ver, self.server_subminor_ver).into(),
+            " | ".into(),
+            "Latency: ".gray().bold(),
+            match self.latency {
+                Some(latency) => format!("{}ms", latency.as_millis()).into(),
+                None => "-".gray(),
+            },
         ].into();
         let status_paragraph = Paragraph::new(status_line)
             .block(header_block);
         status_paragraph.render(outer_layout[1], buf);
 
-        let chat_title = Line::from(" Chat ");
+        // the room we're in, and what it's about
+        let chat_title = match self.rooms.get(&self.room_id) {
+            Some(room) if !room.topic.is_empty() => Line::from(vec![
+                Span::raw(" "),
+                room.name.as_str().bold(),
+                " — ".dark_gray(),
+                Span::raw(room.topic.as_str()),
+                Span::raw(" "),
+            ]),
+            Some(room) => Line::from(vec![Span::raw(" "), room.name.as_str().bold(), Span::raw(" ")]),
+            None => Line::from(" Chat "),
+        };
         let users_title = Line::from(" Users ");
         let chat_block = Block::bordered()
             .title(chat_title.centered())
@@ -60,27 +470,106 @@ impl Widget for &App {
             .direction(Direction::Vertical)
             .constraints([
                 Constraint::Percentage(100),
+                Constraint::Length(1),
                 Constraint::Length(3),
             ])
             .split(inner_layout[0]);
         let send_message_block = Block::bordered()
             .border_set(border::DOUBLE);
+        let send_message_text = match self.input_mode {
+            InputMode::Normal => Paragraph::new(masked_input(&self.input).gray()),
+            InputMode::Editing => Paragraph::new(Line::from(vec![Span::raw(masked_input(&self.input)), "_".slow_blink()])),
+        }.block(send_message_block);
         let recv_messages_block = Block::bordered()
             .border_set(border::EMPTY);
-        let recv_messages_text = Paragraph::new(String::from(str::from_utf8(&self.inbuffer).unwrap()))
+        // keep the newest messages in view unless the user has scrolled up
+        let chat_height = recv_messages_block.inner(chat_inner_layout[0]).height;
+        self.chat_height.set(chat_height);
+        let top = self.chat_row_count()
+            .saturating_sub(chat_height)
+            .saturating_sub(self.scroll_offset);
+        let recv_messages_text = Paragraph::new(self.chat_lines())
+            .scroll((top, 0))
             .block(recv_messages_block);
          let users_block = Block::bordered()
             .title(users_title.centered())
             .border_set(border::ROUNDED);
-        let users_str = self.active_users.keys()
-            .map(|s| format!("{}\n", s))
-            .collect::<Vec<_>>()
-            .concat();
-        let users_text = Paragraph::new(users_str)
+        let users_text = Paragraph::new(self.user_lines())
             .block(users_block);
         recv_messages_text.render(chat_inner_layout[0], buf);
-        send_message_block.render(chat_inner_layout[1], buf);
+        self.typing_line().render(chat_inner_layout[1], buf);
+        send_message_text.render(chat_inner_layout[2], buf);
         chat_block.render(inner_layout[0], buf);
-        users_text.render(inner_layout[1], buf);
+        if self.thread.is_some() {
+            let thread_block = Block::bordered()
+                .title(Line::from(" Thread ").centered())
+                .title_bottom(Line::from(" (t) close ").centered())
+                .border_set(border::ROUNDED);
+            // like the chat pane, keep the newest replies in view
+            let lines = self.thread_lines();
+            let height = thread_block.inner(inner_layout[1]).height;
+            let top = u16::try_from(lines.len()).unwrap_or(u16::MAX).saturating_sub(height);
+            Paragraph::new(lines)
+                .scroll((top, 0))
+                .block(thread_block)
+                .render(inner_layout[1], buf);
+        } else {
+            // rooms above users, as tall as the list of rooms, with the newest mentions of us in between
+            // once there are any
+            let rooms_height = u16::try_from(self.rooms.len()).unwrap_or(u16::MAX).max(1).saturating_add(2);
+            let mentions_height = match self.mentions.len() {
+                0 => 0,
+                count => u16::try_from(count.min(MENTIONS_SHOWN)).unwrap_or(0) + 2,
+            };
+            let side_layout = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(rooms_height), Constraint::Length(mentions_height), Constraint::Fill(1)])
+                .split(inner_layout[1]);
+            Paragraph::new(self.room_lines())
+                .block(Block::bordered().title(Line::from(" Rooms ").centered()).border_set(border::ROUNDED))
+                .render(side_layout[0], buf);
+            if !self.mentions.is_empty() {
+                let mentions_block = Block::bordered()
+                    .title(Line::from(" Mentions ").centered())
+                    .title_bottom(Line::from(" (m) jump ").centered())
+                    .border_set(border::ROUNDED);
+                Paragraph::new(self.mention_lines())
+                    .block(mentions_block)
+                    .render(side_layout[1], buf);
+            }
+            users_text.render(side_layout[2], buf);
+        }
+        if let Some(away) = &self.away {
+            let area = centered(inner_layout[0], 90, 70);
+            let away_block = Block::bordered()
+                .title(Line::from(" While you were away ").centered())
+                .title_bottom(Line::from(" (Enter) jump | (↑/↓) select | (Esc) close ").centered())
+                .border_set(border::ROUNDED);
+            // the count stays put; the messages scroll to keep the selection in view
+            let height = away_block.inner(area).height.saturating_sub(1);
+            let top = u16::try_from(away.selected).unwrap_or(u16::MAX).saturating_sub(height.saturating_sub(1));
+            let mut lines = away_lines(away);
+            lines.drain(1..1 + usize::from(top).min(lines.len() - 1));
+            Clear.render(area, buf);
+            Paragraph::new(lines)
+                .block(away_block)
+                .render(area, buf);
+        }
+        if let Some(search) = &self.search {
+            let area = centered(inner_layout[0], 90, 70);
+            let search_block = Block::bordered()
+                .title(Line::from(" Search ").centered())
+                .title_bottom(Line::from(" (Enter) search/jump | (↑/↓) select | (Esc) close ").centered())
+                .border_set(border::ROUNDED);
+            // the three header rows stay put; the hits scroll to keep the selection in view
+            let height = search_block.inner(area).height.saturating_sub(3);
+            let top = u16::try_from(search.selected).unwrap_or(u16::MAX).saturating_sub(height.saturating_sub(1));
+            let mut lines = search_lines(search);
+            lines.drain(3..3 + usize::from(top).min(lines.len() - 3));
+            Clear.render(area, buf);
+            Paragraph::new(lines)
+                .block(search_block)
+                .render(area, buf);
+        }
     }
 }
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+use common_bork::read_frame;
+
+// decode as many frames as the input holds; anything that decodes must survive a round trip
+fuzz_target!(|data: &[u8]| {
+    let mut cursor = Cursor::new(data);
+    while let Ok(frame) = read_frame(&mut cursor) {
+        let encoded = frame.encode();
+        let decoded = read_frame(&mut Cursor::new(&encoded)).expect("re-encoded frame must decode");
+        assert_eq!(frame, decoded);
+    }
+});
@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::{ExtendedMessageType, Frame, FrameError};
+
+/// A message type carried inside EXTENDED. Each one owns an ID from [`ExtendedMessageType`] and
+/// knows how to turn itself into a payload and back.
+pub trait Extension: Sized {
+    const ID: u64;
+    const NAME: &'static str;
+
+    fn encode(&self) -> Vec<u8>;
+    fn decode(payload: &[u8]) -> Result<Self, FrameError>;
+
+    /// Wrap this in an EXTENDED frame, ready to send
+    fn to_frame(&self) -> Frame {
+        Frame::Extended { extended_type: Self::ID, payload: self.encode() }
+    }
+}
+
+type Handler<C> = Box<dyn Fn(&[u8], &mut C) -> Result<(), FrameError> + Send + Sync>;
+
+/// The extensions one peer understands, with what to do when each arrives. `C` is whatever the
+/// handlers need to act on -- the connection on the server, the app on the client.
+pub struct ExtensionRegistry<C>{
+    extensions: BTreeMap<u64, (&'static str, Handler<C>)>,
+}
+
+impl<C> Default for ExtensionRegistry<C>{
+    fn default() -> Self {
+        ExtensionRegistry { extensions: BTreeMap::new() }
+    }
+}
+
+impl<C> fmt::Debug for ExtensionRegistry<C>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.extensions.iter().map(|(id, (name, _))| (id, name)))
+            .finish()
+    }
+}
+
+impl<C> ExtensionRegistry<C>{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle `E` with `handler` from now on. Registering the same ID twice replaces the first.
+    pub fn register<E, F>(&mut self, handler: F)
+    where
+        E: Extension,
+        F: Fn(E, &mut C) + Send + Sync + 'static,
+    {
+        let handle = move |payload: &[u8], context: &mut C| {
+            handler(E::decode(payload)?, context);
+            Ok(())
+        };
+        self.extensions.insert(E::ID, (E::NAME, Box::new(handle)));
+    }
+
+    /// IDs to advertise in CAPABILITIES
+    pub fn capabilities(&self) -> Vec<u64> {
+        self.extensions.keys().copied().collect()
+    }
+
+    /// Decode `payload` and run its handler. Ok(false) means nobody registered `extended_type`;
+    /// the payload length is always known, so the frame can simply be skipped.
+    pub fn dispatch(&self, extended_type: u64, payload: &[u8], context: &mut C) -> Result<bool, FrameError> {
+        match self.extensions.get(&extended_type) {
+            Some((_, handle)) => handle(payload, context).map(|()| true),
+            None => Ok(false),
+        }
+    }
+}
+
+/// Round-trip probe; the peer answers with a [`Pong`] carrying the same nonce.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ping{
+    pub nonce: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pong{
+    pub nonce: u64,
+}
+
+impl Extension for Ping{
+    const ID: u64 = ExtendedMessageType::PING;
+    const NAME: &'static str = "ping";
+
+    fn encode(&self) -> Vec<u8> {
+        self.nonce.to_le_bytes().to_vec()
+    }
+    fn decode(payload: &[u8]) -> Result<Self, FrameError> {
+        Ok(Ping { nonce: decode_u64(payload, "ping")? })
+    }
+}
+
+impl Extension for Pong{
+    const ID: u64 = ExtendedMessageType::PONG;
+    const NAME: &'static str = "pong";
+
+    fn encode(&self) -> Vec<u8> {
+        self.nonce.to_le_bytes().to_vec()
+    }
+    fn decode(payload: &[u8]) -> Result<Self, FrameError> {
+        Ok(Pong { nonce: decode_u64(payload, "pong")? })
+    }
+}
+
+fn decode_u64(payload: &[u8], what: &'static str) -> Result<u64, FrameError> {
+    let bytes: [u8; 8] = payload.try_into().map_err(|_| FrameError::Malformed(what))?;
+    Ok(u64::from_le_bytes(bytes))
+}
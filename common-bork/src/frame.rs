@@ -0,0 +1,1423 @@
+use std::fmt;
+use std::io::{self, Read};
+use uuid::Uuid;
+
+use crate::{MessageType, PresenceStatus, RoomRole};
+
+// Hard limits on variable-length fields, in bytes. Lengths on the wire are u16, but
+// nothing legitimate comes close to that, so anything over these is a protocol violation
+pub const MAX_USERNAME_LEN: usize = 32;
+pub const MAX_MESSAGE_LEN:  usize = 4096;
+pub const MAX_WELCOME_LEN:  usize = 8192;
+// most messages a single HISTORYBATCH may carry
+pub const MAX_HISTORY_BATCH: usize = 100;
+pub const MAX_EXTENDED_LEN: usize = 65536;
+// most extension IDs a CAPABILITIES message may list
+pub const MAX_CAPABILITIES: usize = 256;
+// a reaction is an emoji or a short code like :bork:, not a message
+pub const MAX_REACTION_LEN: usize = 32;
+// most distinct reactions one message may collect
+pub const MAX_REACTIONS:    usize = 32;
+// "in a meeting", not a message
+pub const MAX_STATUS_LEN:   usize = 64;
+// a bare file name; never a path
+pub const MAX_FILE_NAME_LEN: usize = 255;
+// most file data a single CHUNK may carry
+pub const MAX_CHUNK_LEN:    usize = 32768;
+pub const MAX_QUERY_LEN:    usize = 256;
+pub const MAX_ROOM_NAME_LEN: usize = 64;
+pub const MAX_TOPIC_LEN:    usize = 256;
+pub const MAX_DESCRIPTION_LEN: usize = 4096;
+// most users one message may mention
+pub const MAX_MENTIONS:     usize = 32;
+// session tokens are opaque to clients, but never long
+pub const MAX_TOKEN_LEN:    usize = 128;
+// the longest address SMTP allows
+pub const MAX_EMAIL_LEN:    usize = 254;
+pub const MAX_CODE_LEN:     usize = 16;
+pub const MAX_PASSWORD_LEN: usize = 128;
+// ed25519 public keys and signatures, and the nonces signed to log in with a key, are fixed-size
+pub const PUBLIC_KEY_LEN:   usize = 32;
+pub const SIGNATURE_LEN:    usize = 64;
+pub const NONCE_LEN:        usize = 32;
+
+// end-to-end encrypted direct messages: ChaCha20-Poly1305 nonces, and ciphertext no longer than the
+// longest chat message plus its 16-byte tag
+pub const DM_NONCE_LEN:     usize = 12;
+pub const MAX_CIPHERTEXT_LEN: usize = MAX_MESSAGE_LEN + 16;
+
+/// What a client signs to answer a CHALLENGE: this, then the nonce. The prefix keeps the signature from
+/// meaning anything anywhere else the same key is used.
+pub const CHALLENGE_CONTEXT: &[u8] = b"BorkBork key login\0";
+
+/// A single decoded & validated protocol message, independent of the connection it arrived on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame{
+    ChatMsg{
+        message_id:     u64, // assigned by the server; 0 from clients
+        timestamp:      u64, // seconds since the Unix epoch, UTC, assigned by the server; 0 from clients
+        sender_id:      Uuid,
+        room_id:        u64, // ignored for replies, which always go to their parent's room
+        nonce:          u64, // chosen by the sending client and echoed in SENTACK; 0 when relayed
+        parent_id:      u64, // the message this replies to, starting or continuing its thread; 0 if none
+        message_text:   String,
+    },
+    Join{
+        username:       String,
+    },
+    Leave,
+    Version{
+        major_rev:      u16,
+        minor_rev:      u16,
+        subminor_rev:   u16,
+    },
+    Welcome{
+        welcome_msg:    String,
+    },
+    Userjoined{
+        user_id:        Uuid,
+        username:       String,
+    },
+    Userleft{
+        user_id:        Uuid,
+    },
+    Extended{
+        extended_type:  u64, // see ExtendedMessageType
+        payload:        Vec<u8>, // decoded by whichever extension registered extended_type
+    },
+    History{
+        room_id:        u64,
+        before_id:      u64, // 0 asks for the newest messages
+        limit:          u16,
+    },
+    HistoryBatch{
+        room_id:        u64,
+        entries:        Vec<HistoryEntry>,
+    },
+    ServerShutdown{
+        restart_eta:    u32, // seconds until the server expects to be back; 0 if unknown
+        reason:         String,
+    },
+    Notice{
+        severity:       u8,  // one of NoticeSeverity; treat anything unknown as INFO
+        room_id:        u64, // ALL_ROOMS for server-wide notices
+        notice_text:    String,
+    },
+    Capabilities{
+        extensions:     Vec<u64>, // extended types this peer understands
+    },
+    SentAck{
+        nonce:          u64, // from the CHATMSG being acknowledged
+        message_id:     u64,
+        timestamp:      u64,
+    },
+    EditMsg{
+        message_id:     u64,
+        message_text:   String, // replaces the message's text entirely
+    },
+    DeleteMsg{
+        message_id:     u64,
+    },
+    Reaction{
+        message_id:     u64,
+        action:         u8,  // one of ReactionAction
+        reaction:       String,
+    },
+    Reactions{
+        message_id:     u64,
+        reactions:      Vec<ReactionCount>, // every reaction the message has; empty once the last is removed
+    },
+    Thread{
+        parent_id:      u64,
+    },
+    ThreadBatch{
+        parent_id:      u64,
+        entries:        Vec<HistoryEntry>,
+    },
+    Typing{
+        room_id:        u64,
+        user_id:        Uuid, // filled in by the server; clients send anything
+        typing:         bool, // false once they've stopped
+    },
+    Presence{
+        user_id:        Uuid, // filled in by the server; clients send anything
+        status:         u8,   // one of PresenceStatus
+        status_text:    String, // optional; empty if none
+    },
+    Offer{
+        transfer_id:    u64, // chosen by the uploading client; the message ID when the server sends a file
+        room_id:        u64,
+        size:           u64, // of the whole file, in bytes
+        sha256:         [u8; 32], // of the whole file
+        file_name:      String,
+    },
+    Chunk{
+        transfer_id:    u64,
+        offset:         u64, // where data goes in the file; chunks arrive in order
+        data:           Vec<u8>,
+    },
+    Complete{
+        transfer_id:    u64,
+    },
+    FileRequest{
+        message_id:     u64,
+    },
+    Attachment{
+        message_id:     u64,
+        size:           u64,
+        sha256:         [u8; 32],
+        file_name:      String,
+    },
+    Search{
+        search_id:      u64, // chosen by the client and echoed in SEARCHRESULTS
+        room_id:        u64, // 0 for every room the user is in
+        username:       String, // only messages from this user; empty for anyone's
+        since:          u64, // only messages sent at or after this time; 0 for no limit
+        until:          u64, // only messages sent before this time; 0 for no limit
+        before_id:      u64, // only messages older than this one, for the next page; 0 for the newest
+        limit:          u16,
+        query:          String,
+    },
+    SearchResults{
+        search_id:      u64,
+        hits:           Vec<SearchHit>, // newest first
+    },
+    MarkRead{
+        room_id:        u64,
+        message_id:     u64, // the newest message read; markers never move backwards
+    },
+    Unread{
+        room_id:        u64,
+        room_name:      String,
+        last_read:      u64, // message_id of the last message read; 0 if none
+        unread_count:   u32,
+    },
+    Mentions{
+        message_id:     u64,
+        user_ids:       Vec<Uuid>, // everyone the message mentions; empty once an edit removes the last
+    },
+    Queued{
+        entries:        Vec<SearchHit>, // held for the user while they were offline, oldest first
+    },
+    Session{
+        token:          String, // empty when there's no session: the token given in RESUME is no good
+        expires:        u64,    // unix time; 0 with an empty token
+    },
+    Resume{
+        token:          String,
+    },
+    Register{
+        email:          String,
+    },
+    Verify{
+        code:           String, // as mailed to the address given in REGISTER
+    },
+    Login{
+        username:       String,
+        password:       String,
+    },
+    ChangePassword{
+        old_password:   String, // empty if the account has none yet
+        new_password:   String,
+    },
+    ResetRequest{
+        username:       String,
+    },
+    ResetPassword{
+        username:       String,
+        code:           String, // as mailed after RESETREQUEST
+        new_password:   String,
+    },
+    KeyLogin{
+        username:       String,
+        public_key:     [u8; PUBLIC_KEY_LEN], // ed25519
+    },
+    Challenge{
+        nonce:          [u8; NONCE_LEN],
+    },
+    ChallengeResponse{
+        signature:      [u8; SIGNATURE_LEN], // over CHALLENGE_CONTEXT and the nonce
+    },
+    AddKey{
+        public_key:     [u8; PUBLIC_KEY_LEN],
+    },
+    RemoveKey{
+        public_key:     [u8; PUBLIC_KEY_LEN],
+    },
+    PublishKey{
+        public_key:     [u8; PUBLIC_KEY_LEN], // X25519, for end-to-end encrypted direct messages
+    },
+    KeyRequest{
+        username:       String,
+    },
+    IdentityKey{
+        user_id:        Uuid, // nil if there's no such user
+        username:       String,
+        public_key:     Option<[u8; PUBLIC_KEY_LEN]>, // None if they haven't published one
+    },
+    DirectMsg{
+        message_id:     u64, // assigned by the server; 0 from clients
+        timestamp:      u64, // assigned by the server; 0 from clients
+        sender_id:      Uuid,   // filled in by the server; nil from clients
+        username:       String, // the sender's, filled in by the server; empty from clients
+        recipient_id:   Uuid,
+        sender_key:     [u8; PUBLIC_KEY_LEN], // the identity keys it was encrypted between
+        recipient_key:  [u8; PUBLIC_KEY_LEN],
+        nonce:          [u8; DM_NONCE_LEN],
+        ciphertext:     Vec<u8>, // opaque to the server
+    },
+    CreateRoom{
+        room_name:      String,
+        is_public:      bool, // false for a room only those invited may join
+    },
+    JoinRoom{
+        room_name:      String,
+    },
+    Invite{
+        room_id:        u64,
+        room_name:      String, // filled in by the server; empty from clients
+        username:       String, // from clients, who to invite; from the server, who invited you
+    },
+    RoomInfo{
+        room_id:        u64,
+        room_name:      String,
+        is_public:      bool,
+        topic:          String,
+        description:    String,
+    },
+    SetTopic{
+        room_id:        u64,
+        topic:          String,
+        description:    String,
+    },
+    RoomRole{
+        room_id:        u64,
+        user_id:        Uuid,   // filled in by the server; nil from clients
+        username:       String,
+        room_role:      u8,     // RoomRole
+    },
+}
+
+/// One stored chat message, as replayed by HISTORYBATCH and THREADBATCH. Oldest entries come first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry{
+    pub message_id:     u64,
+    pub timestamp:      u64,
+    pub flags:          u8,  // MessageFlags; a DELETED entry's text is empty
+    pub parent_id:      u64, // 0 unless this is a reply
+    pub reply_count:    u32,
+    pub sender_id:      Uuid,
+    pub username:       String,
+    pub message_text:   String,
+}
+
+/// A message that matched a SEARCH, and the room it's in
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit{
+    pub room_id:        u64,
+    pub entry:          HistoryEntry,
+}
+
+/// How many users reacted to a message with one particular reaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReactionCount{
+    pub reaction:       String,
+    pub count:          u32,
+}
+
+/// Everything that can go wrong while reading a frame. Anything other than `Io` means the
+/// peer broke the protocol, and the connection should be dropped.
+#[derive(Debug)]
+pub enum FrameError{
+    Io(io::Error),
+    UnknownType(u8),
+    Malformed(&'static str),
+    TooLong{ field: &'static str, len: usize, max: usize },
+    Empty(&'static str),
+    InvalidUtf8(&'static str),
+    ControlCharacter(&'static str),
+}
+
+impl fmt::Display for FrameError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Io(err) => write!(f, "i/o error: {}", err),
+            FrameError::UnknownType(t) => write!(f, "unknown message type {}", t),
+            FrameError::Malformed(what) => write!(f, "malformed {}", what),
+            FrameError::TooLong { field, len, max } => {
+                write!(f, "{} is {} bytes, limit is {}", field, len, max)
+            }
+            FrameError::Empty(field) => write!(f, "{} is empty", field),
+            FrameError::InvalidUtf8(field) => write!(f, "{} is not valid UTF-8", field),
+            FrameError::ControlCharacter(field) => write!(f, "{} contains control characters", field),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError{
+    fn from(err: io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+impl FrameError{
+    /// true when the peer went away (or the read failed), rather than sending garbage
+    pub fn is_io(&self) -> bool {
+        matches!(self, FrameError::Io(_))
+    }
+}
+
+/// Read exactly one frame from `reader`. Length fields are checked against the per-field limits
+/// before anything is allocated, and text fields are validated before they're handed back.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Frame, FrameError> {
+    let message_type = read_u8(reader)?;
+    let frame = match message_type {
+        MessageType::CHATMSG => Frame::ChatMsg {
+            message_id: read_u64(reader)?,
+            timestamp: read_u64(reader)?,
+            sender_id: read_uuid(reader)?,
+            room_id: read_u64(reader)?,
+            nonce: read_u64(reader)?,
+            parent_id: read_u64(reader)?,
+            message_text: read_text(reader, "message", MAX_MESSAGE_LEN, true)?,
+        },
+        MessageType::JOIN => Frame::Join {
+            username: read_username(reader)?,
+        },
+        MessageType::LEAVE => Frame::Leave,
+        MessageType::VERSION => Frame::Version {
+            major_rev: read_u16(reader)?,
+            minor_rev: read_u16(reader)?,
+            subminor_rev: read_u16(reader)?,
+        },
+        MessageType::WELCOME => Frame::Welcome {
+            welcome_msg: read_text(reader, "welcome message", MAX_WELCOME_LEN, true)?,
+        },
+        MessageType::USERJOINED => Frame::Userjoined {
+            user_id: read_uuid(reader)?,
+            username: read_username(reader)?,
+        },
+        MessageType::USERLEFT => Frame::Userleft {
+            user_id: read_uuid(reader)?,
+        },
+        MessageType::HISTORY => Frame::History {
+            room_id: read_u64(reader)?,
+            before_id: read_u64(reader)?,
+            limit: read_u16(reader)?,
+        },
+        MessageType::HISTORYBATCH => Frame::HistoryBatch {
+            room_id: read_u64(reader)?,
+            entries: read_entries(reader)?,
+        },
+        MessageType::THREAD => Frame::Thread {
+            parent_id: read_u64(reader)?,
+        },
+        MessageType::THREADBATCH => Frame::ThreadBatch {
+            parent_id: read_u64(reader)?,
+            entries: read_entries(reader)?,
+        },
+        MessageType::SERVERSHUTDOWN => Frame::ServerShutdown {
+            restart_eta: read_u32(reader)?,
+            reason: read_text(reader, "shutdown reason", MAX_MESSAGE_LEN, true)?,
+        },
+        MessageType::NOTICE => Frame::Notice {
+            severity: read_u8(reader)?,
+            room_id: read_u64(reader)?,
+            notice_text: read_text(reader, "notice", MAX_MESSAGE_LEN, true)?,
+        },
+        MessageType::CAPABILITIES => {
+            let count = read_u16(reader)? as usize;
+            if count > MAX_CAPABILITIES {
+                return Err(FrameError::TooLong { field: "capabilities", len: count, max: MAX_CAPABILITIES });
+            }
+            let mut extensions = Vec::with_capacity(count);
+            for _ in 0..count {
+                extensions.push(read_u64(reader)?);
+            }
+            Frame::Capabilities { extensions }
+        }
+        MessageType::SENTACK => Frame::SentAck {
+            nonce: read_u64(reader)?,
+            message_id: read_u64(reader)?,
+            timestamp: read_u64(reader)?,
+        },
+        MessageType::EDITMSG => Frame::EditMsg {
+            message_id: read_u64(reader)?,
+            message_text: read_text(reader, "message", MAX_MESSAGE_LEN, true)?,
+        },
+        MessageType::DELETEMSG => Frame::DeleteMsg {
+            message_id: read_u64(reader)?,
+        },
+        MessageType::REACTION => Frame::Reaction {
+            message_id: read_u64(reader)?,
+            action: read_u8(reader)?,
+            reaction: read_reaction(reader)?,
+        },
+        MessageType::REACTIONS => {
+            let message_id = read_u64(reader)?;
+            let count = read_u16(reader)? as usize;
+            if count > MAX_REACTIONS {
+                return Err(FrameError::TooLong { field: "reactions", len: count, max: MAX_REACTIONS });
+            }
+            let mut reactions = Vec::with_capacity(count);
+            for _ in 0..count {
+                reactions.push(ReactionCount {
+                    reaction: read_reaction(reader)?,
+                    count: read_u32(reader)?,
+                });
+            }
+            Frame::Reactions { message_id, reactions }
+        }
+        MessageType::TYPING => Frame::Typing {
+            room_id: read_u64(reader)?,
+            user_id: read_uuid(reader)?,
+            typing: match read_u8(reader)? {
+                0 => false,
+                1 => true,
+                _ => return Err(FrameError::Malformed("typing state")),
+            },
+        },
+        MessageType::PRESENCE => Frame::Presence {
+            user_id: read_uuid(reader)?,
+            status: match read_u8(reader)? {
+                status @ (PresenceStatus::ONLINE | PresenceStatus::AWAY | PresenceStatus::DO_NOT_DISTURB) => status,
+                _ => return Err(FrameError::Malformed("presence status")),
+            },
+            status_text: read_text(reader, "status text", MAX_STATUS_LEN, false)?,
+        },
+        MessageType::OFFER => Frame::Offer {
+            transfer_id: read_u64(reader)?,
+            room_id: read_u64(reader)?,
+            size: read_u64(reader)?,
+            sha256: read_array(reader)?,
+            file_name: read_file_name(reader)?,
+        },
+        MessageType::CHUNK => {
+            let transfer_id = read_u64(reader)?;
+            let offset = read_u64(reader)?;
+            let len = read_u32(reader)? as usize;
+            if len > MAX_CHUNK_LEN {
+                return Err(FrameError::TooLong { field: "chunk", len, max: MAX_CHUNK_LEN });
+            }
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data)?;
+            Frame::Chunk { transfer_id, offset, data }
+        }
+        MessageType::COMPLETE => Frame::Complete {
+            transfer_id: read_u64(reader)?,
+        },
+        MessageType::FILEREQUEST => Frame::FileRequest {
+            message_id: read_u64(reader)?,
+        },
+        MessageType::ATTACHMENT => Frame::Attachment {
+            message_id: read_u64(reader)?,
+            size: read_u64(reader)?,
+            sha256: read_array(reader)?,
+            file_name: read_file_name(reader)?,
+        },
+        MessageType::SEARCH => Frame::Search {
+            search_id: read_u64(reader)?,
+            room_id: read_u64(reader)?,
+            username: read_text(reader, "username", MAX_USERNAME_LEN, false)?,
+            since: read_u64(reader)?,
+            until: read_u64(reader)?,
+            before_id: read_u64(reader)?,
+            limit: read_u16(reader)?,
+            query: read_text(reader, "search query", MAX_QUERY_LEN, false)?,
+        },
+        MessageType::SEARCHRESULTS => Frame::SearchResults {
+            search_id: read_u64(reader)?,
+            hits: read_hits(reader, "search results")?,
+        },
+        MessageType::QUEUED => Frame::Queued {
+            entries: read_hits(reader, "queued messages")?,
+        },
+        MessageType::SESSION => Frame::Session {
+            token: read_text(reader, "session token", MAX_TOKEN_LEN, false)?,
+            expires: read_u64(reader)?,
+        },
+        MessageType::RESUME => Frame::Resume {
+            token: read_text(reader, "session token", MAX_TOKEN_LEN, false)?,
+        },
+        MessageType::REGISTER => Frame::Register {
+            email: read_text(reader, "email address", MAX_EMAIL_LEN, false)?,
+        },
+        MessageType::VERIFY => Frame::Verify {
+            code: read_text(reader, "verification code", MAX_CODE_LEN, false)?,
+        },
+        MessageType::LOGIN => Frame::Login {
+            username: read_text(reader, "username", MAX_USERNAME_LEN, false)?,
+            password: read_text(reader, "password", MAX_PASSWORD_LEN, false)?,
+        },
+        MessageType::CHANGEPASSWORD => Frame::ChangePassword {
+            old_password: read_text(reader, "password", MAX_PASSWORD_LEN, false)?,
+            new_password: read_text(reader, "password", MAX_PASSWORD_LEN, false)?,
+        },
+        MessageType::RESETREQUEST => Frame::ResetRequest {
+            username: read_text(reader, "username", MAX_USERNAME_LEN, false)?,
+        },
+        MessageType::RESETPASSWORD => Frame::ResetPassword {
+            username: read_text(reader, "username", MAX_USERNAME_LEN, false)?,
+            code: read_text(reader, "reset code", MAX_CODE_LEN, false)?,
+            new_password: read_text(reader, "password", MAX_PASSWORD_LEN, false)?,
+        },
+        MessageType::KEYLOGIN => Frame::KeyLogin {
+            username: read_username(reader)?,
+            public_key: read_array(reader)?,
+        },
+        MessageType::CHALLENGE => Frame::Challenge { nonce: read_array(reader)? },
+        MessageType::CHALLENGERESPONSE => Frame::ChallengeResponse { signature: read_array(reader)? },
+        MessageType::ADDKEY => Frame::AddKey { public_key: read_array(reader)? },
+        MessageType::REMOVEKEY => Frame::RemoveKey { public_key: read_array(reader)? },
+        MessageType::PUBLISHKEY => Frame::PublishKey { public_key: read_array(reader)? },
+        MessageType::KEYREQUEST => Frame::KeyRequest { username: read_username(reader)? },
+        MessageType::IDENTITYKEY => Frame::IdentityKey {
+            user_id: read_uuid(reader)?,
+            username: read_text(reader, "username", MAX_USERNAME_LEN, false)?,
+            public_key: match read_u8(reader)? {
+                0 => None,
+                _ => Some(read_array(reader)?),
+            },
+        },
+        MessageType::DIRECTMSG => {
+            let message_id = read_u64(reader)?;
+            let timestamp = read_u64(reader)?;
+            let sender_id = read_uuid(reader)?;
+            let username = read_text(reader, "username", MAX_USERNAME_LEN, false)?;
+            let recipient_id = read_uuid(reader)?;
+            let sender_key = read_array(reader)?;
+            let recipient_key = read_array(reader)?;
+            let nonce = read_array(reader)?;
+            let len = read_u16(reader)? as usize;
+            if len > MAX_CIPHERTEXT_LEN {
+                return Err(FrameError::TooLong { field: "ciphertext", len, max: MAX_CIPHERTEXT_LEN });
+            }
+            let mut ciphertext = vec![0u8; len];
+            reader.read_exact(&mut ciphertext)?;
+            Frame::DirectMsg {
+                message_id, timestamp, sender_id, username, recipient_id, sender_key, recipient_key, nonce, ciphertext,
+            }
+        }
+        MessageType::CREATEROOM => Frame::CreateRoom {
+            room_name: read_text(reader, "room name", MAX_ROOM_NAME_LEN, false)?,
+            is_public: match read_u8(reader)? {
+                0 => false,
+                1 => true,
+                _ => return Err(FrameError::Malformed("room visibility")),
+            },
+        },
+        MessageType::JOINROOM => Frame::JoinRoom {
+            room_name: read_text(reader, "room name", MAX_ROOM_NAME_LEN, false)?,
+        },
+        MessageType::INVITE => Frame::Invite {
+            room_id: read_u64(reader)?,
+            room_name: read_text(reader, "room name", MAX_ROOM_NAME_LEN, false)?,
+            username: read_username(reader)?,
+        },
+        MessageType::ROOMINFO => Frame::RoomInfo {
+            room_id: read_u64(reader)?,
+            room_name: read_text(reader, "room name", MAX_ROOM_NAME_LEN, false)?,
+            is_public: match read_u8(reader)? {
+                0 => false,
+                1 => true,
+                _ => return Err(FrameError::Malformed("room visibility")),
+            },
+            topic: read_text(reader, "topic", MAX_TOPIC_LEN, false)?,
+            description: read_text(reader, "room description", MAX_DESCRIPTION_LEN, true)?,
+        },
+        MessageType::SETTOPIC => Frame::SetTopic {
+            room_id: read_u64(reader)?,
+            topic: read_text(reader, "topic", MAX_TOPIC_LEN, false)?,
+            description: read_text(reader, "room description", MAX_DESCRIPTION_LEN, true)?,
+        },
+        MessageType::ROOMROLE => Frame::RoomRole {
+            room_id: read_u64(reader)?,
+            user_id: read_uuid(reader)?,
+            username: read_username(reader)?,
+            room_role: match read_u8(reader)? {
+                role @ (RoomRole::MEMBER | RoomRole::MODERATOR | RoomRole::OWNER) => role,
+                _ => return Err(FrameError::Malformed("room role")),
+            },
+        },
+        MessageType::MARKREAD => Frame::MarkRead {
+            room_id: read_u64(reader)?,
+            message_id: read_u64(reader)?,
+        },
+        MessageType::UNREAD => Frame::Unread {
+            room_id: read_u64(reader)?,
+            room_name: read_text(reader, "room name", MAX_ROOM_NAME_LEN, false)?,
+            last_read: read_u64(reader)?,
+            unread_count: read_u32(reader)?,
+        },
+        MessageType::MENTIONS => {
+            let message_id = read_u64(reader)?;
+            let count = read_u16(reader)? as usize;
+            if count > MAX_MENTIONS {
+                return Err(FrameError::TooLong { field: "mentions", len: count, max: MAX_MENTIONS });
+            }
+            let mut user_ids = Vec::with_capacity(count);
+            for _ in 0..count {
+                user_ids.push(read_uuid(reader)?);
+            }
+            Frame::Mentions { message_id, user_ids }
+        }
+        MessageType::EXTENDED => {
+            let extended_type = read_u64(reader)?;
+            let len = read_u32(reader)? as usize;
+            if len > MAX_EXTENDED_LEN {
+                return Err(FrameError::TooLong { field: "extended payload", len, max: MAX_EXTENDED_LEN });
+            }
+            let mut payload = vec![0u8; len];
+            reader.read_exact(&mut payload)?;
+            Frame::Extended { extended_type, payload }
+        }
+        other => return Err(FrameError::UnknownType(other)),
+    };
+    Ok(frame)
+}
+
+impl Frame{
+    pub fn message_type(&self) -> u8 {
+        match self {
+            Frame::ChatMsg { .. } => MessageType::CHATMSG,
+            Frame::Join { .. } => MessageType::JOIN,
+            Frame::Leave => MessageType::LEAVE,
+            Frame::Version { .. } => MessageType::VERSION,
+            Frame::Welcome { .. } => MessageType::WELCOME,
+            Frame::Userjoined { .. } => MessageType::USERJOINED,
+            Frame::Userleft { .. } => MessageType::USERLEFT,
+            Frame::Extended { .. } => MessageType::EXTENDED,
+            Frame::History { .. } => MessageType::HISTORY,
+            Frame::HistoryBatch { .. } => MessageType::HISTORYBATCH,
+            Frame::ServerShutdown { .. } => MessageType::SERVERSHUTDOWN,
+            Frame::Notice { .. } => MessageType::NOTICE,
+            Frame::Capabilities { .. } => MessageType::CAPABILITIES,
+            Frame::SentAck { .. } => MessageType::SENTACK,
+            Frame::EditMsg { .. } => MessageType::EDITMSG,
+            Frame::DeleteMsg { .. } => MessageType::DELETEMSG,
+            Frame::Reaction { .. } => MessageType::REACTION,
+            Frame::Reactions { .. } => MessageType::REACTIONS,
+            Frame::Thread { .. } => MessageType::THREAD,
+            Frame::ThreadBatch { .. } => MessageType::THREADBATCH,
+            Frame::Typing { .. } => MessageType::TYPING,
+            Frame::Presence { .. } => MessageType::PRESENCE,
+            Frame::Offer { .. } => MessageType::OFFER,
+            Frame::Chunk { .. } => MessageType::CHUNK,
+            Frame::Complete { .. } => MessageType::COMPLETE,
+            Frame::FileRequest { .. } => MessageType::FILEREQUEST,
+            Frame::Attachment { .. } => MessageType::ATTACHMENT,
+            Frame::Search { .. } => MessageType::SEARCH,
+            Frame::SearchResults { .. } => MessageType::SEARCHRESULTS,
+            Frame::MarkRead { .. } => MessageType::MARKREAD,
+            Frame::Unread { .. } => MessageType::UNREAD,
+            Frame::Mentions { .. } => MessageType::MENTIONS,
+            Frame::Queued { .. } => MessageType::QUEUED,
+            Frame::Session { .. } => MessageType::SESSION,
+            Frame::Resume { .. } => MessageType::RESUME,
+            Frame::Register { .. } => MessageType::REGISTER,
+            Frame::Verify { .. } => MessageType::VERIFY,
+            Frame::Login { .. } => MessageType::LOGIN,
+            Frame::ChangePassword { .. } => MessageType::CHANGEPASSWORD,
+            Frame::ResetRequest { .. } => MessageType::RESETREQUEST,
+            Frame::ResetPassword { .. } => MessageType::RESETPASSWORD,
+            Frame::KeyLogin { .. } => MessageType::KEYLOGIN,
+            Frame::Challenge { .. } => MessageType::CHALLENGE,
+            Frame::ChallengeResponse { .. } => MessageType::CHALLENGERESPONSE,
+            Frame::AddKey { .. } => MessageType::ADDKEY,
+            Frame::RemoveKey { .. } => MessageType::REMOVEKEY,
+            Frame::PublishKey { .. } => MessageType::PUBLISHKEY,
+            Frame::KeyRequest { .. } => MessageType::KEYREQUEST,
+            Frame::IdentityKey { .. } => MessageType::IDENTITYKEY,
+            Frame::DirectMsg { .. } => MessageType::DIRECTMSG,
+            Frame::CreateRoom { .. } => MessageType::CREATEROOM,
+            Frame::JoinRoom { .. } => MessageType::JOINROOM,
+            Frame::Invite { .. } => MessageType::INVITE,
+            Frame::RoomInfo { .. } => MessageType::ROOMINFO,
+            Frame::SetTopic { .. } => MessageType::SETTOPIC,
+            Frame::RoomRole { .. } => MessageType::ROOMROLE,
+        }
+    }
+
+    /// Serialize to wire format. Text longer than a u16 length can describe is truncated;
+    /// callers are expected to respect the MAX_* limits anyway.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut message: Vec<u8> = vec![self.message_type()];
+        match self {
+            Frame::ChatMsg { message_id, timestamp, sender_id, room_id, nonce, parent_id, message_text } => {
+                message.extend(message_id.to_le_bytes());
+                message.extend(timestamp.to_le_bytes());
+                put_uuid(&mut message, sender_id);
+                message.extend(room_id.to_le_bytes());
+                message.extend(nonce.to_le_bytes());
+                message.extend(parent_id.to_le_bytes());
+                put_text(&mut message, message_text);
+            }
+            Frame::Join { username } => {
+                put_text(&mut message, username);
+            }
+            Frame::Leave => (),
+            Frame::Version { major_rev, minor_rev, subminor_rev } => {
+                message.extend(major_rev.to_le_bytes());
+                message.extend(minor_rev.to_le_bytes());
+                message.extend(subminor_rev.to_le_bytes());
+            }
+            Frame::Welcome { welcome_msg } => {
+                put_text(&mut message, welcome_msg);
+            }
+            Frame::Userjoined { user_id, username } => {
+                put_uuid(&mut message, user_id);
+                put_text(&mut message, username);
+            }
+            Frame::Userleft { user_id } => {
+                put_uuid(&mut message, user_id);
+            }
+            Frame::Extended { extended_type, payload } => {
+                let len = payload.len().min(MAX_EXTENDED_LEN);
+                message.extend(extended_type.to_le_bytes());
+                message.extend((len as u32).to_le_bytes());
+                message.extend(&payload[..len]);
+            }
+            Frame::History { room_id, before_id, limit } => {
+                message.extend(room_id.to_le_bytes());
+                message.extend(before_id.to_le_bytes());
+                message.extend(limit.to_le_bytes());
+            }
+            Frame::HistoryBatch { room_id, entries } => {
+                message.extend(room_id.to_le_bytes());
+                put_entries(&mut message, entries);
+            }
+            Frame::Thread { parent_id } => {
+                message.extend(parent_id.to_le_bytes());
+            }
+            Frame::ThreadBatch { parent_id, entries } => {
+                message.extend(parent_id.to_le_bytes());
+                put_entries(&mut message, entries);
+            }
+            Frame::Typing { room_id, user_id, typing } => {
+                message.extend(room_id.to_le_bytes());
+                put_uuid(&mut message, user_id);
+                message.push(u8::from(*typing));
+            }
+            Frame::Presence { user_id, status, status_text } => {
+                put_uuid(&mut message, user_id);
+                message.push(*status);
+                put_text(&mut message, status_text);
+            }
+            Frame::Offer { transfer_id, room_id, size, sha256, file_name } => {
+                message.extend(transfer_id.to_le_bytes());
+                message.extend(room_id.to_le_bytes());
+                message.extend(size.to_le_bytes());
+                message.extend(sha256);
+                put_text(&mut message, file_name);
+            }
+            Frame::Chunk { transfer_id, offset, data } => {
+                let len = data.len().min(MAX_CHUNK_LEN);
+                message.extend(transfer_id.to_le_bytes());
+                message.extend(offset.to_le_bytes());
+                message.extend((len as u32).to_le_bytes());
+                message.extend(&data[..len]);
+            }
+            Frame::Complete { transfer_id } => {
+                message.extend(transfer_id.to_le_bytes());
+            }
+            Frame::FileRequest { message_id } => {
+                message.extend(message_id.to_le_bytes());
+            }
+            Frame::Attachment { message_id, size, sha256, file_name } => {
+                message.extend(message_id.to_le_bytes());
+                message.extend(size.to_le_bytes());
+                message.extend(sha256);
+                put_text(&mut message, file_name);
+            }
+            Frame::Search { search_id, room_id, username, since, until, before_id, limit, query } => {
+                message.extend(search_id.to_le_bytes());
+                message.extend(room_id.to_le_bytes());
+                put_text(&mut message, username);
+                message.extend(since.to_le_bytes());
+                message.extend(until.to_le_bytes());
+                message.extend(before_id.to_le_bytes());
+                message.extend(limit.to_le_bytes());
+                put_text(&mut message, query);
+            }
+            Frame::SearchResults { search_id, hits } => {
+                message.extend(search_id.to_le_bytes());
+                put_hits(&mut message, hits);
+            }
+            Frame::Queued { entries } => put_hits(&mut message, entries),
+            Frame::Session { token, expires } => {
+                put_text(&mut message, token);
+                message.extend(expires.to_le_bytes());
+            }
+            Frame::Resume { token } => put_text(&mut message, token),
+            Frame::Register { email } => put_text(&mut message, email),
+            Frame::Verify { code } => put_text(&mut message, code),
+            Frame::Login { username, password } => {
+                put_text(&mut message, username);
+                put_text(&mut message, password);
+            }
+            Frame::ChangePassword { old_password, new_password } => {
+                put_text(&mut message, old_password);
+                put_text(&mut message, new_password);
+            }
+            Frame::ResetRequest { username } => put_text(&mut message, username),
+            Frame::ResetPassword { username, code, new_password } => {
+                put_text(&mut message, username);
+                put_text(&mut message, code);
+                put_text(&mut message, new_password);
+            }
+            Frame::KeyLogin { username, public_key } => {
+                put_text(&mut message, username);
+                message.extend(public_key);
+            }
+            Frame::Challenge { nonce } => message.extend(nonce),
+            Frame::ChallengeResponse { signature } => message.extend(signature),
+            Frame::AddKey { public_key } | Frame::RemoveKey { public_key } | Frame::PublishKey { public_key } => {
+                message.extend(public_key);
+            }
+            Frame::KeyRequest { username } => put_text(&mut message, username),
+            Frame::IdentityKey { user_id, username, public_key } => {
+                put_uuid(&mut message, user_id);
+                put_text(&mut message, username);
+                match public_key {
+                    Some(public_key) => {
+                        message.push(1);
+                        message.extend(public_key);
+                    }
+                    None => message.push(0),
+                }
+            }
+            Frame::DirectMsg {
+                message_id, timestamp, sender_id, username, recipient_id, sender_key, recipient_key, nonce, ciphertext,
+            } => {
+                let len = ciphertext.len().min(MAX_CIPHERTEXT_LEN);
+                message.extend(message_id.to_le_bytes());
+                message.extend(timestamp.to_le_bytes());
+                put_uuid(&mut message, sender_id);
+                put_text(&mut message, username);
+                put_uuid(&mut message, recipient_id);
+                message.extend(sender_key);
+                message.extend(recipient_key);
+                message.extend(nonce);
+                message.extend((len as u16).to_le_bytes());
+                message.extend(&ciphertext[..len]);
+            }
+            Frame::CreateRoom { room_name, is_public } => {
+                put_text(&mut message, room_name);
+                message.push(u8::from(*is_public));
+            }
+            Frame::JoinRoom { room_name } => put_text(&mut message, room_name),
+            Frame::Invite { room_id, room_name, username } => {
+                message.extend(room_id.to_le_bytes());
+                put_text(&mut message, room_name);
+                put_text(&mut message, username);
+            }
+            Frame::RoomInfo { room_id, room_name, is_public, topic, description } => {
+                message.extend(room_id.to_le_bytes());
+                put_text(&mut message, room_name);
+                message.push(u8::from(*is_public));
+                put_text(&mut message, topic);
+                put_text(&mut message, description);
+            }
+            Frame::SetTopic { room_id, topic, description } => {
+                message.extend(room_id.to_le_bytes());
+                put_text(&mut message, topic);
+                put_text(&mut message, description);
+            }
+            Frame::RoomRole { room_id, user_id, username, room_role } => {
+                message.extend(room_id.to_le_bytes());
+                put_uuid(&mut message, user_id);
+                put_text(&mut message, username);
+                message.push(*room_role);
+            }
+            Frame::MarkRead { room_id, message_id } => {
+                message.extend(room_id.to_le_bytes());
+                message.extend(message_id.to_le_bytes());
+            }
+            Frame::Unread { room_id, room_name, last_read, unread_count } => {
+                message.extend(room_id.to_le_bytes());
+                put_text(&mut message, room_name);
+                message.extend(last_read.to_le_bytes());
+                message.extend(unread_count.to_le_bytes());
+            }
+            Frame::Mentions { message_id, user_ids } => {
+                let count = user_ids.len().min(MAX_MENTIONS);
+                message.extend(message_id.to_le_bytes());
+                message.extend((count as u16).to_le_bytes());
+                for user_id in &user_ids[..count] {
+                    put_uuid(&mut message, user_id);
+                }
+            }
+            Frame::ServerShutdown { restart_eta, reason } => {
+                message.extend(restart_eta.to_le_bytes());
+                put_text(&mut message, reason);
+            }
+            Frame::Notice { severity, room_id, notice_text } => {
+                message.push(*severity);
+                message.extend(room_id.to_le_bytes());
+                put_text(&mut message, notice_text);
+            }
+            Frame::Capabilities { extensions } => {
+                let count = extensions.len().min(MAX_CAPABILITIES);
+                message.extend((count as u16).to_le_bytes());
+                for extended_type in &extensions[..count] {
+                    message.extend(extended_type.to_le_bytes());
+                }
+            }
+            Frame::SentAck { nonce, message_id, timestamp } => {
+                message.extend(nonce.to_le_bytes());
+                message.extend(message_id.to_le_bytes());
+                message.extend(timestamp.to_le_bytes());
+            }
+            Frame::EditMsg { message_id, message_text } => {
+                message.extend(message_id.to_le_bytes());
+                put_text(&mut message, message_text);
+            }
+            Frame::DeleteMsg { message_id } => {
+                message.extend(message_id.to_le_bytes());
+            }
+            Frame::Reaction { message_id, action, reaction } => {
+                message.extend(message_id.to_le_bytes());
+                message.push(*action);
+                put_text(&mut message, reaction);
+            }
+            Frame::Reactions { message_id, reactions } => {
+                let count = reactions.len().min(MAX_REACTIONS);
+                message.extend(message_id.to_le_bytes());
+                message.extend((count as u16).to_le_bytes());
+                for entry in &reactions[..count] {
+                    put_text(&mut message, &entry.reaction);
+                    message.extend(entry.count.to_le_bytes());
+                }
+            }
+        }
+        message
+    }
+}
+
+/// Usernames are displayed everywhere, so they get the strictest rules: non-empty, short, and
+/// no control characters at all
+pub fn validate_username(username: &str) -> Result<(), FrameError> {
+    validate_text(username, "username", MAX_USERNAME_LEN, false)?;
+    if username.is_empty() {
+        return Err(FrameError::Empty("username"));
+    }
+    Ok(())
+}
+
+/// Reactions are a single short token: non-empty, and no whitespace or control characters
+pub fn validate_reaction(reaction: &str) -> Result<(), FrameError> {
+    validate_text(reaction, "reaction", MAX_REACTION_LEN, false)?;
+    if reaction.is_empty() {
+        return Err(FrameError::Empty("reaction"));
+    }
+    if reaction.chars().any(char::is_whitespace) {
+        return Err(FrameError::Malformed("reaction"));
+    }
+    Ok(())
+}
+
+/// Status text is shown beside a name in the Users pane, so it stays on one line
+pub fn validate_status(status_text: &str) -> Result<(), FrameError> {
+    validate_text(status_text, "status text", MAX_STATUS_LEN, false)
+}
+
+/// Email addresses only need to be plausible, since the verification code is what proves them: one
+/// `@`, something either side of it, a dot somewhere in the domain, and no spaces
+pub fn validate_email(email: &str) -> Result<(), FrameError> {
+    validate_text(email, "email address", MAX_EMAIL_LEN, false)?;
+    if email.is_empty() {
+        return Err(FrameError::Empty("email address"));
+    }
+    let plausible = match email.split_once('@') {
+        Some((local, domain)) => !local.is_empty()
+            && !domain.contains('@')
+            && domain.contains('.')
+            && !domain.starts_with('.')
+            && !domain.ends_with('.'),
+        None => false,
+    };
+    if !plausible || email.chars().any(char::is_whitespace) {
+        return Err(FrameError::Malformed("email address"));
+    }
+    Ok(())
+}
+
+/// Verification codes are short, and typed on one line
+pub fn validate_code(code: &str) -> Result<(), FrameError> {
+    validate_text(code, "verification code", MAX_CODE_LEN, false)
+}
+
+/// Passwords are typed on one line; how long they must be is up to the server
+pub fn validate_password(password: &str) -> Result<(), FrameError> {
+    validate_text(password, "password", MAX_PASSWORD_LEN, false)
+}
+
+/// Search queries are typed on one line
+pub fn validate_query(query: &str) -> Result<(), FrameError> {
+    validate_text(query, "search query", MAX_QUERY_LEN, false)
+}
+
+/// Room names are joined by, so they're one word: non-empty, and no whitespace
+pub fn validate_room_name(room_name: &str) -> Result<(), FrameError> {
+    validate_text(room_name, "room name", MAX_ROOM_NAME_LEN, false)?;
+    if room_name.is_empty() {
+        return Err(FrameError::Empty("room name"));
+    }
+    if room_name.chars().any(char::is_whitespace) {
+        return Err(FrameError::Malformed("room name"));
+    }
+    Ok(())
+}
+
+/// A room's topic is a line; its description may run on
+pub fn validate_topic(topic: &str, description: &str) -> Result<(), FrameError> {
+    validate_text(topic, "topic", MAX_TOPIC_LEN, false)?;
+    validate_text(description, "room description", MAX_DESCRIPTION_LEN, true)
+}
+
+/// File names are only ever names: non-empty, no control characters, and nothing that would let them
+/// reach outside the directory they're saved in
+pub fn validate_file_name(file_name: &str) -> Result<(), FrameError> {
+    validate_text(file_name, "file name", MAX_FILE_NAME_LEN, false)?;
+    if file_name.is_empty() {
+        return Err(FrameError::Empty("file name"));
+    }
+    if file_name.contains(['/', '\\']) || file_name == "." || file_name == ".." {
+        return Err(FrameError::Malformed("file name"));
+    }
+    Ok(())
+}
+
+/// Message text may contain newlines and tabs, but no other control characters
+pub fn validate_message(message_text: &str) -> Result<(), FrameError> {
+    validate_text(message_text, "message", MAX_MESSAGE_LEN, true)
+}
+
+/// Same rules as messages, with a larger size limit
+pub fn validate_welcome(welcome_msg: &str) -> Result<(), FrameError> {
+    validate_text(welcome_msg, "welcome message", MAX_WELCOME_LEN, true)
+}
+
+fn validate_text(text: &str, field: &'static str, max: usize, multiline: bool) -> Result<(), FrameError> {
+    if text.len() > max {
+        return Err(FrameError::TooLong { field, len: text.len(), max });
+    }
+    let allowed = |c: char| multiline && (c == '\n' || c == '\t');
+    if text.chars().any(|c| c.is_control() && !allowed(c)) {
+        return Err(FrameError::ControlCharacter(field));
+    }
+    Ok(())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, FrameError> {
+    let mut buf = [0u8];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, FrameError> {
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, FrameError> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, FrameError> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_uuid<R: Read>(reader: &mut R) -> Result<Uuid, FrameError> {
+    let mut buf = [0u8; 16];
+    reader.read_exact(&mut buf)?;
+    Ok(Uuid::from_u128(u128::from_le_bytes(buf)))
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> Result<[u8; N], FrameError> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_file_name<R: Read>(reader: &mut R) -> Result<String, FrameError> {
+    let file_name = read_text(reader, "file name", MAX_FILE_NAME_LEN, false)?;
+    validate_file_name(&file_name)?;
+    Ok(file_name)
+}
+
+fn read_username<R: Read>(reader: &mut R) -> Result<String, FrameError> {
+    let username = read_text(reader, "username", MAX_USERNAME_LEN, false)?;
+    validate_username(&username)?;
+    Ok(username)
+}
+
+// u16 count, then that many HistoryEntry
+fn read_entries<R: Read>(reader: &mut R) -> Result<Vec<HistoryEntry>, FrameError> {
+    let count = read_u16(reader)? as usize;
+    if count > MAX_HISTORY_BATCH {
+        return Err(FrameError::TooLong { field: "history batch", len: count, max: MAX_HISTORY_BATCH });
+    }
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        entries.push(read_entry(reader)?);
+    }
+    Ok(entries)
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> Result<HistoryEntry, FrameError> {
+    Ok(HistoryEntry {
+        message_id: read_u64(reader)?,
+        timestamp: read_u64(reader)?,
+        flags: read_u8(reader)?,
+        parent_id: read_u64(reader)?,
+        reply_count: read_u32(reader)?,
+        sender_id: read_uuid(reader)?,
+        username: read_username(reader)?,
+        message_text: read_text(reader, "message", MAX_MESSAGE_LEN, true)?,
+    })
+}
+
+// a room ID and an entry for each message, as SEARCHRESULTS and QUEUED carry them
+fn read_hits<R: Read>(reader: &mut R, field: &'static str) -> Result<Vec<SearchHit>, FrameError> {
+    let count = read_u16(reader)? as usize;
+    if count > MAX_HISTORY_BATCH {
+        return Err(FrameError::TooLong { field, len: count, max: MAX_HISTORY_BATCH });
+    }
+    let mut hits = Vec::with_capacity(count);
+    for _ in 0..count {
+        hits.push(SearchHit { room_id: read_u64(reader)?, entry: read_entry(reader)? });
+    }
+    Ok(hits)
+}
+
+fn read_reaction<R: Read>(reader: &mut R) -> Result<String, FrameError> {
+    let reaction = read_text(reader, "reaction", MAX_REACTION_LEN, false)?;
+    validate_reaction(&reaction)?;
+    Ok(reaction)
+}
+
+// u16 length prefix, then that many bytes of UTF-8
+fn read_text<R: Read>(
+    reader: &mut R,
+    field: &'static str,
+    max: usize,
+    multiline: bool) -> Result<String, FrameError> {
+    let len = read_u16(reader)? as usize;
+    if len > max {
+        return Err(FrameError::TooLong { field, len, max });
+    }
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf)?;
+    let text = String::from_utf8(buf).map_err(|_| FrameError::InvalidUtf8(field))?;
+    validate_text(&text, field, max, multiline)?;
+    Ok(text)
+}
+
+fn put_uuid(message: &mut Vec<u8>, uuid: &Uuid) {
+    message.extend(uuid.as_u128().to_le_bytes());
+}
+
+fn put_entries(message: &mut Vec<u8>, entries: &[HistoryEntry]) {
+    let count = entries.len().min(MAX_HISTORY_BATCH);
+    message.extend((count as u16).to_le_bytes());
+    for entry in &entries[..count] {
+        put_entry(message, entry);
+    }
+}
+
+fn put_entry(message: &mut Vec<u8>, entry: &HistoryEntry) {
+    message.extend(entry.message_id.to_le_bytes());
+    message.extend(entry.timestamp.to_le_bytes());
+    message.push(entry.flags);
+    message.extend(entry.parent_id.to_le_bytes());
+    message.extend(entry.reply_count.to_le_bytes());
+    put_uuid(message, &entry.sender_id);
+    put_text(message, &entry.username);
+    put_text(message, &entry.message_text);
+}
+
+fn put_hits(message: &mut Vec<u8>, hits: &[SearchHit]) {
+    let count = hits.len().min(MAX_HISTORY_BATCH);
+    message.extend((count as u16).to_le_bytes());
+    for hit in &hits[..count] {
+        message.extend(hit.room_id.to_le_bytes());
+        put_entry(message, &hit.entry);
+    }
+}
+
+fn put_text(message: &mut Vec<u8>, text: &str) {
+    let mut len = text.len().min(u16::MAX as usize);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    message.extend((len as u16).to_le_bytes());
+    message.extend(&text.as_bytes()[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(message_id: u64, message_text: &str) -> HistoryEntry {
+        HistoryEntry {
+            message_id,
+            timestamp: 1_700_000_000 + message_id,
+            flags: 0,
+            parent_id: 0,
+            reply_count: 2,
+            sender_id: Uuid::from_u128(7),
+            username: String::from("rex"),
+            message_text: String::from(message_text),
+        }
+    }
+
+    fn samples() -> Vec<Frame> {
+        vec![
+            Frame::ChatMsg {
+                message_id: 1,
+                timestamp: 2,
+                sender_id: Uuid::from_u128(3),
+                room_id: 4,
+                nonce: 5,
+                parent_id: 6,
+                message_text: String::from("woof\nwoof ☃"),
+            },
+            Frame::Join { username: String::from("rex") },
+            Frame::Leave,
+            Frame::Version { major_rev: 0, minor_rev: 2, subminor_rev: 9 },
+            Frame::Notice { severity: 1, room_id: u64::MAX, notice_text: String::from("heads up") },
+            Frame::HistoryBatch { room_id: 1, entries: vec![entry(10, "first"), entry(11, "")] },
+            Frame::Capabilities { extensions: vec![1, u64::MAX] },
+            Frame::Reaction { message_id: 9, action: 1, reaction: String::from("👍") },
+            Frame::Extended { extended_type: 42, payload: vec![0, 1, 2, 255] },
+            Frame::Login { username: String::from("rex"), password: String::from("hunter22") },
+            Frame::Challenge { nonce: [7; NONCE_LEN] },
+            Frame::ChallengeResponse { signature: [8; SIGNATURE_LEN] },
+            Frame::DirectMsg {
+                message_id: 1,
+                timestamp: 2,
+                sender_id: Uuid::from_u128(3),
+                username: String::from("rex"),
+                recipient_id: Uuid::from_u128(4),
+                sender_key: [5; PUBLIC_KEY_LEN],
+                recipient_key: [6; PUBLIC_KEY_LEN],
+                nonce: [9; DM_NONCE_LEN],
+                ciphertext: vec![1; 40],
+            },
+            Frame::CreateRoom { room_name: String::from("den"), is_public: false },
+            Frame::RoomInfo {
+                room_id: 3,
+                room_name: String::from("den"),
+                is_public: true,
+                topic: String::from("chewing bones"),
+                description: String::from("a quiet\nplace"),
+            },
+        ]
+    }
+
+    // a u8 type, then a u16 length and that many bytes of text
+    fn text_frame(message_type: u8, text: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![message_type];
+        bytes.extend((text.len() as u16).to_le_bytes());
+        bytes.extend(text);
+        bytes
+    }
+
+    #[test]
+    fn frames_survive_a_round_trip() {
+        for frame in samples() {
+            let bytes = frame.encode();
+            assert_eq!(bytes[0], frame.message_type());
+            let mut reader = &bytes[..];
+            assert_eq!(read_frame(&mut reader).unwrap(), frame);
+            assert!(reader.is_empty(), "{:?} left bytes unread", frame);
+        }
+    }
+
+    #[test]
+    fn frames_read_one_after_another() {
+        let bytes: Vec<u8> = samples().iter().flat_map(Frame::encode).collect();
+        let mut reader = &bytes[..];
+        for frame in samples() {
+            assert_eq!(read_frame(&mut reader).unwrap(), frame);
+        }
+        assert!(read_frame(&mut reader).unwrap_err().is_io());
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        for frame in samples() {
+            let bytes = frame.encode();
+            for len in 0..bytes.len() {
+                let result = read_frame(&mut &bytes[..len]);
+                assert!(result.is_err(), "{:?} cut to {} bytes was read", frame, len);
+            }
+        }
+    }
+
+    #[test]
+    fn over_long_text_is_refused() {
+        let username = vec![b'a'; MAX_USERNAME_LEN + 1];
+        assert!(matches!(
+            read_frame(&mut &text_frame(MessageType::JOIN, &username)[..]),
+            Err(FrameError::TooLong { len, max: MAX_USERNAME_LEN, .. }) if len == MAX_USERNAME_LEN + 1
+        ));
+        let mut chat = Frame::ChatMsg {
+            message_id: 0,
+            timestamp: 0,
+            sender_id: Uuid::nil(),
+            room_id: 0,
+            nonce: 0,
+            parent_id: 0,
+            message_text: "a".repeat(MAX_MESSAGE_LEN),
+        };
+        assert!(read_frame(&mut &chat.encode()[..]).is_ok());
+        if let Frame::ChatMsg { message_text, .. } = &mut chat {
+            message_text.push('a');
+        }
+        assert!(matches!(read_frame(&mut &chat.encode()[..]), Err(FrameError::TooLong { .. })));
+    }
+
+    #[test]
+    fn bad_text_is_refused() {
+        assert!(matches!(read_frame(&mut &text_frame(MessageType::JOIN, b"")[..]), Err(FrameError::Empty(_))));
+        assert!(matches!(read_frame(&mut &text_frame(MessageType::JOIN, b"r\x1bx")[..]), Err(FrameError::ControlCharacter(_))));
+        assert!(matches!(read_frame(&mut &text_frame(MessageType::JOIN, b"r\xffx")[..]), Err(FrameError::InvalidUtf8(_))));
+    }
+
+    #[test]
+    fn unknown_types_are_refused() {
+        assert!(matches!(read_frame(&mut &[255u8][..]), Err(FrameError::UnknownType(255))));
+    }
+
+    #[test]
+    fn over_long_extended_payloads_are_refused_before_reading_them() {
+        let mut bytes = vec![MessageType::EXTENDED];
+        bytes.extend(42u64.to_le_bytes());
+        bytes.extend((MAX_EXTENDED_LEN as u32 + 1).to_le_bytes());
+        // no payload follows: the length alone has to be enough to refuse it
+        assert!(matches!(
+            read_frame(&mut &bytes[..]),
+            Err(FrameError::TooLong { field: "extended payload", .. })
+        ));
+        let mut bytes = vec![MessageType::EXTENDED];
+        bytes.extend(42u64.to_le_bytes());
+        bytes.extend(u32::MAX.to_le_bytes());
+        assert!(matches!(read_frame(&mut &bytes[..]), Err(FrameError::TooLong { .. })));
+    }
+
+    #[test]
+    fn over_long_lists_are_refused() {
+        let mut bytes = vec![MessageType::CAPABILITIES];
+        bytes.extend((MAX_CAPABILITIES as u16 + 1).to_le_bytes());
+        assert!(matches!(read_frame(&mut &bytes[..]), Err(FrameError::TooLong { field: "capabilities", .. })));
+    }
+}
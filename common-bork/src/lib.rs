@@ -1,8 +1,13 @@
 use std::sync::Arc;
 use std::net::TcpStream;
-use uuid::Uuid;
 
-// Matches BorkBork protocol version 0.0.3
+pub mod extension;
+pub mod frame;
+pub mod mention;
+pub use extension::{Extension, ExtensionRegistry};
+pub use frame::{Frame, FrameError, HistoryEntry, ReactionCount, SearchHit, read_frame};
+
+// Matches BorkBork protocol version 0.0.26
 // https://github.com/Trogluddite/BorkBork/blob/main/protocol/network_protocol_specification.md
 pub struct MessageType;
 impl MessageType{
@@ -14,60 +19,109 @@ impl MessageType{
     pub const EXTENDED:     u8 = 5;
     pub const USERJOINED:   u8 = 6;
     pub const USERLEFT:     u8 = 7;
+    pub const HISTORY:      u8 = 8;
+    pub const HISTORYBATCH: u8 = 9;
+    pub const SERVERSHUTDOWN: u8 = 10;
+    pub const NOTICE:       u8 = 11;
+    pub const CAPABILITIES: u8 = 12;
+    pub const SENTACK:      u8 = 13;
+    pub const EDITMSG:      u8 = 14;
+    pub const DELETEMSG:    u8 = 15;
+    pub const REACTION:     u8 = 16;
+    pub const REACTIONS:    u8 = 17;
+    pub const THREAD:       u8 = 18;
+    pub const THREADBATCH:  u8 = 19;
+    pub const TYPING:       u8 = 20;
+    pub const PRESENCE:     u8 = 21;
+    pub const OFFER:        u8 = 22;
+    pub const CHUNK:        u8 = 23;
+    pub const COMPLETE:     u8 = 24;
+    pub const FILEREQUEST:  u8 = 25;
+    pub const ATTACHMENT:   u8 = 26;
+    pub const SEARCH:       u8 = 27;
+    pub const SEARCHRESULTS: u8 = 28;
+    pub const MARKREAD:     u8 = 29;
+    pub const UNREAD:       u8 = 30;
+    pub const MENTIONS:     u8 = 31;
+    pub const QUEUED:       u8 = 32;
+    pub const SESSION:      u8 = 33;
+    pub const RESUME:       u8 = 34;
+    pub const REGISTER:     u8 = 35;
+    pub const VERIFY:       u8 = 36;
+    pub const LOGIN:        u8 = 37;
+    pub const CHANGEPASSWORD: u8 = 38;
+    pub const RESETREQUEST: u8 = 39;
+    pub const RESETPASSWORD: u8 = 40;
+    pub const KEYLOGIN:     u8 = 41;
+    pub const CHALLENGE:    u8 = 42;
+    pub const CHALLENGERESPONSE: u8 = 43;
+    pub const ADDKEY:       u8 = 44;
+    pub const REMOVEKEY:    u8 = 45;
+    pub const PUBLISHKEY:   u8 = 46;
+    pub const KEYREQUEST:   u8 = 47;
+    pub const IDENTITYKEY:  u8 = 48;
+    pub const DIRECTMSG:    u8 = 49;
+    pub const CREATEROOM:   u8 = 50;
+    pub const JOINROOM:     u8 = 51;
+    pub const INVITE:       u8 = 52;
+    pub const ROOMINFO:     u8 = 53;
+    pub const SETTOPIC:     u8 = 54;
+    pub const ROOMROLE:     u8 = 55;
+}
+
+pub struct NoticeSeverity;
+impl NoticeSeverity{
+    pub const INFO:         u8 = 0;
+    pub const WARNING:      u8 = 1;
+    pub const CRITICAL:     u8 = 2;
+}
+
+// bits in HistoryEntry::flags
+pub struct MessageFlags;
+impl MessageFlags{
+    pub const EDITED:       u8 = 1;
+    pub const DELETED:      u8 = 2;
+}
+
+pub struct ReactionAction;
+impl ReactionAction{
+    pub const ADD:          u8 = 0;
+    pub const REMOVE:       u8 = 1;
+}
+
+// a connected user's availability; a user who isn't connected has no presence at all
+pub struct PresenceStatus;
+impl PresenceStatus{
+    pub const ONLINE:       u8 = 0;
+    pub const AWAY:         u8 = 1;
+    pub const DO_NOT_DISTURB: u8 = 2;
 }
 
+// what a member may do in one room, apart from their role on the server as a whole. Owners set the
+// others' roles; owners and moderators set the topic and may edit and delete anyone's messages there.
+pub struct RoomRole;
+impl RoomRole{
+    pub const MEMBER:       u8 = 0;
+    pub const MODERATOR:    u8 = 1;
+    pub const OWNER:        u8 = 2;
+}
+
+// room 0 is never a real room; a NOTICE scoped to it goes to everyone
+pub const ALL_ROOMS: u64 = 0;
+// every user is a member of the lobby; it always exists
+pub const LOBBY_ROOM_ID: u64 = 1;
+
+// Registry of extension IDs carried in EXTENDED messages. IDs are never reused; add new ones to
+// the table in the protocol specification as well.
 pub struct ExtendedMessageType;
 impl ExtendedMessageType{
-    pub const FUTURE: u64 = 0;
+    pub const FUTURE: u64 = 0; // reserved
+    pub const PING:   u64 = 1;
+    pub const PONG:   u64 = 2;
 }
 
-pub enum Message{
-    ChatMsg{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-        sender_id:      u128,
-        message_len:    u16,
-        message_text:   Vec<u8>,
-    },
-    Join{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-        name_len:       u16,
-        username:       Vec<u8>,
-    },
-    Leave{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-    },
-    Version{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-        major_rev:      u16,
-        minor_rev:      u16,
-        subminor_rev:   u16,
-    },
-    Welcome{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-        message_len:    u16,
-        welcome_msg:    Vec<u8>,
-    },
-    Extended{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-        extended_type:  u64,
-        content:        Vec<u8>, //future: we likely want type-specific controls for the extensions
-    },
-    Userjoined{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-        user_id:        Uuid,
-        username_len:   u16,
-        username:       Vec<u8>,
-    },
-    Userleft{
-        author:         Arc<TcpStream>,
-        message_type:   u8,
-        user_id:        Uuid,
-    }
+/// A frame to be written to (or that was read from) a particular client connection
+pub struct Message{
+    pub author:     Arc<TcpStream>,
+    pub frame:      Frame,
 }
@@ -0,0 +1,81 @@
+use std::ops::Range;
+
+/// Every `@name` in `text` that names one of `names`, as the byte range of the mention (the `@`
+/// included) and the name it matched. Usernames may contain spaces and punctuation, so rather than
+/// guessing where a name ends, the longest of `names` that follows the `@` wins. A mention has to
+/// stand on its own: an `@` straight after a letter or digit (as in an email address) isn't one, and
+/// neither is a name that runs on into more letters, digits or underscores.
+pub fn find_mentions<'a>(text: &str, names: &[&'a str]) -> Vec<(Range<usize>, &'a str)> {
+    let mut mentions = Vec::new();
+    let mut at = 0;
+    while let Some(found) = text[at..].find('@') {
+        let start = at + found;
+        at = start + 1;
+        if text[..start].chars().next_back().is_some_and(char::is_alphanumeric) {
+            continue;
+        }
+        let rest = &text[at..];
+        let name = names.iter()
+            .filter(|name| !name.is_empty() && rest.starts_with(**name))
+            .filter(|name| !rest[name.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+            .max_by_key(|name| name.len());
+        if let Some(&name) = name {
+            at += name.len();
+            mentions.push((start..at, name));
+        }
+    }
+    mentions
+}
+
+/// The distinct names `text` mentions, in the order they first appear
+pub fn mentioned_names<'a>(text: &str, names: &[&'a str]) -> Vec<&'a str> {
+    let mut mentioned: Vec<&str> = Vec::new();
+    for (_, name) in find_mentions(text, names) {
+        if !mentioned.contains(&name) {
+            mentioned.push(name);
+        }
+    }
+    mentioned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: &[&str] = &["rex", "rex the dog", "ann", ""];
+
+    #[test]
+    fn finds_mentions_with_their_ranges() {
+        assert_eq!(find_mentions("@ann, hi @rex.", NAMES), [(0..4, "ann"), (9..13, "rex")]);
+        assert_eq!(find_mentions("nobody here", NAMES), []);
+    }
+
+    #[test]
+    fn the_longest_name_wins() {
+        assert_eq!(find_mentions("hi @rex the dog!", NAMES), [(3..15, "rex the dog")]);
+        assert_eq!(find_mentions("hi @rex the cat", NAMES), [(3..7, "rex")]);
+    }
+
+    #[test]
+    fn a_mention_stands_on_its_own() {
+        assert_eq!(find_mentions("ann@rex.com", NAMES), []);
+        assert_eq!(find_mentions("@rexy @rex_ @ann2", NAMES), []);
+        assert_eq!(find_mentions("(@rex)", NAMES), [(1..5, "rex")]);
+        assert_eq!(find_mentions("@ @", NAMES), []);
+    }
+
+    #[test]
+    fn ranges_are_bytes_in_multibyte_text() {
+        let text = "über @ann";
+        let mentions = find_mentions(text, NAMES);
+        assert_eq!(mentions, [(6..10, "ann")]);
+        assert_eq!(&text[mentions[0].0.clone()], "@ann");
+        assert_eq!(find_mentions("é@ann", NAMES), []);
+        assert_eq!(find_mentions("@annü", NAMES), []);
+    }
+
+    #[test]
+    fn mentioned_names_are_distinct_and_in_order() {
+        assert_eq!(mentioned_names("@ann @rex @ann", NAMES), ["ann", "rex"]);
+    }
+}
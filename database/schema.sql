@@ -1,34 +1,58 @@
-DROP TABLE IF EXISTS user;
-DROP TABLE IF EXISTS rooms;
-DROP TABLE IF EXISTS room_membership;
-DROP TABLE IF EXISTS chat_history;
+-- applied by server-bork every time it starts, so everything here must be idempotent
 
-CREATE TABLE user (
+CREATE TABLE IF NOT EXISTS user (
   user_id INTEGER PRIMARY KEY AUTOINCREMENT,
   created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
-  username VARCHAR NOT NULL,
+  uuid VARCHAR NOT NULL UNIQUE,
+  username VARCHAR NOT NULL UNIQUE,
   email VARCHAR NOT NULL,
   email_verified BIT NOT NULL,
   role VARCHAR NOT NULL,
   password_hash INTEGER
 );
 
-CREATE TABLE room (
+-- anyone may join a public room; a private one only takes members someone in it has invited. topic and
+-- description are newer than the table, and Database::open adds them to databases without them
+CREATE TABLE IF NOT EXISTS room (
   room_id INTEGER PRIMARY KEY AUTOINCREMENT,
   created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
   room_name VARCHAR NOT NULL,
-  is_public BIT NOT NULL
+  is_public BIT NOT NULL,
+  topic TEXT NOT NULL DEFAULT '',
+  description TEXT NOT NULL DEFAULT ''
 );
 
-CREATE TABLE room_membership (
+-- rooms are joined by name, so no two may share one
+CREATE UNIQUE INDEX IF NOT EXISTS room_name ON room (room_name COLLATE NOCASE);
+
+-- everyone lands in the lobby, and it has no owner
+INSERT OR IGNORE INTO room (room_id, room_name, is_public) VALUES (1, 'lobby', 1);
+
+-- last_read is the newest message_id the user has seen in the room; room_role is 'owner', 'moderator' or
+-- 'member', and separate from user.role. Database::open adds either to databases from before they existed
+CREATE TABLE IF NOT EXISTS room_membership (
   user_id INTEGER,
   room_id INTEGER,
+  last_read INTEGER NOT NULL DEFAULT 0,
+  room_role VARCHAR NOT NULL DEFAULT 'member',
   PRIMARY KEY (user_id, room_id),
   FOREIGN KEY (user_id) REFERENCES user(user_id),
   FOREIGN KEY (room_id) REFERENCES room(room_id)
 );
 
-CREATE TABLE chat_history(
+-- an invitation to a room, from one of its members; used up when the invited user joins
+CREATE TABLE IF NOT EXISTS room_invite (
+  room_id INTEGER NOT NULL,
+  user_id INTEGER NOT NULL,
+  invited_by INTEGER NOT NULL,
+  created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (room_id, user_id),
+  FOREIGN KEY (room_id) REFERENCES room(room_id),
+  FOREIGN KEY (user_id) REFERENCES user(user_id),
+  FOREIGN KEY (invited_by) REFERENCES user(user_id)
+);
+
+CREATE TABLE IF NOT EXISTS chat_history(
   message_id INTEGER PRIMARY KEY AUTOINCREMENT,
   message_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
   message_text TEXT,
@@ -37,3 +61,182 @@ CREATE TABLE chat_history(
   FOREIGN KEY (room_id) REFERENCES room(room_id)
 );
 
+CREATE INDEX IF NOT EXISTS chat_history_room ON chat_history (room_id, message_id);
+
+-- what a message said before each edit; chat_history always holds the current text
+CREATE TABLE IF NOT EXISTS message_revision (
+  revision_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  message_id INTEGER NOT NULL,
+  edited TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  editor_id INTEGER NOT NULL,
+  message_text TEXT,
+  FOREIGN KEY (message_id) REFERENCES chat_history(message_id),
+  FOREIGN KEY (editor_id) REFERENCES user(user_id)
+);
+
+CREATE INDEX IF NOT EXISTS message_revision_message ON message_revision (message_id);
+
+-- replies: every message here belongs to the thread started by parent_id, which is never a reply itself
+CREATE TABLE IF NOT EXISTS message_thread (
+  message_id INTEGER PRIMARY KEY,
+  parent_id INTEGER NOT NULL,
+  FOREIGN KEY (message_id) REFERENCES chat_history(message_id),
+  FOREIGN KEY (parent_id) REFERENCES chat_history(message_id)
+);
+
+CREATE INDEX IF NOT EXISTS message_thread_parent ON message_thread (parent_id, message_id);
+
+-- one row per user per reaction; counts are aggregated when they're sent out
+CREATE TABLE IF NOT EXISTS reaction (
+  message_id INTEGER NOT NULL,
+  user_id INTEGER NOT NULL,
+  reaction VARCHAR NOT NULL,
+  created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (message_id, user_id, reaction),
+  FOREIGN KEY (message_id) REFERENCES chat_history(message_id),
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+
+-- deleted messages keep their chat_history row, with the text moved into message_revision, so
+-- message IDs stay meaningful
+CREATE TABLE IF NOT EXISTS message_tombstone (
+  message_id INTEGER PRIMARY KEY,
+  deleted TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  deleted_by INTEGER NOT NULL,
+  FOREIGN KEY (message_id) REFERENCES chat_history(message_id),
+  FOREIGN KEY (deleted_by) REFERENCES user(user_id)
+);
+
+-- a message that carries a file; the file itself is on disk, named for its SHA-256, and
+-- chat_history.message_text holds its name
+CREATE TABLE IF NOT EXISTS attachment (
+  message_id INTEGER PRIMARY KEY,
+  file_name VARCHAR NOT NULL,
+  size INTEGER NOT NULL,
+  sha256 BLOB NOT NULL,
+  FOREIGN KEY (message_id) REFERENCES chat_history(message_id)
+);
+
+-- users a message mentions by @name; worked out by the server whenever the message is sent or edited
+CREATE TABLE IF NOT EXISTS mention (
+  message_id INTEGER NOT NULL,
+  user_id INTEGER NOT NULL,
+  PRIMARY KEY (message_id, user_id),
+  FOREIGN KEY (message_id) REFERENCES chat_history(message_id),
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+
+CREATE INDEX IF NOT EXISTS mention_user ON mention (user_id, message_id);
+
+-- messages held for users who were offline when they were sent, handed over (and deleted) the next
+-- time they join
+CREATE TABLE IF NOT EXISTS delivery_queue (
+  user_id INTEGER NOT NULL,
+  message_id INTEGER NOT NULL,
+  queued TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, message_id),
+  FOREIGN KEY (user_id) REFERENCES user(user_id),
+  FOREIGN KEY (message_id) REFERENCES chat_history(message_id)
+);
+
+-- logins that can be resumed without logging in again. Only a hash of each token is kept, so a copy
+-- of the database can't be used to take over anyone's session.
+CREATE TABLE IF NOT EXISTS session (
+  token_hash BLOB PRIMARY KEY,
+  user_id INTEGER NOT NULL,
+  created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires INTEGER NOT NULL, -- unix time; pushed back each time the session is resumed
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+
+-- a code mailed to the address a user registered, waiting to be typed back in. Hashed, like session
+-- tokens; a few wrong guesses and it's no good.
+CREATE TABLE IF NOT EXISTS email_verification (
+  user_id INTEGER PRIMARY KEY,
+  code_hash BLOB NOT NULL,
+  expires INTEGER NOT NULL, -- unix time
+  attempts INTEGER NOT NULL DEFAULT 0,
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+
+-- a code mailed to a user's verified address so they can set a new password, in the same way
+CREATE TABLE IF NOT EXISTS password_reset (
+  user_id INTEGER PRIMARY KEY,
+  code_hash BLOB NOT NULL,
+  expires INTEGER NOT NULL, -- unix time
+  attempts INTEGER NOT NULL DEFAULT 0,
+  sent INTEGER NOT NULL DEFAULT 0, -- unix time the latest code was mailed
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+
+-- a wrong reset code, kept for a while so an account can only be guessed at so many times however
+-- many codes are asked for
+CREATE TABLE IF NOT EXISTS reset_guess (
+  user_id INTEGER NOT NULL,
+  guessed INTEGER NOT NULL, -- unix time
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+CREATE INDEX IF NOT EXISTS reset_guess_user ON reset_guess (user_id, guessed);
+
+-- a failed LOGIN, by the name tried rather than the account, so names nobody has are slowed down in the
+-- same way and the refusal doesn't say which exist
+CREATE TABLE IF NOT EXISTS login_failure (
+  username VARCHAR NOT NULL COLLATE NOCASE,
+  peer VARCHAR NOT NULL, -- IP address it came from
+  failed INTEGER NOT NULL -- unix time
+);
+CREATE INDEX IF NOT EXISTS login_failure_username ON login_failure (username, failed);
+CREATE INDEX IF NOT EXISTS login_failure_peer ON login_failure (peer, failed);
+
+-- an ed25519 public key a user can log in with, by signing a challenge; a key belongs to one account
+CREATE TABLE IF NOT EXISTS user_key (
+  public_key BLOB PRIMARY KEY, -- 32 bytes
+  user_id INTEGER NOT NULL,
+  added INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+
+-- a user's X25519 public key for end-to-end encrypted direct messages; publishing another replaces it
+CREATE TABLE IF NOT EXISTS identity_key (
+  user_id INTEGER PRIMARY KEY,
+  public_key BLOB NOT NULL, -- 32 bytes
+  published INTEGER NOT NULL DEFAULT (CAST(strftime('%s', 'now') AS INTEGER)),
+  FOREIGN KEY (user_id) REFERENCES user(user_id)
+);
+
+-- a direct message, encrypted by the sender's client for the recipient's: all the server can do with it
+-- is keep it and pass it on. delivered is set once the recipient has been sent it.
+CREATE TABLE IF NOT EXISTS direct_message (
+  message_id INTEGER PRIMARY KEY AUTOINCREMENT,
+  message_time TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  sender_id INTEGER NOT NULL,
+  recipient_id INTEGER NOT NULL,
+  sender_key BLOB NOT NULL, -- the identity keys it was encrypted between
+  recipient_key BLOB NOT NULL,
+  nonce BLOB NOT NULL,
+  ciphertext BLOB NOT NULL,
+  delivered BIT NOT NULL DEFAULT 0,
+  FOREIGN KEY (sender_id) REFERENCES user(user_id),
+  FOREIGN KEY (recipient_id) REFERENCES user(user_id)
+);
+
+-- full-text index over chat_history.message_text, kept up to date by the triggers below; the
+-- server rebuilds it at startup if it's out of step (say, for history from before it existed)
+CREATE VIRTUAL TABLE IF NOT EXISTS chat_search USING fts5 (
+  message_text,
+  content = 'chat_history',
+  content_rowid = 'message_id'
+);
+
+CREATE TRIGGER IF NOT EXISTS chat_search_insert AFTER INSERT ON chat_history BEGIN
+  INSERT INTO chat_search (rowid, message_text) VALUES (new.message_id, new.message_text);
+END;
+
+CREATE TRIGGER IF NOT EXISTS chat_search_update AFTER UPDATE OF message_text ON chat_history BEGIN
+  INSERT INTO chat_search (chat_search, rowid, message_text) VALUES ('delete', old.message_id, old.message_text);
+  INSERT INTO chat_search (rowid, message_text) VALUES (new.message_id, new.message_text);
+END;
+
+CREATE TRIGGER IF NOT EXISTS chat_search_delete AFTER DELETE ON chat_history BEGIN
+  INSERT INTO chat_search (chat_search, rowid, message_text) VALUES ('delete', old.message_id, old.message_text);
+END;
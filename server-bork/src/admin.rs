@@ -0,0 +1,264 @@
+use log::{error, info};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common_bork::{ALL_ROOMS, Frame, Message, NoticeSeverity};
+
+use crate::config::Config;
+use crate::db::{Database, ROLE_ADMIN, ROLE_MODERATOR, ROLE_USER};
+use crate::{load_motd, request_shutdown, send_notice, send_to, wait_for_writes, Result, ServerState,
+    ShutdownFlag, ShutdownRequest};
+
+// admin commands are a single line of text; nothing legitimate is longer than a notice
+const MAX_COMMAND_LEN: u64 = 8192;
+
+const USAGE: &str = "\
+usage: server-bork admin <command>
+
+commands:
+    list                list connections, with peer address and user
+    kick <username> [reason]
+                        disconnect every connection joined as <username>, and tell everyone
+    notice [--severity info|warning|critical] [--room <room id>] <text>
+                        send a notice to every joined user, or only to a room's members
+    reload-motd         re-read the MOTD file; new connections get the new text
+    role <username> user|moderator|admin
+                        change a user's role; moderators and admins may edit and delete anyone's
+                        messages
+    shutdown [eta] [reason]
+                        stop accepting connections, tell everyone why (and, optionally, in
+                        how many seconds the server will be back), disconnect them and exit
+";
+
+/// `server-bork admin ...`: send one command to a running server's control socket and print the
+/// reply
+pub fn run_cli(args: &[String], config: &Config) -> Result<()> {
+    if args.is_empty() {
+        eprint!("{}", USAGE);
+        return Err(());
+    }
+    let mut stream = UnixStream::connect(&config.admin_socket).map_err(|err| {
+        eprintln!("couldn't connect to admin socket {}: {}", config.admin_socket, err);
+    })?;
+    writeln!(stream, "{}", args.join(" ")).map_err(|err| {
+        eprintln!("couldn't send command: {}", err);
+    })?;
+    let _ = stream.shutdown(Shutdown::Write);
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).map_err(|err| {
+        eprintln!("couldn't read reply: {}", err);
+    })?;
+    print!("{}", reply);
+    Ok(())
+}
+
+/// Everything an admin command may need to reach into
+pub struct AdminContext{
+    pub config:         Config,
+    pub message:        Sender<Message>,
+    pub server_state:   Arc<Mutex<ServerState>>,
+    pub db:             Arc<Mutex<Database>>,
+    pub shutdown:       ShutdownFlag,
+}
+
+/// Listen on the control socket, one command per connection. Only the user running the server
+/// may connect.
+pub fn listen(context: AdminContext) -> Result<()> {
+    let path = &context.config.admin_socket;
+    // a socket left behind by a previous run would make bind fail
+    let _ = fs::remove_file(path);
+    let listener = bind_private(path)?;
+    info!("admin console listening on {}", path);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = handle_admin(stream, &context) {
+                    error!("admin command failed: {}", err);
+                }
+            }
+            Err(err) => error!("couldn't accept admin connection: {}", err),
+        }
+        if context.shutdown.lock().unwrap().is_some() {
+            break;
+        }
+    }
+    let _ = fs::remove_file(path);
+    Ok(())
+}
+
+// bind creates the socket with the process umask, so it's bound inside a directory only we can enter,
+// narrowed to 0600 there, and only then moved to `path`; nobody else gets a moment to connect
+fn bind_private(path: &str) -> Result<UnixListener> {
+    let dir = format!("{}.{}.tmp", path, std::process::id());
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir).map_err(|err| {
+        error!("couldn't create {} to bind the admin socket in: {}", dir, err);
+    })?;
+    let staged = format!("{}/socket", dir);
+    let bound = UnixListener::bind(&staged).map_err(|err| {
+        error!("couldn't bind admin socket {}: {}", path, err);
+    }).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600)).map_err(|err| {
+            error!("couldn't restrict permissions on admin socket {}: {}", path, err);
+        })?;
+        fs::rename(&staged, path).map_err(|err| {
+            error!("couldn't move admin socket into place at {}: {}", path, err);
+        })?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&dir);
+    bound
+}
+
+fn handle_admin(stream: UnixStream, context: &AdminContext) -> std::io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).take(MAX_COMMAND_LEN).read_line(&mut line)?;
+    let line = line.trim();
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+    let argument = argument.trim();
+    info!("admin command: {}", line);
+
+    let reply = match command {
+        "list" => list(context),
+        "kick" if !argument.is_empty() => kick(context, argument),
+        "notice" if !argument.is_empty() => notice(context, argument),
+        "reload-motd" => reload_motd(context),
+        "role" if !argument.is_empty() => role(context, argument),
+        "shutdown" => match shutdown_request(context, argument) {
+            Ok(request) => {
+                // reply first; the process may be gone soon after the request is made
+                (&stream).write_all(b"shutting down\n")?;
+                request_shutdown(&context.shutdown, request);
+                return Ok(());
+            }
+            Err(reply) => reply,
+        },
+        _ => String::from(USAGE),
+    };
+    (&stream).write_all(reply.as_bytes())
+}
+
+fn list(context: &AdminContext) -> String {
+    let state = context.server_state.lock().unwrap();
+    let mut reply = format!("{} connection(s)\n", state.connections.len());
+    for (peer, connection) in &state.connections {
+        let user = state.username(connection).unwrap_or("(not joined)");
+        reply.push_str(&format!("{}\t{}\n", peer, user));
+    }
+    reply
+}
+
+// the kicked user gets told why; shutting their socket down then makes their thread see EOF and
+// clean up as if they had left
+fn kick(context: &AdminContext, argument: &str) -> String {
+    let (username, reason) = argument.split_once(' ').unwrap_or((argument, ""));
+    let reason = reason.trim();
+    let streams: Vec<_> = {
+        let state = context.server_state.lock().unwrap();
+        state.connections.iter()
+            .filter(|(_, c)| state.username(c) == Some(username))
+            .map(|(peer, c)| {
+                info!("kicking {} ({:?})", username, peer);
+                c.stream.clone()
+            })
+            .collect()
+    };
+    if streams.is_empty() {
+        return format!("{} isn't connected\n", username);
+    }
+
+    let why = if reason.is_empty() { String::new() } else { format!(": {}", reason) };
+    let notice = Frame::Notice {
+        severity: NoticeSeverity::CRITICAL,
+        room_id: ALL_ROOMS,
+        notice_text: format!("you have been disconnected by an administrator{}", why),
+    };
+    let _ = send_to(&context.message, streams.clone(), notice);
+    wait_for_writes(Duration::from_secs(1));
+    for stream in &streams {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    let _ = send_notice(&context.message, &context.server_state, &context.db,
+        NoticeSeverity::INFO, ALL_ROOMS, &format!("{} was removed by an administrator{}", username, why));
+    format!("kicked {} connection(s) for {}\n", streams.len(), username)
+}
+
+fn notice(context: &AdminContext, argument: &str) -> String {
+    let mut severity = NoticeSeverity::INFO;
+    let mut room_id = ALL_ROOMS;
+    let mut rest = argument;
+    loop {
+        let (flag, after) = rest.split_once(' ').unwrap_or((rest, ""));
+        let (value, after) = after.trim_start().split_once(' ').unwrap_or((after.trim_start(), ""));
+        match flag {
+            "--severity" => severity = match value {
+                "info" => NoticeSeverity::INFO,
+                "warning" => NoticeSeverity::WARNING,
+                "critical" => NoticeSeverity::CRITICAL,
+                _ => return format!("unknown severity {:?}\n", value),
+            },
+            "--room" => match value.parse() {
+                Ok(id) => room_id = id,
+                Err(_) => return format!("bad room id {:?}\n", value),
+            },
+            _ => break,
+        }
+        rest = after.trim_start();
+    }
+    if rest.is_empty() {
+        return String::from(USAGE);
+    }
+    if let Err(err) = common_bork::frame::validate_message(rest) {
+        return format!("not sending notice: {}\n", err);
+    }
+    match send_notice(&context.message, &context.server_state, &context.db, severity, room_id, rest) {
+        Ok(count) => format!("notice sent to {} connection(s)\n", count),
+        Err(()) => String::from("couldn't send notice; see server.log\n"),
+    }
+}
+
+fn reload_motd(context: &AdminContext) -> String {
+    let motd = load_motd(&context.config);
+    let reply = format!("MOTD is now {} bytes\n", motd.len());
+    context.server_state.lock().unwrap().motd = motd;
+    reply
+}
+
+fn role(context: &AdminContext, argument: &str) -> String {
+    let Some((username, role)) = argument.split_once(' ') else {
+        return String::from(USAGE);
+    };
+    let role = role.trim();
+    if ![ROLE_USER, ROLE_MODERATOR, ROLE_ADMIN].contains(&role) {
+        return format!("unknown role {:?}\n", role);
+    }
+    match context.db.lock().unwrap().set_role(username, role) {
+        Ok(true) => format!("{} is now a {}\n", username, role),
+        Ok(false) => format!("there's no user called {}\n", username),
+        Err(()) => String::from("couldn't change role; see server.log\n"),
+    }
+}
+
+fn shutdown_request(context: &AdminContext, argument: &str) -> std::result::Result<ShutdownRequest, String> {
+    // an optional leading number is the restart ETA; anything after it is the reason
+    let (first, rest) = argument.split_once(' ').unwrap_or((argument, ""));
+    let (restart_eta, reason) = match first.parse::<u32>() {
+        Ok(eta) => (eta, rest.trim()),
+        Err(_) => (context.config.restart_eta, argument),
+    };
+    let reason = if reason.is_empty() { context.config.shutdown_reason.as_str() } else { reason };
+    if let Err(err) = common_bork::frame::validate_message(reason) {
+        return Err(format!("not shutting down: reason {}\n", err));
+    }
+    Ok(ShutdownRequest {
+        reason: String::from(reason),
+        restart_eta,
+    })
+}
@@ -0,0 +1,63 @@
+use log::{error, info};
+use std::env;
+use std::str::FromStr;
+
+use common_bork::frame::MAX_HISTORY_BATCH;
+
+const DEFAULT_HISTORY_ON_JOIN: u16 = 50;
+const DEFAULT_ADMIN_SOCKET: &str = "./server-bork.sock";
+const DEFAULT_ATTACHMENT_DIR: &str = "./attachments";
+const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 8 * 1024 * 1024;
+const DEFAULT_MAIL: &str = "stdout";
+const DEFAULT_MAIL_FROM: &str = "borkbork@localhost";
+const DEFAULT_MOTD_FILE: &str = "./motd.txt";
+const DEFAULT_SESSION_LIFETIME: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_SHUTDOWN_DEADLINE: u64 = 10;
+const DEFAULT_SHUTDOWN_REASON: &str = "the server is shutting down";
+
+/// Runtime settings, read once at startup from BORK_* environment variables
+#[derive(Clone, Debug)]
+pub struct Config{
+    pub admin_socket:       String,
+    pub attachment_dir:     String,
+    pub history_on_join:    u16,
+    pub mail:               String, // where mail goes: smtp://host:port, file:<path> or stdout
+    pub mail_from:          String,
+    pub max_attachment_size: u64,   // bytes; bigger uploads are refused
+    pub motd_file:          String,
+    pub restart_eta:        u32,    // seconds; sent to clients on shutdown, 0 if unknown
+    pub session_lifetime:   u64,    // seconds a session token lasts without being used
+    pub shutdown_deadline:  u64,    // seconds a graceful shutdown may take before we just exit
+    pub shutdown_reason:    String, // sent to clients when a signal shuts the server down
+}
+
+impl Config{
+    pub fn from_env() -> Config{
+        let config = Config{
+            admin_socket: env_or("BORK_ADMIN_SOCKET", String::from(DEFAULT_ADMIN_SOCKET)),
+            attachment_dir: env_or("BORK_ATTACHMENT_DIR", String::from(DEFAULT_ATTACHMENT_DIR)),
+            history_on_join: env_or("BORK_HISTORY_ON_JOIN", DEFAULT_HISTORY_ON_JOIN)
+                .min(MAX_HISTORY_BATCH as u16),
+            mail: env_or("BORK_MAIL", String::from(DEFAULT_MAIL)),
+            mail_from: env_or("BORK_MAIL_FROM", String::from(DEFAULT_MAIL_FROM)),
+            max_attachment_size: env_or("BORK_MAX_ATTACHMENT_SIZE", DEFAULT_MAX_ATTACHMENT_SIZE),
+            motd_file: env_or("BORK_MOTD_FILE", String::from(DEFAULT_MOTD_FILE)),
+            restart_eta: env_or("BORK_RESTART_ETA", 0),
+            session_lifetime: env_or("BORK_SESSION_LIFETIME", DEFAULT_SESSION_LIFETIME),
+            shutdown_deadline: env_or("BORK_SHUTDOWN_DEADLINE", DEFAULT_SHUTDOWN_DEADLINE),
+            shutdown_reason: env_or("BORK_SHUTDOWN_REASON", String::from(DEFAULT_SHUTDOWN_REASON)),
+        };
+        info!("running with config: {:?}", config);
+        config
+    }
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            error!("couldn't parse {}={:?}; using the default", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,1564 @@
+use log::{error, info};
+use rand::{Rng, RngCore};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use sqlite::{Connection, State, Statement};
+use uuid::Uuid;
+
+use common_bork::{Frame, HistoryEntry, LOBBY_ROOM_ID, MessageFlags, ReactionCount, RoomRole, SearchHit};
+use common_bork::frame::{MAX_MENTIONS, PUBLIC_KEY_LEN};
+use common_bork::mention::mentioned_names;
+
+use crate::Result;
+
+const SCHEMA: &str = include_str!("../../database/schema.sql");
+
+// everything a HistoryEntry needs; read back by read_entries. Filter on h (chat_history) and
+// th (message_thread, NULL for messages that aren't replies)
+const ENTRY_QUERY: &str = "
+    SELECT h.message_id, CAST(strftime('%s', h.message_time) AS INTEGER), u.uuid, u.username, h.message_text,
+        EXISTS (SELECT 1 FROM message_revision r WHERE r.message_id = h.message_id),
+        EXISTS (SELECT 1 FROM message_tombstone t WHERE t.message_id = h.message_id),
+        th.parent_id,
+        (SELECT COUNT(*) FROM message_thread replies WHERE replies.parent_id = h.message_id),
+        h.room_id
+    FROM chat_history h
+        JOIN user u ON u.user_id = h.sender_id
+        LEFT JOIN message_thread th ON th.message_id = h.message_id";
+
+// values of user.role
+pub const ROLE_USER: &str = "user";
+pub const ROLE_MODERATOR: &str = "moderator";
+pub const ROLE_ADMIN: &str = "admin";
+
+/// Moderators and admins may edit and delete anyone's messages
+pub fn is_moderator(role: &str) -> bool {
+    role == ROLE_MODERATOR || role == ROLE_ADMIN
+}
+
+// values of room_membership.room_role, and the RoomRole each goes out as
+const ROOM_OWNER: &str = "owner";
+const ROOM_MODERATOR: &str = "moderator";
+const ROOM_MEMBER: &str = "member";
+
+fn room_role_name(room_role: u8) -> &'static str {
+    match room_role {
+        RoomRole::OWNER => ROOM_OWNER,
+        RoomRole::MODERATOR => ROOM_MODERATOR,
+        _ => ROOM_MEMBER,
+    }
+}
+
+fn room_role(name: &str) -> u8 {
+    match name {
+        ROOM_OWNER => RoomRole::OWNER,
+        ROOM_MODERATOR => RoomRole::MODERATOR,
+        _ => RoomRole::MEMBER,
+    }
+}
+
+/// Who wrote a stored message, and where
+pub struct StoredMessage{
+    pub sender_id:  i64,
+    pub room_id:    u64,
+    pub deleted:    bool,
+    pub parent_id:  Option<u64>, // set if this is a reply
+}
+
+/// What a search is narrowed down to, besides its words
+pub struct SearchFilter{
+    pub room_id:    u64,
+    pub username:   String,
+    pub since:      u64,
+    pub until:      u64,
+    pub before_id:  u64,
+}
+
+/// An existing account, as LOGIN and password resets need it
+pub struct Account{
+    pub user_id:        i64,
+    pub uuid:           Uuid,
+    pub email:          String,
+    pub email_verified: bool,
+    pub password_hash:  Option<String>, // None if they haven't set one
+    pub has_keys:       bool,           // whether they've added any public keys
+}
+
+impl Account{
+    /// Whether anyone may JOIN as this user: only if they have no password and no keys
+    pub fn is_guest(&self) -> bool {
+        self.password_hash.is_none() && !self.has_keys
+    }
+}
+
+// the codes we mail out, each kind kept in its own table
+#[derive(Clone, Copy)]
+enum CodeKind{
+    Verification,
+    Reset,
+}
+impl CodeKind{
+    fn table(self) -> &'static str {
+        match self {
+            CodeKind::Verification => "email_verification",
+            CodeKind::Reset => "password_reset",
+        }
+    }
+}
+
+// how a code someone typed in compares with the one we sent
+enum CodeCheck{
+    Right,
+    Wrong,
+    UsedUp,  // wrong, and that was the last try
+    Expired,
+    Missing, // nothing was sent, or it's been used
+}
+
+/// How often an account's password reset may be asked for and guessed at
+pub struct ResetThrottle{
+    pub cooldown:    u64, // seconds between mailed codes
+    pub window:      u64, // seconds a wrong code counts against the account
+    pub max_guesses: u32, // wrong codes allowed in that window, across every code mailed
+}
+
+/// How many failed LOGINs an account name, and an address, may have before more are refused
+pub struct LoginThrottle{
+    pub window:         u64, // seconds a failure counts
+    pub max_per_name:   u32,
+    pub max_per_peer:   u32,
+}
+
+/// The file a message carries
+pub struct StoredAttachment{
+    pub file_name:  String,
+    pub size:       u64,
+    pub sha256:     [u8; 32],
+}
+
+/// Thin wrapper over the sqlite connection; every query the server makes lives here.
+pub struct Database{
+    conn: Connection,
+}
+
+impl Database{
+    pub fn open(path: &str) -> Result<Database> {
+        let conn = Connection::open(path).map_err(|err| {
+            error!("couldn't open database {}: {}", path, err);
+        })?;
+        conn.execute(SCHEMA).map_err(|err| {
+            error!("couldn't apply schema to {}: {}", path, err);
+        })?;
+        let db = Database { conn };
+        // existing members start out having read everything, rather than finding the whole history unread
+        db.add_column("room_membership", "last_read",
+            "ALTER TABLE room_membership ADD COLUMN last_read INTEGER NOT NULL DEFAULT 0;
+             UPDATE room_membership SET last_read = (SELECT COALESCE(MAX(message_id), 0) FROM chat_history);")?;
+        db.add_column("room_membership", "room_role",
+            "ALTER TABLE room_membership ADD COLUMN room_role VARCHAR NOT NULL DEFAULT 'member'")?;
+        db.add_column("room", "topic", "ALTER TABLE room ADD COLUMN topic TEXT NOT NULL DEFAULT ''")?;
+        db.add_column("room", "description", "ALTER TABLE room ADD COLUMN description TEXT NOT NULL DEFAULT ''")?;
+        db.add_column("password_reset", "sent", "ALTER TABLE password_reset ADD COLUMN sent INTEGER NOT NULL DEFAULT 0")?;
+        db.add_lobby_members()?;
+        db.check_search_index()?;
+        Ok(db)
+    }
+
+    // CREATE TABLE IF NOT EXISTS leaves an older table as it was; run `alter` to bring it up to date if
+    // it's missing `column`
+    fn add_column(&self, table: &str, column: &str, alter: &str) -> Result<()> {
+        let mut statement = self.prepare("SELECT 1 FROM pragma_table_info(?) WHERE name = ?")?;
+        bind(&mut statement, 1, table)?;
+        bind(&mut statement, 2, column)?;
+        if next_row(&mut statement)?.is_some() {
+            return Ok(());
+        }
+        info!("adding {}.{}", table, column);
+        self.conn.execute(alter).map_err(|err| {
+            error!("couldn't add {}.{}: {}", table, column, err);
+        })
+    }
+
+    // users join the lobby as their account is made; accounts from before that was so join it here, once,
+    // having read everything in it
+    fn add_lobby_members(&self) -> Result<()> {
+        let mut statement = self.prepare(
+            "INSERT OR IGNORE INTO room_membership (user_id, room_id, last_read, room_role)
+             SELECT user_id, ?, (SELECT COALESCE(MAX(message_id), 0) FROM chat_history), ? FROM user")?;
+        bind(&mut statement, 1, LOBBY_ROOM_ID as i64)?;
+        bind(&mut statement, 2, ROOM_MEMBER)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    // every message has a row in the search index's docsize table once it's been indexed; history
+    // from before the index existed hasn't been
+    fn check_search_index(&self) -> Result<()> {
+        let mut statement = self.prepare(
+            "SELECT (SELECT COUNT(*) FROM chat_search_docsize) = (SELECT COUNT(*) FROM chat_history)")?;
+        let in_step = match next_row(&mut statement)? {
+            Some(row) => read::<i64>(row, 0)? != 0,
+            None => false,
+        };
+        if !in_step {
+            info!("rebuilding the chat search index");
+            self.conn.execute("INSERT INTO chat_search (chat_search) VALUES ('rebuild')").map_err(|err| {
+                error!("couldn't rebuild the chat search index: {}", err);
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Find the account for `username`, creating a guest account the first time a name is seen.
+    /// Returns the (row id, uuid) pair.
+    pub fn user_for_name(&self, username: &str) -> Result<(i64, Uuid)> {
+        let mut statement = self.prepare("SELECT user_id, uuid FROM user WHERE username = ?")?;
+        bind(&mut statement, 1, username)?;
+        if let Some(row) = next_row(&mut statement)? {
+            let user_id = read::<i64>(row, 0)?;
+            let uuid = parse_uuid(&read::<String>(row, 1)?)?;
+            return Ok((user_id, uuid));
+        }
+
+        // everyone starts out in the lobby
+        let uuid = Uuid::new_v4();
+        self.transaction(|db| {
+            let mut statement = db.prepare(
+                "INSERT INTO user (uuid, username, email, email_verified, role) VALUES (?, ?, '', 0, ?)
+                 RETURNING user_id")?;
+            bind(&mut statement, 1, uuid.to_string().as_str())?;
+            bind(&mut statement, 2, username)?;
+            bind(&mut statement, 3, ROLE_USER)?;
+            let row = next_row(&mut statement)?.ok_or_else(|| {
+                error!("inserting user {} returned no row", username);
+            })?;
+            let user_id = read::<i64>(row, 0)?;
+            drop(statement);
+            db.add_member(user_id, LOBBY_ROOM_ID, ROOM_MEMBER)?;
+            Ok((user_id, uuid))
+        })
+    }
+
+    /// The account called `username`, if there is one; unlike user_for_name, this never creates one
+    pub fn account(&self, username: &str) -> Result<Option<Account>> {
+        let mut statement = self.prepare(
+            "SELECT user_id, uuid, email, email_verified, password_hash,
+                    EXISTS (SELECT 1 FROM user_key WHERE user_key.user_id = user.user_id)
+             FROM user WHERE username = ?")?;
+        bind(&mut statement, 1, username)?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(Some(Account {
+                user_id: read::<i64>(row, 0)?,
+                uuid: parse_uuid(&read::<String>(row, 1)?)?,
+                email: read::<String>(row, 2)?,
+                email_verified: read::<i64>(row, 3)? != 0,
+                password_hash: read::<Option<String>>(row, 4)?,
+                has_keys: read::<i64>(row, 5)? != 0,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Store `user_id`'s new password hash, as made by password::hash
+    pub fn set_password(&self, user_id: i64, password_hash: &str) -> Result<()> {
+        let mut statement = self.prepare("UPDATE user SET password_hash = ? WHERE user_id = ?")?;
+        bind(&mut statement, 1, password_hash)?;
+        bind(&mut statement, 2, user_id)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// The hash of `user_id`'s password; None if they haven't set one
+    pub fn password_hash(&self, user_id: i64) -> Result<Option<String>> {
+        let mut statement = self.prepare("SELECT password_hash FROM user WHERE user_id = ?")?;
+        bind(&mut statement, 1, user_id)?;
+        match next_row(&mut statement)? {
+            Some(row) => read::<Option<String>>(row, 0),
+            None => Ok(None),
+        }
+    }
+
+    pub fn user_role(&self, user_id: i64) -> Result<String> {
+        let mut statement = self.prepare("SELECT role FROM user WHERE user_id = ?")?;
+        bind(&mut statement, 1, user_id)?;
+        let row = next_row(&mut statement)?.ok_or_else(|| {
+            error!("no user with id {}", user_id);
+        })?;
+        read::<String>(row, 0)
+    }
+
+    /// Give `username` a new role. Returns false if there's no such user.
+    pub fn set_role(&self, username: &str, role: &str) -> Result<bool> {
+        let mut statement = self.prepare("UPDATE user SET role = ? WHERE username = ? RETURNING user_id")?;
+        bind(&mut statement, 1, role)?;
+        bind(&mut statement, 2, username)?;
+        Ok(next_row(&mut statement)?.is_some())
+    }
+
+    /// Rooms the user belongs to, the lobby among them
+    pub fn rooms_for_user(&self, user_id: i64) -> Result<Vec<u64>> {
+        let mut statement = self.prepare(
+            "SELECT room_id FROM room_membership WHERE user_id = ? ORDER BY room_id")?;
+        bind(&mut statement, 1, user_id)?;
+        let mut rooms = Vec::new();
+        while let Some(row) = next_row(&mut statement)? {
+            rooms.push(read::<i64>(row, 0)? as u64);
+        }
+        Ok(rooms)
+    }
+
+    // make `user_id` a member of `room_id`, if they aren't already. A newcomer has nothing to catch up on.
+    fn add_member(&self, user_id: i64, room_id: u64, room_role: &str) -> Result<()> {
+        let mut statement = self.prepare(
+            "INSERT OR IGNORE INTO room_membership (user_id, room_id, last_read, room_role)
+             VALUES (?, ?, (SELECT COALESCE(MAX(message_id), 0) FROM chat_history), ?)")?;
+        bind(&mut statement, 1, user_id)?;
+        bind(&mut statement, 2, room_id as i64)?;
+        bind(&mut statement, 3, room_role)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// Make a room called `room_name`, with `owner_id` as its owner and first member. Returns its ID, or
+    /// why not.
+    pub fn create_room(&self, owner_id: i64, room_name: &str, is_public: bool)
+        -> Result<std::result::Result<u64, &'static str>> {
+
+        self.transaction(|db| {
+            if db.room_by_name(room_name)?.is_some() {
+                return Ok(Err("there's already a room called that"));
+            }
+            let mut statement = db.prepare("INSERT INTO room (room_name, is_public) VALUES (?, ?) RETURNING room_id")?;
+            bind(&mut statement, 1, room_name)?;
+            bind(&mut statement, 2, i64::from(is_public))?;
+            let row = next_row(&mut statement)?.ok_or_else(|| {
+                error!("inserting room {} returned no row", room_name);
+            })?;
+            let room_id = read::<i64>(row, 0)? as u64;
+            db.add_member(owner_id, room_id, ROOM_OWNER)?;
+            Ok(Ok(room_id))
+        })
+    }
+
+    /// Make `user_id` a member of the room called `room_name`, using up any invitation they had to it.
+    /// Returns its ID, or why not; a private room they weren't invited to looks like no room at all.
+    pub fn join_room(&self, user_id: i64, room_name: &str) -> Result<std::result::Result<u64, &'static str>> {
+        self.transaction(|db| {
+            let Some((room_id, is_public)) = db.room_by_name(room_name)? else {
+                return Ok(Err("there's no such room"));
+            };
+            if db.room_role(user_id, room_id)?.is_some() {
+                return Ok(Err("you're already in it"));
+            }
+            let mut statement = db.prepare("DELETE FROM room_invite WHERE room_id = ? AND user_id = ? RETURNING 1")?;
+            bind(&mut statement, 1, room_id as i64)?;
+            bind(&mut statement, 2, user_id)?;
+            let invited = next_row(&mut statement)?.is_some();
+            drop(statement);
+            if !is_public && !invited {
+                return Ok(Err("there's no such room"));
+            }
+            db.add_member(user_id, room_id, ROOM_MEMBER)?;
+            Ok(Ok(room_id))
+        })
+    }
+
+    // the ID of the room called `room_name`, whatever its case, and whether it's public
+    fn room_by_name(&self, room_name: &str) -> Result<Option<(u64, bool)>> {
+        let mut statement = self.prepare("SELECT room_id, is_public FROM room WHERE room_name = ? COLLATE NOCASE")?;
+        bind(&mut statement, 1, room_name)?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(Some((read::<i64>(row, 0)? as u64, read::<i64>(row, 1)? != 0))),
+            None => Ok(None),
+        }
+    }
+
+    /// `user_id`'s RoomRole in `room_id`; None if they aren't a member
+    pub fn room_role(&self, user_id: i64, room_id: u64) -> Result<Option<u8>> {
+        let mut statement = self.prepare("SELECT room_role FROM room_membership WHERE user_id = ? AND room_id = ?")?;
+        bind(&mut statement, 1, user_id)?;
+        bind(&mut statement, 2, room_id as i64)?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(Some(room_role(&read::<String>(row, 0)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether `user_id` may moderate `room_id`: they own it or moderate it, or they're a moderator of the
+    /// whole server
+    pub fn can_moderate(&self, user_id: i64, room_id: u64) -> Result<bool> {
+        if self.room_role(user_id, room_id)?.is_some_and(|role| role != RoomRole::MEMBER) {
+            return Ok(true);
+        }
+        Ok(is_moderator(&self.user_role(user_id)?))
+    }
+
+    /// Give `username` a new RoomRole in `room_id`. Returns their UUID, or why not.
+    pub fn set_room_role(&self, room_id: u64, username: &str, new_role: u8)
+        -> Result<std::result::Result<Uuid, &'static str>> {
+
+        let mut statement = self.prepare(
+            "SELECT m.user_id, u.uuid, m.room_role FROM room_membership m JOIN user u ON u.user_id = m.user_id
+             WHERE m.room_id = ? AND u.username = ?")?;
+        bind(&mut statement, 1, room_id as i64)?;
+        bind(&mut statement, 2, username)?;
+        let Some(row) = next_row(&mut statement)? else {
+            return Ok(Err("they aren't in the room"));
+        };
+        let (user_id, uuid) = (read::<i64>(row, 0)?, parse_uuid(&read::<String>(row, 1)?)?);
+        if read::<String>(row, 2)? == ROOM_OWNER {
+            return Ok(Err("the owner's role can't be changed"));
+        }
+        drop(statement);
+        let mut statement = self.prepare("UPDATE room_membership SET room_role = ? WHERE user_id = ? AND room_id = ?")?;
+        bind(&mut statement, 1, room_role_name(new_role))?;
+        bind(&mut statement, 2, user_id)?;
+        bind(&mut statement, 3, room_id as i64)?;
+        next_row(&mut statement)?;
+        Ok(Ok(uuid))
+    }
+
+    /// A ROOMINFO for `room_id`, if there's such a room
+    pub fn room_info(&self, room_id: u64) -> Result<Option<Frame>> {
+        let mut statement = self.prepare("SELECT room_name, is_public, topic, description FROM room WHERE room_id = ?")?;
+        bind(&mut statement, 1, room_id as i64)?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(Some(Frame::RoomInfo {
+                room_id,
+                room_name: read::<String>(row, 0)?,
+                is_public: read::<i64>(row, 1)? != 0,
+                topic: read::<String>(row, 2)?,
+                description: read::<String>(row, 3)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_topic(&self, room_id: u64, topic: &str, description: &str) -> Result<()> {
+        let mut statement = self.prepare("UPDATE room SET topic = ?, description = ? WHERE room_id = ?")?;
+        bind(&mut statement, 1, topic)?;
+        bind(&mut statement, 2, description)?;
+        bind(&mut statement, 3, room_id as i64)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// Let `username` join `room_id` on `invited_by`'s say-so, replacing any invitation they had to it
+    /// already. Returns their UUID, or why not.
+    pub fn invite(&self, room_id: u64, invited_by: i64, username: &str) -> Result<std::result::Result<Uuid, &'static str>> {
+        let mut statement = self.prepare("SELECT user_id, uuid FROM user WHERE username = ?")?;
+        bind(&mut statement, 1, username)?;
+        let Some(row) = next_row(&mut statement)? else {
+            return Ok(Err("there's no such user"));
+        };
+        let (user_id, uuid) = (read::<i64>(row, 0)?, parse_uuid(&read::<String>(row, 1)?)?);
+        drop(statement);
+        if self.room_role(user_id, room_id)?.is_some() {
+            return Ok(Err("they're already in it"));
+        }
+        let mut statement = self.prepare(
+            "INSERT OR REPLACE INTO room_invite (room_id, user_id, invited_by) VALUES (?, ?, ?)")?;
+        bind(&mut statement, 1, room_id as i64)?;
+        bind(&mut statement, 2, user_id)?;
+        bind(&mut statement, 3, invited_by)?;
+        next_row(&mut statement)?;
+        Ok(Ok(uuid))
+    }
+
+    /// An INVITE for each room `user_id` has been invited to and hasn't joined yet, oldest first
+    pub fn invites(&self, user_id: i64) -> Result<Vec<Frame>> {
+        let mut statement = self.prepare(
+            "SELECT i.room_id, r.room_name, u.username FROM room_invite i
+                JOIN room r ON r.room_id = i.room_id
+                JOIN user u ON u.user_id = i.invited_by
+             WHERE i.user_id = ? ORDER BY i.created, i.room_id")?;
+        bind(&mut statement, 1, user_id)?;
+        let mut invites = Vec::new();
+        while let Some(row) = next_row(&mut statement)? {
+            invites.push(Frame::Invite {
+                room_id: read::<i64>(row, 0)? as u64,
+                room_name: read::<String>(row, 1)?,
+                username: read::<String>(row, 2)?,
+            });
+        }
+        Ok(invites)
+    }
+
+    /// Move `user_id`'s read marker in `room_id` up to `message_id`. Returns false if it didn't move:
+    /// it was already there or further on, they aren't a member, or there's no such message yet.
+    pub fn mark_read(&self, user_id: i64, room_id: u64, message_id: u64) -> Result<bool> {
+        let mut statement = self.prepare(
+            "UPDATE room_membership SET last_read = ?
+             WHERE user_id = ? AND room_id = ? AND last_read < ?
+                AND ? <= (SELECT COALESCE(MAX(message_id), 0) FROM chat_history)
+             RETURNING 1")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        bind(&mut statement, 2, user_id)?;
+        bind(&mut statement, 3, room_id as i64)?;
+        bind(&mut statement, 4, message_id as i64)?;
+        bind(&mut statement, 5, message_id as i64)?;
+        Ok(next_row(&mut statement)?.is_some())
+    }
+
+    /// An UNREAD for each of `user_id`'s rooms: where they left off, and how much others have said since
+    pub fn unread(&self, user_id: i64) -> Result<Vec<Frame>> {
+        let mut statement = self.prepare(
+            "SELECT m.room_id, r.room_name, m.last_read,
+                (SELECT COUNT(*) FROM chat_history h
+                    LEFT JOIN message_tombstone t ON t.message_id = h.message_id
+                    LEFT JOIN message_thread th ON th.message_id = h.message_id
+                 WHERE h.room_id = m.room_id AND h.message_id > m.last_read AND h.sender_id != m.user_id
+                    AND t.message_id IS NULL AND th.parent_id IS NULL)
+             FROM room_membership m JOIN room r ON r.room_id = m.room_id
+             WHERE m.user_id = ? ORDER BY m.room_id")?;
+        bind(&mut statement, 1, user_id)?;
+        let mut unread = Vec::new();
+        while let Some(row) = next_row(&mut statement)? {
+            unread.push(Frame::Unread {
+                room_id: read::<i64>(row, 0)? as u64,
+                room_name: read::<String>(row, 1)?,
+                last_read: read::<i64>(row, 2)? as u64,
+                unread_count: read::<i64>(row, 3)? as u32,
+            });
+        }
+        Ok(unread)
+    }
+
+    /// UUIDs of everyone in `room_id`
+    pub fn room_members(&self, room_id: u64) -> Result<Vec<Uuid>> {
+        let mut statement = self.prepare(
+            "SELECT u.uuid FROM room_membership m JOIN user u ON u.user_id = m.user_id
+             WHERE m.room_id = ?")?;
+        bind(&mut statement, 1, room_id as i64)?;
+        let mut members = Vec::new();
+        while let Some(row) = next_row(&mut statement)? {
+            members.push(parse_uuid(&read::<String>(row, 0)?)?);
+        }
+        Ok(members)
+    }
+
+    /// Work out which members of `room_id` `message_text` mentions and record them against the message,
+    /// replacing whoever it mentioned before. Returns their UUIDs.
+    pub fn set_mentions(&self, message_id: u64, room_id: u64, message_text: &str) -> Result<Vec<Uuid>> {
+        let mut members = Vec::new();
+        if message_text.contains('@') {
+            let mut statement = self.prepare(
+                "SELECT u.user_id, u.uuid, u.username FROM room_membership m JOIN user u ON u.user_id = m.user_id
+                 WHERE m.room_id = ?")?;
+            bind(&mut statement, 1, room_id as i64)?;
+            while let Some(row) = next_row(&mut statement)? {
+                members.push((read::<i64>(row, 0)?, read::<String>(row, 1)?, read::<String>(row, 2)?));
+            }
+        }
+        let names: Vec<&str> = members.iter().map(|(_, _, username)| username.as_str()).collect();
+        let mentioned = mentioned_names(message_text, &names);
+        let mentioned = members.iter()
+            .filter(|(_, _, username)| mentioned.contains(&username.as_str()))
+            .take(MAX_MENTIONS);
+
+        self.transaction(|db| {
+            let mut statement = db.prepare("DELETE FROM mention WHERE message_id = ?")?;
+            bind(&mut statement, 1, message_id as i64)?;
+            next_row(&mut statement)?;
+            let mut uuids = Vec::new();
+            for (user_id, uuid, _) in mentioned {
+                let mut statement = db.prepare("INSERT INTO mention (message_id, user_id) VALUES (?, ?)")?;
+                bind(&mut statement, 1, message_id as i64)?;
+                bind(&mut statement, 2, *user_id)?;
+                next_row(&mut statement)?;
+                uuids.push(parse_uuid(uuid)?);
+            }
+            Ok(uuids)
+        })
+    }
+
+    /// UUIDs of the users a message mentions
+    pub fn mentions(&self, message_id: u64) -> Result<Vec<Uuid>> {
+        let mut statement = self.prepare(
+            "SELECT u.uuid FROM mention m JOIN user u ON u.user_id = m.user_id WHERE m.message_id = ?")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        let mut uuids = Vec::new();
+        while let Some(row) = next_row(&mut statement)? {
+            uuids.push(parse_uuid(&read::<String>(row, 0)?)?);
+        }
+        Ok(uuids)
+    }
+
+    /// Hold `message_id` for each of `user_ids` until they next join. Holding it twice is harmless.
+    pub fn queue_delivery(&self, message_id: u64, user_ids: &[Uuid]) -> Result<()> {
+        for uuid in user_ids {
+            let mut statement = self.prepare(
+                "INSERT OR IGNORE INTO delivery_queue (user_id, message_id)
+                 SELECT user_id, ? FROM user WHERE uuid = ?")?;
+            bind(&mut statement, 1, message_id as i64)?;
+            bind(&mut statement, 2, uuid.to_string().as_str())?;
+            next_row(&mut statement)?;
+        }
+        Ok(())
+    }
+
+    /// Everything held for `user_id`, oldest first, emptying their queue. Messages that have been
+    /// deleted since, or are in rooms they've left, are dropped.
+    pub fn take_queued(&self, user_id: i64) -> Result<Vec<SearchHit>> {
+        self.transaction(|db| {
+            let mut statement = db.prepare(&format!(
+                "{} JOIN delivery_queue q ON q.message_id = h.message_id
+                 WHERE q.user_id = ?
+                    AND h.room_id IN (SELECT room_id FROM room_membership WHERE user_id = q.user_id)
+                    AND NOT EXISTS (SELECT 1 FROM message_tombstone t WHERE t.message_id = h.message_id)
+                 ORDER BY h.message_id", ENTRY_QUERY))?;
+            bind(&mut statement, 1, user_id)?;
+            let mut queued = Vec::new();
+            while let Some(row) = next_row(&mut statement)? {
+                queued.push(SearchHit { room_id: read::<i64>(row, 9)? as u64, entry: read_entry(row)? });
+            }
+            let mut statement = db.prepare("DELETE FROM delivery_queue WHERE user_id = ?")?;
+            bind(&mut statement, 1, user_id)?;
+            next_row(&mut statement)?;
+            Ok(queued)
+        })
+    }
+
+    /// Start a session for `user_id` that lasts `lifetime` seconds unless it's resumed, clearing out any
+    /// that have expired. Returns the new token, which is never stored, and when it expires.
+    pub fn create_session(&self, user_id: i64, lifetime: u64) -> Result<(String, u64)> {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let token: String = secret.iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.transaction(|db| {
+            let mut statement = db.prepare("DELETE FROM session WHERE expires <= CAST(strftime('%s', 'now') AS INTEGER)")?;
+            next_row(&mut statement)?;
+            let mut statement = db.prepare(
+                "INSERT INTO session (token_hash, user_id, expires)
+                 VALUES (?, ?, CAST(strftime('%s', 'now') AS INTEGER) + ?)
+                 RETURNING expires")?;
+            bind(&mut statement, 1, &token_hash(&token)[..])?;
+            bind(&mut statement, 2, user_id)?;
+            bind(&mut statement, 3, lifetime as i64)?;
+            let expires = match next_row(&mut statement)? {
+                Some(row) => read::<i64>(row, 0)? as u64,
+                None => return Err(()),
+            };
+            Ok((token, expires))
+        })
+    }
+
+    /// The account behind `token`, if it's a session that hasn't expired, pushing its expiry back to
+    /// `lifetime` seconds from now. Returns the account's (row id, uuid, username) and the new expiry.
+    pub fn resume_session(&self, token: &str, lifetime: u64) -> Result<Option<(i64, Uuid, String, u64)>> {
+        let mut statement = self.prepare(
+            "UPDATE session SET expires = CAST(strftime('%s', 'now') AS INTEGER) + ?
+             WHERE token_hash = ? AND expires > CAST(strftime('%s', 'now') AS INTEGER)
+             RETURNING user_id, expires")?;
+        bind(&mut statement, 1, lifetime as i64)?;
+        bind(&mut statement, 2, &token_hash(token)[..])?;
+        let (user_id, expires) = match next_row(&mut statement)? {
+            Some(row) => (read::<i64>(row, 0)?, read::<i64>(row, 1)? as u64),
+            None => return Ok(None),
+        };
+        let mut statement = self.prepare("SELECT uuid, username FROM user WHERE user_id = ?")?;
+        bind(&mut statement, 1, user_id)?;
+        match next_row(&mut statement)? {
+            Some(row) => {
+                let uuid = parse_uuid(&read::<String>(row, 0)?)?;
+                Ok(Some((user_id, uuid, read::<String>(row, 1)?, expires)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Revoke a session token; it can't be resumed after this
+    pub fn end_session(&self, token: &str) -> Result<()> {
+        let mut statement = self.prepare("DELETE FROM session WHERE token_hash = ?")?;
+        bind(&mut statement, 1, &token_hash(token)[..])?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// Record `email` as `user_id`'s address, unverified, and start verifying it, replacing any code
+    /// sent before. Returns the code to mail, or why we won't.
+    pub fn start_verification(&self, user_id: i64, email: &str, lifetime: u64)
+        -> Result<std::result::Result<String, &'static str>> {
+
+        self.transaction(|db| {
+            let mut statement = db.prepare(
+                "SELECT 1 FROM user WHERE email = ? COLLATE NOCASE AND email_verified = 1 AND user_id != ?")?;
+            bind(&mut statement, 1, email)?;
+            bind(&mut statement, 2, user_id)?;
+            if next_row(&mut statement)?.is_some() {
+                return Ok(Err("that address belongs to another account"));
+            }
+            let mut statement = db.prepare("UPDATE user SET email = ?, email_verified = 0 WHERE user_id = ?")?;
+            bind(&mut statement, 1, email)?;
+            bind(&mut statement, 2, user_id)?;
+            next_row(&mut statement)?;
+            db.store_code(CodeKind::Verification, user_id, lifetime).map(Ok)
+        })
+    }
+
+    /// Check `code` against the one mailed to `user_id`, marking their address verified if it's right.
+    /// Returns the address, or why not.
+    pub fn verify_email(&self, user_id: i64, code: &str, max_attempts: u32)
+        -> Result<std::result::Result<String, &'static str>> {
+
+        self.transaction(|db| {
+            match db.use_code(CodeKind::Verification, user_id, code, max_attempts)? {
+                CodeCheck::Right => (),
+                CodeCheck::Missing => return Ok(Err("there's no address waiting to be verified")),
+                CodeCheck::Expired => return Ok(Err("that code has expired; register again for a new one")),
+                CodeCheck::Wrong => return Ok(Err("that isn't the code")),
+                CodeCheck::UsedUp => return Ok(Err(
+                    "that isn't the code, and that was the last try; register again for a new one")),
+            }
+            let mut statement = db.prepare("UPDATE user SET email_verified = 1 WHERE user_id = ? RETURNING email")?;
+            bind(&mut statement, 1, user_id)?;
+            match next_row(&mut statement)? {
+                Some(row) => Ok(Ok(read::<String>(row, 0)?)),
+                None => Ok(Err("there's no such account")),
+            }
+        })
+    }
+
+    /// A new code for resetting `user_id`'s password, or None while they can't have one: the last was
+    /// mailed less than `throttle.cooldown` seconds ago, or too many wrong codes have been tried lately.
+    /// A code that's still valid is replaced but keeps the tries already used against it.
+    pub fn start_reset(&self, user_id: i64, lifetime: u64, throttle: &ResetThrottle) -> Result<Option<String>> {
+        self.transaction(|db| {
+            if db.reset_guesses(user_id, throttle.window)? >= throttle.max_guesses {
+                return Ok(None);
+            }
+            let mut statement = db.prepare(
+                "SELECT expires > CAST(strftime('%s', 'now') AS INTEGER),
+                        sent + ? > CAST(strftime('%s', 'now') AS INTEGER)
+                 FROM password_reset WHERE user_id = ?")?;
+            bind(&mut statement, 1, throttle.cooldown as i64)?;
+            bind(&mut statement, 2, user_id)?;
+            let valid = match next_row(&mut statement)? {
+                Some(row) if read::<i64>(row, 1)? != 0 => return Ok(None),
+                Some(row) => read::<i64>(row, 0)? != 0,
+                None => false,
+            };
+            let code = match valid {
+                true => {
+                    let code = new_code();
+                    let mut statement = db.prepare(
+                        "UPDATE password_reset SET code_hash = ?, expires = CAST(strftime('%s', 'now') AS INTEGER) + ?
+                         WHERE user_id = ?")?;
+                    bind(&mut statement, 1, &token_hash(&code)[..])?;
+                    bind(&mut statement, 2, lifetime as i64)?;
+                    bind(&mut statement, 3, user_id)?;
+                    next_row(&mut statement)?;
+                    code
+                }
+                false => db.store_code(CodeKind::Reset, user_id, lifetime)?,
+            };
+            let mut statement = db.prepare(
+                "UPDATE password_reset SET sent = CAST(strftime('%s', 'now') AS INTEGER) WHERE user_id = ?")?;
+            bind(&mut statement, 1, user_id)?;
+            next_row(&mut statement)?;
+            Ok(Some(code))
+        })
+    }
+
+    /// Check `code` against the reset code mailed to `user_id`, using it up if it's right. Returns why
+    /// not if it isn't.
+    pub fn check_reset(&self, user_id: i64, code: &str, max_attempts: u32, throttle: &ResetThrottle)
+        -> Result<std::result::Result<(), &'static str>> {
+
+        self.transaction(|db| {
+            if db.reset_guesses(user_id, throttle.window)? >= throttle.max_guesses {
+                return Ok(Err("too many wrong codes have been tried; wait a while and ask for another"));
+            }
+            let check = db.use_code(CodeKind::Reset, user_id, code, max_attempts)?;
+            if matches!(check, CodeCheck::Wrong | CodeCheck::UsedUp) {
+                let mut statement = db.prepare(
+                    "INSERT INTO reset_guess (user_id, guessed) VALUES (?, CAST(strftime('%s', 'now') AS INTEGER))")?;
+                bind(&mut statement, 1, user_id)?;
+                next_row(&mut statement)?;
+                let mut statement = db.prepare(
+                    "DELETE FROM reset_guess WHERE user_id = ? AND guessed <= CAST(strftime('%s', 'now') AS INTEGER) - ?")?;
+                bind(&mut statement, 1, user_id)?;
+                bind(&mut statement, 2, throttle.window as i64)?;
+                next_row(&mut statement)?;
+            }
+            match check {
+                CodeCheck::Right => Ok(Ok(())),
+                // same answer as a wrong code, so it doesn't say whether a reset was asked for
+                CodeCheck::Missing | CodeCheck::Wrong => Ok(Err("that isn't the code")),
+                CodeCheck::Expired => Ok(Err("that code has expired; ask for another")),
+                CodeCheck::UsedUp => Ok(Err("that isn't the code, and that was the last try; ask for another")),
+            }
+        })
+    }
+
+    /// Whether LOGIN as `username`, or from `peer`, has failed too often lately to be tried again yet
+    pub fn login_throttled(&self, username: &str, peer: &str, throttle: &LoginThrottle) -> Result<bool> {
+        let mut statement = self.prepare(
+            "SELECT (SELECT COUNT(*) FROM login_failure WHERE username = ? AND failed > CAST(strftime('%s', 'now') AS INTEGER) - ?),
+                    (SELECT COUNT(*) FROM login_failure WHERE peer = ? AND failed > CAST(strftime('%s', 'now') AS INTEGER) - ?)")?;
+        bind(&mut statement, 1, username)?;
+        bind(&mut statement, 2, throttle.window as i64)?;
+        bind(&mut statement, 3, peer)?;
+        bind(&mut statement, 4, throttle.window as i64)?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(read::<i64>(row, 0)? >= i64::from(throttle.max_per_name)
+                || read::<i64>(row, 1)? >= i64::from(throttle.max_per_peer)),
+            None => Ok(false),
+        }
+    }
+
+    /// Count a failed LOGIN as `username` from `peer`, forgetting any older than `window` seconds
+    pub fn login_failed(&self, username: &str, peer: &str, window: u64) -> Result<()> {
+        self.transaction(|db| {
+            let mut statement = db.prepare("DELETE FROM login_failure WHERE failed <= CAST(strftime('%s', 'now') AS INTEGER) - ?")?;
+            bind(&mut statement, 1, window as i64)?;
+            next_row(&mut statement)?;
+            let mut statement = db.prepare(
+                "INSERT INTO login_failure (username, peer, failed) VALUES (?, ?, CAST(strftime('%s', 'now') AS INTEGER))")?;
+            bind(&mut statement, 1, username)?;
+            bind(&mut statement, 2, peer)?;
+            next_row(&mut statement)?;
+            Ok(())
+        })
+    }
+
+    /// Forget the failed LOGINs as `username`, once someone has got its password right
+    pub fn login_succeeded(&self, username: &str) -> Result<()> {
+        let mut statement = self.prepare("DELETE FROM login_failure WHERE username = ?")?;
+        bind(&mut statement, 1, username)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    // how many wrong reset codes have been tried for `user_id` in the last `window` seconds
+    fn reset_guesses(&self, user_id: i64, window: u64) -> Result<u32> {
+        let mut statement = self.prepare(
+            "SELECT COUNT(*) FROM reset_guess WHERE user_id = ? AND guessed > CAST(strftime('%s', 'now') AS INTEGER) - ?")?;
+        bind(&mut statement, 1, user_id)?;
+        bind(&mut statement, 2, window as i64)?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(read::<i64>(row, 0)? as u32),
+            None => Ok(0),
+        }
+    }
+
+    /// Give `user_id` the new `password_hash` once check_reset has passed, and revoke every session they have
+    pub fn reset_password(&self, user_id: i64, password_hash: &str) -> Result<()> {
+        self.transaction(|db| {
+            db.set_password(user_id, password_hash)?;
+            let mut statement = db.prepare("DELETE FROM session WHERE user_id = ?")?;
+            bind(&mut statement, 1, user_id)?;
+            next_row(&mut statement)?;
+            Ok(())
+        })
+    }
+
+    /// Let `user_id` log in with `public_key`. Adding a key they already have changes nothing; returns
+    /// why not if it's someone else's.
+    pub fn add_key(&self, user_id: i64, public_key: &[u8]) -> Result<std::result::Result<(), &'static str>> {
+        let mut statement = self.prepare(
+            "INSERT INTO user_key (public_key, user_id) VALUES (?, ?)
+             ON CONFLICT (public_key) DO UPDATE SET user_id = user_id
+             RETURNING user_id")?;
+        bind(&mut statement, 1, public_key)?;
+        bind(&mut statement, 2, user_id)?;
+        match next_row(&mut statement)? {
+            Some(row) if read::<i64>(row, 0)? == user_id => Ok(Ok(())),
+            _ => Ok(Err("that key belongs to another account")),
+        }
+    }
+
+    /// Stop `user_id` logging in with `public_key`; false if it wasn't one of theirs
+    pub fn remove_key(&self, user_id: i64, public_key: &[u8]) -> Result<bool> {
+        let mut statement = self.prepare("DELETE FROM user_key WHERE public_key = ? AND user_id = ? RETURNING 1")?;
+        bind(&mut statement, 1, public_key)?;
+        bind(&mut statement, 2, user_id)?;
+        Ok(next_row(&mut statement)?.is_some())
+    }
+
+    /// Whether `public_key` is one `user_id` may log in with
+    pub fn has_key(&self, user_id: i64, public_key: &[u8]) -> Result<bool> {
+        let mut statement = self.prepare("SELECT 1 FROM user_key WHERE public_key = ? AND user_id = ?")?;
+        bind(&mut statement, 1, public_key)?;
+        bind(&mut statement, 2, user_id)?;
+        Ok(next_row(&mut statement)?.is_some())
+    }
+
+    /// Publish `public_key` as `user_id`'s identity key for direct messages, in place of any before it
+    pub fn set_identity_key(&self, user_id: i64, public_key: &[u8]) -> Result<()> {
+        let mut statement = self.prepare("INSERT OR REPLACE INTO identity_key (user_id, public_key) VALUES (?, ?)")?;
+        bind(&mut statement, 1, user_id)?;
+        bind(&mut statement, 2, public_key)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// `username`'s uuid and identity key, if they've published one; None if there's no such user
+    pub fn identity_key(&self, username: &str) -> Result<Option<(Uuid, Option<[u8; PUBLIC_KEY_LEN]>)>> {
+        let mut statement = self.prepare(
+            "SELECT u.uuid, k.public_key FROM user u LEFT JOIN identity_key k ON k.user_id = u.user_id
+             WHERE u.username = ?")?;
+        bind(&mut statement, 1, username)?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(Some((parse_uuid(&read::<String>(row, 0)?)?, read_key(row, 1)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// The row id and identity key of the user with `uuid`; None if there's no such user
+    pub fn key_holder(&self, uuid: &Uuid) -> Result<Option<(i64, Option<[u8; PUBLIC_KEY_LEN]>)>> {
+        let mut statement = self.prepare(
+            "SELECT u.user_id, k.public_key FROM user u LEFT JOIN identity_key k ON k.user_id = u.user_id
+             WHERE u.uuid = ?")?;
+        bind(&mut statement, 1, uuid.to_string().as_str())?;
+        match next_row(&mut statement)? {
+            Some(row) => Ok(Some((read::<i64>(row, 0)?, read_key(row, 1)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Keep a direct message, encrypted between `keys` (the sender's, then the recipient's), until it's
+    /// delivered. Returns the (message_id, timestamp) the database gave it.
+    pub fn store_direct(
+        &self,
+        sender_id: i64,
+        recipient_id: i64,
+        keys: (&[u8], &[u8]),
+        nonce: &[u8],
+        ciphertext: &[u8]) -> Result<(u64, u64)> {
+
+        let mut statement = self.prepare(
+            "INSERT INTO direct_message (sender_id, recipient_id, sender_key, recipient_key, nonce, ciphertext)
+             VALUES (?, ?, ?, ?, ?, ?)
+             RETURNING message_id, CAST(strftime('%s', message_time) AS INTEGER)")?;
+        bind(&mut statement, 1, sender_id)?;
+        bind(&mut statement, 2, recipient_id)?;
+        bind(&mut statement, 3, keys.0)?;
+        bind(&mut statement, 4, keys.1)?;
+        bind(&mut statement, 5, nonce)?;
+        bind(&mut statement, 6, ciphertext)?;
+        let row = next_row(&mut statement)?.ok_or_else(|| {
+            error!("inserting direct message returned no row");
+        })?;
+        Ok((read::<i64>(row, 0)? as u64, read::<i64>(row, 1)? as u64))
+    }
+
+    /// Note that a direct message has reached its recipient
+    pub fn mark_delivered(&self, message_id: u64) -> Result<()> {
+        let mut statement = self.prepare("UPDATE direct_message SET delivered = 1 WHERE message_id = ?")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// The direct messages `user_id` hasn't been sent yet, oldest first, as DirectMsg frames; they count
+    /// as delivered from now on
+    pub fn take_direct(&self, user_id: i64) -> Result<Vec<Frame>> {
+        self.transaction(|db| {
+            let mut statement = db.prepare(
+                "SELECT d.message_id, CAST(strftime('%s', d.message_time) AS INTEGER), s.uuid, r.uuid,
+                        d.sender_key, d.recipient_key, d.nonce, d.ciphertext, s.username
+                 FROM direct_message d
+                    JOIN user s ON s.user_id = d.sender_id
+                    JOIN user r ON r.user_id = d.recipient_id
+                 WHERE d.recipient_id = ? AND d.delivered = 0
+                 ORDER BY d.message_id")?;
+            bind(&mut statement, 1, user_id)?;
+            let mut pending = Vec::new();
+            while let Some(row) = next_row(&mut statement)? {
+                let (Some(sender_key), Some(recipient_key)) = (read_key(row, 4)?, read_key(row, 5)?) else {
+                    continue;
+                };
+                pending.push(Frame::DirectMsg {
+                    message_id: read::<i64>(row, 0)? as u64,
+                    timestamp: read::<i64>(row, 1)? as u64,
+                    sender_id: parse_uuid(&read::<String>(row, 2)?)?,
+                    username: read::<String>(row, 8)?,
+                    recipient_id: parse_uuid(&read::<String>(row, 3)?)?,
+                    sender_key,
+                    recipient_key,
+                    nonce: read::<Vec<u8>>(row, 6)?.try_into().map_err(|_| error!("database holds a malformed nonce"))?,
+                    ciphertext: read::<Vec<u8>>(row, 7)?,
+                });
+            }
+            let mut statement = db.prepare("UPDATE direct_message SET delivered = 1 WHERE recipient_id = ? AND delivered = 0")?;
+            bind(&mut statement, 1, user_id)?;
+            next_row(&mut statement)?;
+            Ok(pending)
+        })
+    }
+
+    // a fresh six-digit code of this kind for `user_id`, stored hashed in place of any before it
+    fn store_code(&self, kind: CodeKind, user_id: i64, lifetime: u64) -> Result<String> {
+        let code = new_code();
+        let mut statement = self.prepare(&format!(
+            "INSERT OR REPLACE INTO {} (user_id, code_hash, expires)
+             VALUES (?, ?, CAST(strftime('%s', 'now') AS INTEGER) + ?)", kind.table()))?;
+        bind(&mut statement, 1, user_id)?;
+        bind(&mut statement, 2, &token_hash(&code)[..])?;
+        bind(&mut statement, 3, lifetime as i64)?;
+        next_row(&mut statement)?;
+        Ok(code)
+    }
+
+    // check `code` against `user_id`'s code of this kind. A code that's right, expired or out of tries is
+    // thrown away; a wrong one counts against it.
+    fn use_code(&self, kind: CodeKind, user_id: i64, code: &str, max_attempts: u32) -> Result<CodeCheck> {
+        let mut statement = self.prepare(&format!(
+            "SELECT code_hash, expires <= CAST(strftime('%s', 'now') AS INTEGER), attempts FROM {}
+             WHERE user_id = ?", kind.table()))?;
+        bind(&mut statement, 1, user_id)?;
+        let (code_hash, expired, attempts) = match next_row(&mut statement)? {
+            Some(row) => (read::<Vec<u8>>(row, 0)?, read::<i64>(row, 1)? != 0, read::<i64>(row, 2)?),
+            None => return Ok(CodeCheck::Missing),
+        };
+        let check = if expired {
+            CodeCheck::Expired
+        } else if code_hash != token_hash(code) {
+            match attempts + 1 >= i64::from(max_attempts) {
+                true => CodeCheck::UsedUp,
+                false => {
+                    let mut statement = self.prepare(&format!(
+                        "UPDATE {} SET attempts = attempts + 1 WHERE user_id = ?", kind.table()))?;
+                    bind(&mut statement, 1, user_id)?;
+                    next_row(&mut statement)?;
+                    return Ok(CodeCheck::Wrong);
+                }
+            }
+        } else {
+            CodeCheck::Right
+        };
+        let mut statement = self.prepare(&format!("DELETE FROM {} WHERE user_id = ?", kind.table()))?;
+        bind(&mut statement, 1, user_id)?;
+        next_row(&mut statement)?;
+        Ok(check)
+    }
+
+    /// Persist a chat message, returning the (message_id, timestamp) the database gave it. A
+    /// `parent_id` files it as a reply in that message's thread.
+    pub fn store_message(
+        &self,
+        sender_id: i64,
+        room_id: u64,
+        parent_id: Option<u64>,
+        message_text: &str) -> Result<(u64, u64)> {
+
+        self.transaction(|db| db.insert_message(sender_id, room_id, parent_id, message_text))
+    }
+
+    // store_message's work, for callers already in a transaction
+    fn insert_message(
+        &self,
+        sender_id: i64,
+        room_id: u64,
+        parent_id: Option<u64>,
+        message_text: &str) -> Result<(u64, u64)> {
+
+        let mut statement = self.prepare(
+            "INSERT INTO chat_history (message_text, sender_id, room_id) VALUES (?, ?, ?)
+             RETURNING message_id, CAST(strftime('%s', message_time) AS INTEGER)")?;
+        bind(&mut statement, 1, message_text)?;
+        bind(&mut statement, 2, sender_id)?;
+        bind(&mut statement, 3, room_id as i64)?;
+        let row = next_row(&mut statement)?.ok_or_else(|| {
+            error!("inserting chat message returned no row");
+        })?;
+        let stored = (read::<i64>(row, 0)? as u64, read::<i64>(row, 1)? as u64);
+        if let Some(parent_id) = parent_id {
+            let mut statement = self.prepare("INSERT INTO message_thread (message_id, parent_id) VALUES (?, ?)")?;
+            bind(&mut statement, 1, stored.0 as i64)?;
+            bind(&mut statement, 2, parent_id as i64)?;
+            next_row(&mut statement)?;
+        }
+        Ok(stored)
+    }
+
+    /// Store a file's message: its text is the file name
+    pub fn store_attachment(
+        &self,
+        sender_id: i64,
+        room_id: u64,
+        attachment: &StoredAttachment) -> Result<(u64, u64)> {
+
+        self.transaction(|db| {
+            let stored = db.insert_message(sender_id, room_id, None, &attachment.file_name)?;
+            let mut statement = db.prepare(
+                "INSERT INTO attachment (message_id, file_name, size, sha256) VALUES (?, ?, ?, ?)")?;
+            bind(&mut statement, 1, stored.0 as i64)?;
+            bind(&mut statement, 2, attachment.file_name.as_str())?;
+            bind(&mut statement, 3, attachment.size as i64)?;
+            bind(&mut statement, 4, &attachment.sha256[..])?;
+            next_row(&mut statement)?;
+            Ok(stored)
+        })
+    }
+
+    pub fn attachment(&self, message_id: u64) -> Result<Option<StoredAttachment>> {
+        let mut statement = self.prepare("SELECT file_name, size, sha256 FROM attachment WHERE message_id = ?")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        let Some(row) = next_row(&mut statement)? else {
+            return Ok(None);
+        };
+        let sha256 = read::<Vec<u8>>(row, 2)?.try_into().map_err(|_| {
+            error!("attachment {} has a malformed sha256", message_id);
+        })?;
+        Ok(Some(StoredAttachment {
+            file_name: read::<String>(row, 0)?,
+            size: read::<i64>(row, 1)? as u64,
+            sha256,
+        }))
+    }
+
+    pub fn stored_message(&self, message_id: u64) -> Result<Option<StoredMessage>> {
+        let mut statement = self.prepare(
+            "SELECT h.sender_id, h.room_id, t.message_id IS NOT NULL, th.parent_id
+             FROM chat_history h
+                 LEFT JOIN message_tombstone t ON t.message_id = h.message_id
+                 LEFT JOIN message_thread th ON th.message_id = h.message_id
+             WHERE h.message_id = ?")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        let Some(row) = next_row(&mut statement)? else {
+            return Ok(None);
+        };
+        Ok(Some(StoredMessage {
+            sender_id: read::<i64>(row, 0)?,
+            room_id: read::<i64>(row, 1)? as u64,
+            deleted: read::<i64>(row, 2)? != 0,
+            parent_id: read::<Option<i64>>(row, 3)?.map(|id| id as u64),
+        }))
+    }
+
+    /// Replace a message's text, keeping what it said before as a revision
+    pub fn edit_message(&self, message_id: u64, editor_id: i64, message_text: &str) -> Result<()> {
+        self.transaction(|db| {
+            db.save_revision(message_id, editor_id)?;
+            let mut statement = db.prepare("UPDATE chat_history SET message_text = ? WHERE message_id = ?")?;
+            bind(&mut statement, 1, message_text)?;
+            bind(&mut statement, 2, message_id as i64)?;
+            next_row(&mut statement)?;
+            Ok(())
+        })
+    }
+
+    /// Clear a message's text and leave a tombstone in its place. The text is kept as a revision.
+    pub fn delete_message(&self, message_id: u64, deleted_by: i64) -> Result<()> {
+        self.transaction(|db| {
+            db.save_revision(message_id, deleted_by)?;
+            let mut statement = db.prepare("UPDATE chat_history SET message_text = NULL WHERE message_id = ?")?;
+            bind(&mut statement, 1, message_id as i64)?;
+            next_row(&mut statement)?;
+            let mut statement = db.prepare(
+                "INSERT INTO message_tombstone (message_id, deleted_by) VALUES (?, ?)")?;
+            bind(&mut statement, 1, message_id as i64)?;
+            bind(&mut statement, 2, deleted_by)?;
+            next_row(&mut statement)?;
+            Ok(())
+        })
+    }
+
+    /// Record `user_id`'s reaction. Reacting twice the same way is harmless.
+    pub fn add_reaction(&self, message_id: u64, user_id: i64, reaction: &str) -> Result<()> {
+        let mut statement = self.prepare(
+            "INSERT OR IGNORE INTO reaction (message_id, user_id, reaction) VALUES (?, ?, ?)")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        bind(&mut statement, 2, user_id)?;
+        bind(&mut statement, 3, reaction)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    pub fn remove_reaction(&self, message_id: u64, user_id: i64, reaction: &str) -> Result<()> {
+        let mut statement = self.prepare(
+            "DELETE FROM reaction WHERE message_id = ? AND user_id = ? AND reaction = ?")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        bind(&mut statement, 2, user_id)?;
+        bind(&mut statement, 3, reaction)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// Every reaction on a message with how many users chose it, in the order they first appeared
+    pub fn reactions(&self, message_id: u64) -> Result<Vec<ReactionCount>> {
+        let mut statement = self.prepare(
+            "SELECT reaction, COUNT(*) FROM reaction WHERE message_id = ?
+             GROUP BY reaction ORDER BY MIN(created), reaction")?;
+        bind(&mut statement, 1, message_id as i64)?;
+        let mut reactions = Vec::new();
+        while let Some(row) = next_row(&mut statement)? {
+            reactions.push(ReactionCount {
+                reaction: read::<String>(row, 0)?,
+                count: read::<i64>(row, 1)? as u32,
+            });
+        }
+        Ok(reactions)
+    }
+
+    fn save_revision(&self, message_id: u64, editor_id: i64) -> Result<()> {
+        let mut statement = self.prepare(
+            "INSERT INTO message_revision (message_id, editor_id, message_text)
+             SELECT message_id, ?, message_text FROM chat_history WHERE message_id = ?")?;
+        bind(&mut statement, 1, editor_id)?;
+        bind(&mut statement, 2, message_id as i64)?;
+        next_row(&mut statement)?;
+        Ok(())
+    }
+
+    /// Up to `limit` messages from `room_id` older than `before_id` (or the newest, if that's 0),
+    /// oldest first. Replies stay in their threads.
+    pub fn history(&self, room_id: u64, before_id: u64, limit: u16) -> Result<Vec<HistoryEntry>> {
+        let before_id = if before_id == 0 { i64::MAX } else { before_id as i64 };
+        let mut statement = self.prepare(&format!(
+            "{} WHERE h.room_id = ? AND h.message_id < ? AND th.parent_id IS NULL
+             ORDER BY h.message_id DESC LIMIT ?", ENTRY_QUERY))?;
+        bind(&mut statement, 1, room_id as i64)?;
+        bind(&mut statement, 2, before_id)?;
+        bind(&mut statement, 3, limit as i64)?;
+        read_entries(&mut statement)
+    }
+
+    /// The newest `limit` replies to `parent_id`, oldest first
+    pub fn thread(&self, parent_id: u64, limit: u16) -> Result<Vec<HistoryEntry>> {
+        let mut statement = self.prepare(&format!(
+            "{} WHERE th.parent_id = ? ORDER BY h.message_id DESC LIMIT ?", ENTRY_QUERY))?;
+        bind(&mut statement, 1, parent_id as i64)?;
+        bind(&mut statement, 2, limit as i64)?;
+        read_entries(&mut statement)
+    }
+
+    /// Messages `user_id` can see that contain every word of `query`, newest first. Empty filters
+    /// (0, or "" for the username) don't filter anything.
+    pub fn search(&self, user_id: i64, filter: &SearchFilter, query: &str, limit: u16) -> Result<Vec<SearchHit>> {
+        // each word is quoted, so nothing the user types is taken as FTS5 query syntax
+        let terms: Vec<String> = query.split_whitespace()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let before_id = if filter.before_id == 0 { i64::MAX } else { filter.before_id as i64 };
+        let until = if filter.until == 0 { i64::MAX } else { filter.until as i64 };
+        let mut statement = self.prepare(&format!(
+            "{} JOIN chat_search ON chat_search.rowid = h.message_id
+             WHERE chat_search MATCH ?
+                AND h.room_id IN (SELECT room_id FROM room_membership WHERE user_id = ?)
+                AND (? = 0 OR h.room_id = ?)
+                AND (? = '' OR u.username = ?)
+                AND CAST(strftime('%s', h.message_time) AS INTEGER) BETWEEN ? AND ? - 1
+                AND h.message_id < ?
+             ORDER BY h.message_id DESC LIMIT ?", ENTRY_QUERY))?;
+        bind(&mut statement, 1, terms.join(" ").as_str())?;
+        bind(&mut statement, 2, user_id)?;
+        bind(&mut statement, 3, filter.room_id as i64)?;
+        bind(&mut statement, 4, filter.room_id as i64)?;
+        bind(&mut statement, 5, filter.username.as_str())?;
+        bind(&mut statement, 6, filter.username.as_str())?;
+        bind(&mut statement, 7, filter.since as i64)?;
+        bind(&mut statement, 8, until)?;
+        bind(&mut statement, 9, before_id)?;
+        bind(&mut statement, 10, limit as i64)?;
+        let mut hits = Vec::new();
+        while let Some(row) = next_row(&mut statement)? {
+            hits.push(SearchHit { room_id: read::<i64>(row, 9)? as u64, entry: read_entry(row)? });
+        }
+        Ok(hits)
+    }
+
+    // run `body` in a transaction, rolled back if it fails
+    fn transaction<T>(&self, body: impl FnOnce(&Self) -> Result<T>) -> Result<T> {
+        self.conn.execute("BEGIN").map_err(|err| {
+            error!("couldn't begin transaction: {}", err);
+        })?;
+        let result = body(self);
+        let end = if result.is_ok() { "COMMIT" } else { "ROLLBACK" };
+        self.conn.execute(end).map_err(|err| {
+            error!("couldn't {} transaction: {}", end, err);
+        })?;
+        result
+    }
+
+    fn prepare(&self, query: &str) -> Result<Statement<'_>> {
+        self.conn.prepare(query).map_err(|err| {
+            error!("couldn't prepare query {:?}: {}", query, err);
+        })
+    }
+}
+
+// rows from ENTRY_QUERY, newest first, turned around
+fn read_entries(statement: &mut Statement<'_>) -> Result<Vec<HistoryEntry>> {
+    let mut entries = Vec::new();
+    while let Some(row) = next_row(statement)? {
+        entries.push(read_entry(row)?);
+    }
+    entries.reverse();
+    Ok(entries)
+}
+
+fn read_entry(row: &Statement<'_>) -> Result<HistoryEntry> {
+    // a deleted message has a revision too, but it only needs to say it's deleted
+    let flags = if read::<i64>(row, 6)? != 0 {
+        MessageFlags::DELETED
+    } else if read::<i64>(row, 5)? != 0 {
+        MessageFlags::EDITED
+    } else {
+        0
+    };
+    Ok(HistoryEntry {
+        message_id: read::<i64>(row, 0)? as u64,
+        timestamp: read::<i64>(row, 1)? as u64,
+        flags,
+        parent_id: read::<Option<i64>>(row, 7)?.unwrap_or(0) as u64,
+        reply_count: read::<i64>(row, 8)? as u32,
+        sender_id: parse_uuid(&read::<String>(row, 2)?)?,
+        username: read::<String>(row, 3)?,
+        message_text: read::<Option<String>>(row, 4)?.unwrap_or_default(),
+    })
+}
+
+fn bind<T: sqlite::BindableWithIndex>(statement: &mut Statement<'_>, index: usize, value: T) -> Result<()> {
+    statement.bind((index, value)).map_err(|err| {
+        error!("couldn't bind query parameter {}: {}", index, err);
+    })
+}
+
+// step the statement; Some(statement) while there are rows left to read
+fn next_row<'a, 'l>(statement: &'a mut Statement<'l>) -> Result<Option<&'a mut Statement<'l>>> {
+    match statement.next() {
+        Ok(State::Row) => Ok(Some(statement)),
+        Ok(State::Done) => Ok(None),
+        Err(err) => {
+            error!("query failed: {}", err);
+            Err(())
+        }
+    }
+}
+
+fn read<T: sqlite::ReadableWithIndex>(statement: &Statement<'_>, index: usize) -> Result<T> {
+    statement.read::<T, _>(index).map_err(|err| {
+        error!("couldn't read column {}: {}", index, err);
+    })
+}
+
+// a six-digit code to mail out
+fn new_code() -> String {
+    format!("{:06}", OsRng.gen_range(0..1_000_000))
+}
+
+// what the database keeps in place of a session token or a mailed code
+fn token_hash(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+// a 32-byte key column; None if it's NULL
+fn read_key(row: &Statement<'_>, index: usize) -> Result<Option<[u8; PUBLIC_KEY_LEN]>> {
+    match read::<Option<Vec<u8>>>(row, index)? {
+        Some(key) => key.try_into().map(Some).map_err(|key: Vec<u8>| {
+            error!("database holds a {}-byte key in column {}", key.len(), index);
+        }),
+        None => Ok(None),
+    }
+}
+
+fn parse_uuid(text: &str) -> Result<Uuid> {
+    Uuid::parse_str(text).map_err(|err| {
+        error!("database holds a malformed uuid {:?}: {}", text, err);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THROTTLE: ResetThrottle = ResetThrottle { cooldown: 0, window: 60 * 60, max_guesses: 10 };
+    const HOUR: u64 = 60 * 60;
+
+    fn open() -> (Database, i64) {
+        let db = Database::open(":memory:").unwrap();
+        let (user_id, _) = db.user_for_name("rex").unwrap();
+        (db, user_id)
+    }
+
+    // a six-digit code that isn't `code`
+    fn wrong(code: &str) -> &'static str {
+        if code == "000000" { "000001" } else { "000000" }
+    }
+
+    fn count(db: &Database, query: &str) -> i64 {
+        let mut statement = db.prepare(query).unwrap();
+        read::<i64>(next_row(&mut statement).unwrap().unwrap(), 0).unwrap()
+    }
+
+    #[test]
+    fn a_reset_code_works_once() {
+        let (db, user_id) = open();
+        let code = db.start_reset(user_id, HOUR, &THROTTLE).unwrap().unwrap();
+        assert_eq!(code.len(), 6);
+        assert!(code.bytes().all(|byte| byte.is_ascii_digit()));
+        assert_eq!(db.check_reset(user_id, &code, 5, &THROTTLE).unwrap(), Ok(()));
+        assert_eq!(db.check_reset(user_id, &code, 5, &THROTTLE).unwrap(), Err("that isn't the code"));
+    }
+
+    #[test]
+    fn an_expired_reset_code_fails() {
+        let (db, user_id) = open();
+        let code = db.start_reset(user_id, 0, &THROTTLE).unwrap().unwrap();
+        assert_eq!(db.check_reset(user_id, &code, 5, &THROTTLE).unwrap(), Err("that code has expired; ask for another"));
+        // and it's gone once it's been found out
+        assert_eq!(db.check_reset(user_id, &code, 5, &THROTTLE).unwrap(), Err("that isn't the code"));
+    }
+
+    #[test]
+    fn wrong_codes_use_a_reset_code_up() {
+        let (db, user_id) = open();
+        let code = db.start_reset(user_id, HOUR, &THROTTLE).unwrap().unwrap();
+        for _ in 0..4 {
+            assert_eq!(db.check_reset(user_id, wrong(&code), 5, &THROTTLE).unwrap(), Err("that isn't the code"));
+        }
+        assert_eq!(
+            db.check_reset(user_id, wrong(&code), 5, &THROTTLE).unwrap(),
+            Err("that isn't the code, and that was the last try; ask for another"));
+        assert_eq!(db.check_reset(user_id, &code, 5, &THROTTLE).unwrap(), Err("that isn't the code"));
+    }
+
+    #[test]
+    fn a_new_reset_code_replaces_the_old_but_keeps_its_tries() {
+        let (db, user_id) = open();
+        let old = db.start_reset(user_id, HOUR, &THROTTLE).unwrap().unwrap();
+        for _ in 0..2 {
+            assert_eq!(db.check_reset(user_id, wrong(&old), 3, &THROTTLE).unwrap(), Err("that isn't the code"));
+        }
+        let new = loop {
+            let new = db.start_reset(user_id, HOUR, &THROTTLE).unwrap().unwrap();
+            if new != old {
+                break new;
+            }
+        };
+        assert_eq!(
+            db.check_reset(user_id, &old, 3, &THROTTLE).unwrap(),
+            Err("that isn't the code, and that was the last try; ask for another"));
+        assert_eq!(db.check_reset(user_id, &new, 3, &THROTTLE).unwrap(), Err("that isn't the code"));
+    }
+
+    #[test]
+    fn reset_codes_wait_out_the_cooldown() {
+        let (db, user_id) = open();
+        let throttle = ResetThrottle { cooldown: 60, ..THROTTLE };
+        assert!(db.start_reset(user_id, HOUR, &throttle).unwrap().is_some());
+        assert_eq!(db.start_reset(user_id, HOUR, &throttle).unwrap(), None);
+        assert!(db.start_reset(user_id, HOUR, &THROTTLE).unwrap().is_some());
+    }
+
+    #[test]
+    fn max_guesses_locks_resets_out() {
+        let (db, user_id) = open();
+        let throttle = ResetThrottle { max_guesses: 3, ..THROTTLE };
+        let code = db.start_reset(user_id, HOUR, &throttle).unwrap().unwrap();
+        for _ in 0..3 {
+            assert_eq!(db.check_reset(user_id, wrong(&code), 5, &throttle).unwrap(), Err("that isn't the code"));
+        }
+        let locked = Err("too many wrong codes have been tried; wait a while and ask for another");
+        assert_eq!(db.check_reset(user_id, &code, 5, &throttle).unwrap(), locked);
+        assert_eq!(db.start_reset(user_id, HOUR, &throttle).unwrap(), None);
+        // guesses older than the window don't count
+        db.conn.execute("UPDATE reset_guess SET guessed = guessed - 2 * 60 * 60").unwrap();
+        assert_eq!(db.check_reset(user_id, &code, 5, &throttle).unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn failed_logins_are_limited_per_name_and_per_address() {
+        let (db, _) = open();
+        let throttle = LoginThrottle { window: HOUR, max_per_name: 2, max_per_peer: 3 };
+        assert!(!db.login_throttled("rex", "10.0.0.1", &throttle).unwrap());
+        db.login_failed("rex", "10.0.0.1", HOUR).unwrap();
+        db.login_failed("REX", "10.0.0.2", HOUR).unwrap();
+        assert!(db.login_throttled("rex", "10.0.0.3", &throttle).unwrap());
+        // names nobody has count the same way
+        db.login_failed("nobody", "10.0.0.1", HOUR).unwrap();
+        db.login_failed("nobody else", "10.0.0.1", HOUR).unwrap();
+        assert!(db.login_throttled("ann", "10.0.0.1", &throttle).unwrap());
+        assert!(!db.login_throttled("ann", "10.0.0.2", &throttle).unwrap());
+        db.login_succeeded("rex").unwrap();
+        assert!(!db.login_throttled("rex", "10.0.0.3", &throttle).unwrap());
+        // and failures older than the window are forgotten
+        db.conn.execute("UPDATE login_failure SET failed = failed - 2 * 60 * 60").unwrap();
+        assert!(!db.login_throttled("ann", "10.0.0.1", &throttle).unwrap());
+    }
+
+    #[test]
+    fn a_reset_revokes_every_session() {
+        let (db, user_id) = open();
+        let (other_id, _) = db.user_for_name("ann").unwrap();
+        let (first, _) = db.create_session(user_id, HOUR).unwrap();
+        let (second, _) = db.create_session(user_id, HOUR).unwrap();
+        let (others, _) = db.create_session(other_id, HOUR).unwrap();
+        db.reset_password(user_id, "new hash").unwrap();
+        assert_eq!(db.resume_session(&first, HOUR).unwrap(), None);
+        assert_eq!(db.resume_session(&second, HOUR).unwrap(), None);
+        assert!(db.resume_session(&others, HOUR).unwrap().is_some());
+        assert_eq!(db.password_hash(user_id).unwrap().as_deref(), Some("new hash"));
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM session"), 1);
+    }
+
+    #[test]
+    fn users_start_out_in_the_lobby() {
+        let (db, user_id) = open();
+        assert_eq!(db.rooms_for_user(user_id).unwrap(), [LOBBY_ROOM_ID]);
+        // looking doesn't add them back; only the migration for older accounts does
+        db.conn.execute("DELETE FROM room_membership").unwrap();
+        assert_eq!(db.rooms_for_user(user_id).unwrap(), []);
+        db.add_lobby_members().unwrap();
+        assert_eq!(db.rooms_for_user(user_id).unwrap(), [LOBBY_ROOM_ID]);
+        db.add_lobby_members().unwrap();
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM room_membership"), 1);
+    }
+
+    #[test]
+    fn a_session_token_resumes() {
+        let (db, user_id) = open();
+        let (_, uuid) = db.user_for_name("rex").unwrap();
+        let (token, _) = db.create_session(user_id, 60).unwrap();
+        assert_eq!(token.len(), 64);
+        assert!(token.bytes().all(|byte| byte.is_ascii_hexdigit()));
+        let (resumed_id, resumed_uuid, username, expires) = db.resume_session(&token, HOUR).unwrap().unwrap();
+        assert_eq!((resumed_id, resumed_uuid, username.as_str()), (user_id, uuid, "rex"));
+        // resuming pushes the expiry back by the new lifetime
+        assert_eq!(count(&db, "SELECT expires FROM session") as u64, expires);
+        assert!(expires >= count(&db, "SELECT CAST(strftime('%s', 'now') AS INTEGER)") as u64 + HOUR);
+        let last = if token.ends_with('0') { '1' } else { '0' };
+        assert_eq!(db.resume_session(&format!("{}{}", &token[..63], last), HOUR).unwrap(), None);
+    }
+
+    #[test]
+    fn only_a_session_token_hash_is_stored() {
+        let (db, user_id) = open();
+        let (token, _) = db.create_session(user_id, HOUR).unwrap();
+        let mut statement = db.prepare("SELECT token_hash FROM session").unwrap();
+        let stored = read::<Vec<u8>>(next_row(&mut statement).unwrap().unwrap(), 0).unwrap();
+        assert_eq!(stored, token_hash(&token));
+        assert_ne!(stored, token.as_bytes());
+        let mut statement = db.prepare("SELECT COUNT(*) FROM session WHERE token_hash = ? OR token_hash = ?").unwrap();
+        bind(&mut statement, 1, token.as_str()).unwrap();
+        bind(&mut statement, 2, token.as_bytes()).unwrap();
+        assert_eq!(read::<i64>(next_row(&mut statement).unwrap().unwrap(), 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn expired_sessions_are_purged() {
+        let (db, user_id) = open();
+        let (expired, _) = db.create_session(user_id, 0).unwrap();
+        assert_eq!(db.resume_session(&expired, HOUR).unwrap(), None);
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM session"), 1);
+        let (fresh, _) = db.create_session(user_id, HOUR).unwrap();
+        assert_eq!(count(&db, "SELECT COUNT(*) FROM session"), 1);
+        assert!(db.resume_session(&fresh, HOUR).unwrap().is_some());
+    }
+
+    #[test]
+    fn an_ended_session_is_revoked() {
+        let (db, user_id) = open();
+        let (left, _) = db.create_session(user_id, HOUR).unwrap();
+        let (kept, _) = db.create_session(user_id, HOUR).unwrap();
+        db.end_session(&left).unwrap();
+        assert_eq!(db.resume_session(&left, HOUR).unwrap(), None);
+        assert!(db.resume_session(&kept, HOUR).unwrap().is_some());
+    }
+}
@@ -0,0 +1,67 @@
+use common_bork::frame::{CHALLENGE_CONTEXT, NONCE_LEN, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use ed25519_dalek::{Signature, VerifyingKey};
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+/// A fresh nonce for a CHALLENGE
+pub fn new_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// Whether `public_key` is an ed25519 key at all; some 32-byte strings aren't
+pub fn is_valid(public_key: &[u8; PUBLIC_KEY_LEN]) -> bool {
+    VerifyingKey::from_bytes(public_key).is_ok()
+}
+
+/// Whether `signature` is `public_key`'s answer to the CHALLENGE that sent `nonce`
+pub fn verify(public_key: &[u8; PUBLIC_KEY_LEN], nonce: &[u8; NONCE_LEN], signature: &[u8; SIGNATURE_LEN]) -> bool {
+    let Ok(key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+    let signed = [CHALLENGE_CONTEXT, &nonce[..]].concat();
+    key.verify_strict(&signed, &Signature::from_bytes(signature)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn sign(key: &SigningKey, context: &[u8], nonce: &[u8; NONCE_LEN]) -> [u8; SIGNATURE_LEN] {
+        key.sign(&[context, &nonce[..]].concat()).to_bytes()
+    }
+
+    #[test]
+    fn a_signed_challenge_verifies() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let nonce = [1; NONCE_LEN];
+        assert!(is_valid(&public_key));
+        assert!(verify(&public_key, &nonce, &sign(&key, CHALLENGE_CONTEXT, &nonce)));
+    }
+
+    #[test]
+    fn the_wrong_nonce_key_or_context_is_refused() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let other_key = SigningKey::from_bytes(&[8; 32]);
+        let nonce = [1; NONCE_LEN];
+        let signature = sign(&key, CHALLENGE_CONTEXT, &nonce);
+
+        assert!(!verify(&public_key, &[2; NONCE_LEN], &signature));
+        assert!(!verify(&other_key.verifying_key().to_bytes(), &nonce, &signature));
+        assert!(!verify(&public_key, &nonce, &sign(&other_key, CHALLENGE_CONTEXT, &nonce)));
+        assert!(!verify(&public_key, &nonce, &sign(&key, b"some other protocol", &nonce)));
+        assert!(!verify(&public_key, &nonce, &sign(&key, b"", &nonce)));
+        let mut tampered = signature;
+        tampered[0] ^= 1;
+        assert!(!verify(&public_key, &nonce, &tampered));
+    }
+
+    #[test]
+    fn nonces_differ() {
+        assert_ne!(new_nonce(), new_nonce());
+    }
+}
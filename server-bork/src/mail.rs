@@ -0,0 +1,244 @@
+use log::{error, info};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::Result;
+use crate::config::Config;
+
+// how long an SMTP server gets to answer each command before we give up on it
+const SMTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One plain-text email
+#[derive(Clone, Debug)]
+pub struct Mail{
+    pub to:         String,
+    pub subject:    String,
+    pub body:       String,
+}
+
+/// Somewhere the server can send mail. Sending may block for a while, so callers shouldn't be holding
+/// any locks.
+pub trait Mailer: Send + Sync{
+    fn send(&self, mail: &Mail) -> Result<()>;
+}
+
+/// The mailer BORK_MAIL asks for: `smtp://host:port`, `file:<path>`, or `stdout`
+pub fn from_config(config: &Config) -> Box<dyn Mailer> {
+    let from = config.mail_from.clone();
+    match config.mail.as_str() {
+        "stdout" => Box::new(FileMailer { path: None, from }),
+        sink => {
+            if let Some(path) = sink.strip_prefix("file:") {
+                return Box::new(FileMailer { path: Some(String::from(path)), from });
+            }
+            if let Some(address) = sink.strip_prefix("smtp://") {
+                return Box::new(SmtpMailer { address: String::from(address.trim_end_matches('/')), from });
+            }
+            error!("BORK_MAIL must be smtp://host:port, file:<path> or stdout, not {:?}; using stdout", sink);
+            Box::new(FileMailer { path: None, from })
+        }
+    }
+}
+
+/// Writes each mail, headers and all, to the end of a file, or to stdout; for testing, and for servers
+/// with nobody to mail
+pub struct FileMailer{
+    path:   Option<String>, // stdout if None
+    from:   String,
+}
+
+impl Mailer for FileMailer{
+    fn send(&self, mail: &Mail) -> Result<()> {
+        let text = format!("{}\n.\n", message(&self.from, mail));
+        let written = match &self.path {
+            Some(path) => OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| file.write_all(text.as_bytes())),
+            None => io::stdout().lock().write_all(text.as_bytes()),
+        };
+        written.map_err(|err| {
+            error!("couldn't write mail for {} to {}: {}", mail.to, self.path.as_deref().unwrap_or("stdout"), err);
+        })
+    }
+}
+
+/// Hands each mail to an SMTP server: plain SMTP, no TLS or authentication, so meant for a relay on
+/// the same host or network
+pub struct SmtpMailer{
+    address:    String, // host:port
+    from:       String,
+}
+
+impl Mailer for SmtpMailer{
+    fn send(&self, mail: &Mail) -> Result<()> {
+        self.converse(mail).map_err(|err| {
+            error!("couldn't send mail for {} through {}: {}", mail.to, self.address, err);
+        })?;
+        info!("sent mail to {} through {}", mail.to, self.address);
+        Ok(())
+    }
+}
+
+impl SmtpMailer{
+    fn converse(&self, mail: &Mail) -> io::Result<()> {
+        let stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(SMTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(SMTP_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+
+        expect(&mut reader, 220)?;
+        command(&mut writer, &mut reader, "EHLO borkbork", 250)?;
+        command(&mut writer, &mut reader, &format!("MAIL FROM:<{}>", self.from), 250)?;
+        command(&mut writer, &mut reader, &format!("RCPT TO:<{}>", mail.to), 250)?;
+        command(&mut writer, &mut reader, "DATA", 354)?;
+        // a line that starts with a dot gets another, so it can't end the message early
+        let mut data = String::new();
+        for line in message(&self.from, mail).lines() {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        writer.write_all(data.as_bytes())?;
+        expect(&mut reader, 250)?;
+        command(&mut writer, &mut reader, "QUIT", 221)
+    }
+}
+
+// headers, a blank line, then the body
+fn message(from: &str, mail: &Mail) -> String {
+    format!("From: {}\nTo: {}\nSubject: {}\n\n{}", from, mail.to, mail.subject, mail.body)
+}
+
+fn command(writer: &mut &TcpStream, reader: &mut BufReader<&TcpStream>, line: &str, code: u16) -> io::Result<()> {
+    writer.write_all(format!("{}\r\n", line).as_bytes())?;
+    expect(reader, code)
+}
+
+// read one reply, which may run over several lines ("250-..." until "250 ..."), and check its code
+fn expect(reader: &mut BufReader<&TcpStream>, code: u16) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the SMTP server hung up"));
+        }
+        let got: Option<u16> = line.get(..3).and_then(|digits| digits.parse().ok());
+        if got != Some(code) {
+            return Err(io::Error::other(format!("expected {}, got {:?}", code, line.trim_end())));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use uuid::Uuid;
+
+    fn mail() -> Mail {
+        Mail {
+            to: String::from("rex@example.com"),
+            subject: String::from("Your code"),
+            body: String::from("Your code is 123456.\n.hidden\n..two dots\nBye.\n"),
+        }
+    }
+
+    // play the server side of one SMTP conversation, returning every line the client sent
+    fn smtp_server(listener: TcpListener) -> Vec<String> {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(&stream);
+        let mut writer = &stream;
+        let mut lines = Vec::new();
+        let mut in_data = false;
+        writer.write_all(b"220 stand-in ready\r\n").unwrap();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap() == 0 {
+                return lines;
+            }
+            assert!(line.ends_with("\r\n"), "{:?} doesn't end in CRLF", line);
+            let line = String::from(line.trim_end_matches("\r\n"));
+            let reply: &[u8] = match line.as_str() {
+                "." if in_data => {
+                    in_data = false;
+                    b"250 queued\r\n"
+                }
+                _ if in_data => b"",
+                "EHLO borkbork" => b"250-stand-in\r\n250 8BITMIME\r\n",
+                "DATA" => {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                }
+                "QUIT" => b"221 bye\r\n",
+                _ => b"250 ok\r\n",
+            };
+            lines.push(line);
+            writer.write_all(reply).unwrap();
+        }
+    }
+
+    #[test]
+    fn smtp_mailer_speaks_smtp_and_dot_stuffs_the_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || smtp_server(listener));
+
+        let mailer = SmtpMailer { address, from: String::from("borkbork@localhost") };
+        assert!(mailer.send(&mail()).is_ok());
+        assert_eq!(server.join().unwrap(), [
+            "EHLO borkbork",
+            "MAIL FROM:<borkbork@localhost>",
+            "RCPT TO:<rex@example.com>",
+            "DATA",
+            "From: borkbork@localhost",
+            "To: rex@example.com",
+            "Subject: Your code",
+            "",
+            "Your code is 123456.",
+            "..hidden",
+            "...two dots",
+            "Bye.",
+            ".",
+            "QUIT",
+        ]);
+    }
+
+    #[test]
+    fn smtp_mailer_gives_up_on_a_refusal() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            (&stream).write_all(b"554 go away\r\n").unwrap();
+        });
+
+        let mailer = SmtpMailer { address, from: String::from("borkbork@localhost") };
+        assert!(mailer.send(&mail()).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn file_mailer_appends_each_mail() {
+        let dir = std::env::temp_dir().join(format!("borkbork-mail-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("mail.txt");
+
+        let mailer = FileMailer { path: Some(path.to_string_lossy().into_owned()), from: String::from("borkbork@localhost") };
+        assert!(mailer.send(&mail()).is_ok());
+        assert!(mailer.send(&mail()).is_ok());
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let one = "From: borkbork@localhost\nTo: rex@example.com\nSubject: Your code\n\n\
+                   Your code is 123456.\n.hidden\n..two dots\nBye.\n\n.\n";
+        assert_eq!(written, one.repeat(2));
+    }
+}
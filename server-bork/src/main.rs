@@ -3,19 +3,60 @@
 use log::{debug, error, info, LevelFilter};
 use std::collections::BTreeMap;
 use std::io::{BufReader, Read, Write};
-use std::{result, thread};
+use std::{env, fs, process, result, thread};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::net::{TcpListener, TcpStream, Shutdown};
+use std::net::{SocketAddr, TcpListener, TcpStream, Shutdown};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use uuid::Uuid;
 
-use::common_bork::{Message, MessageType};
+use::common_bork::{ALL_ROOMS, Extension, ExtensionRegistry, Frame, FrameError, HistoryEntry, LOBBY_ROOM_ID, Message,
+    MessageFlags, NoticeSeverity, PresenceStatus, ReactionAction, RoomRole, read_frame};
+use::common_bork::extension::{Ping, Pong};
+use::common_bork::frame::{DM_NONCE_LEN, MAX_CHUNK_LEN, MAX_HISTORY_BATCH, MAX_REACTIONS, NONCE_LEN, PUBLIC_KEY_LEN,
+    validate_email, validate_room_name, validate_welcome};
+
+use crate::admin::AdminContext;
+use crate::config::Config;
+use crate::db::{Database, LoginThrottle, ResetThrottle, SearchFilter, StoredAttachment};
+use crate::mail::{Mail, Mailer};
+use crate::password::MIN_PASSWORD_LEN;
+use crate::transfer::{Uploads, blob_path};
+
+mod admin;
+mod config;
+mod db;
+mod keys;
+mod mail;
+mod password;
+mod transfer;
 
 type Result<T> = result::Result<T, ()>;
 
 const SERVER_PORT:u16=6556;
 const SERVER_ADDRESS:&str = "0.0.0.0";
 const DB_NAME:&str = "borkbork.db";
+// frames handed to the MPSC writer thread that it hasn't written yet
+static QUEUED_WRITES: AtomicUsize = AtomicUsize::new(0);
+// how long mailed codes last, and how many wrong ones it takes to use one up
+const VERIFICATION_LIFETIME: u64 = 24 * 60 * 60;
+const RESET_LIFETIME: u64 = 60 * 60;
+const MAX_CODE_ATTEMPTS: u32 = 5;
+// RESETREQUEST needs no login, so mailing codes and guessing at them are both kept slow
+const RESET_THROTTLE: ResetThrottle = ResetThrottle {
+    cooldown:    5 * 60,
+    window:      24 * 60 * 60,
+    max_guesses: 10,
+};
+// and so are password guesses, against any one name and from any one address
+const LOGIN_THROTTLE: LoginThrottle = LoginThrottle {
+    window:         15 * 60,
+    max_per_name:   10,
+    max_per_peer:   30,
+};
 const WELCOME:&str = "
         __
      __/o \\_
@@ -32,67 +73,265 @@ WELCOME TO BORK BORK, A PLACE
 ";
 
 
+/// An account that's online: one or more connections (sessions) that JOINed under its name
 #[derive(Clone)]
 struct User{
     displayname:    String,
-    online:         bool,
+    sessions:       BTreeMap<SocketAddr, u8>, // each session's own PresenceStatus
+    status_text:    String,                   // shared by every session; the last one set wins
     uuid:           Uuid,
 }
 impl User{
-    fn new(displayname: String) -> User{
+    fn new(displayname: String, uuid: Uuid) -> User{
         User{
             displayname,
-            online : true,
-            uuid : Uuid::new_v4(),
+            sessions: BTreeMap::new(),
+            status_text: String::new(),
+            uuid,
+        }
+    }
+    /// The account's availability, across its sessions: do not disturb if any session asked for it,
+    /// online if any session is, and away only once they all are
+    fn status(&self) -> u8 {
+        let statuses = || self.sessions.values();
+        if statuses().any(|&s| s == PresenceStatus::DO_NOT_DISTURB) {
+            PresenceStatus::DO_NOT_DISTURB
+        } else if statuses().any(|&s| s == PresenceStatus::ONLINE) {
+            PresenceStatus::ONLINE
+        } else {
+            PresenceStatus::AWAY
+        }
+    }
+    fn presence(&self) -> Frame {
+        Frame::Presence {
+            user_id: self.uuid,
+            status: self.status(),
+            status_text: Clone::clone(&self.status_text),
         }
     }
 }
+struct Connection{
+    stream:     Arc<TcpStream>,
+    user:       Option<Uuid>, // the account, once the client has JOINed
+    extensions: Vec<u64>,     // extended types the client advertised in CAPABILITIES
+}
 struct ServerState{
-    user_map: BTreeMap<String, User>,
+    users: BTreeMap<Uuid, User>, // everyone with at least one session
+    connections: BTreeMap<SocketAddr, Connection>,
+    motd: String,
 }
 impl ServerState{
-    fn new() -> ServerState{
+    fn new(motd: String) -> ServerState{
         ServerState{
-            user_map: BTreeMap::new(),
+            users: BTreeMap::new(),
+            connections: BTreeMap::new(),
+            motd,
         }
     }
-    fn add_user(&mut self, user: &mut User){
-        self.user_map.insert(Clone::clone(&user.displayname), Clone::clone(user));
+    /// streams for every connection that has joined as a user
+    fn joined_streams(&self) -> Vec<Arc<TcpStream>> {
+        self.connections.values()
+            .filter(|c| c.user.is_some())
+            .map(|c| c.stream.clone())
+            .collect()
+    }
+    /// streams for every session of the account `uuid`
+    fn user_streams(&self, uuid: &Uuid) -> Vec<Arc<TcpStream>> {
+        self.connections.values()
+            .filter(|c| c.user.as_ref() == Some(uuid))
+            .map(|c| c.stream.clone())
+            .collect()
+    }
+    /// the name a connection joined under, if it has
+    fn username(&self, connection: &Connection) -> Option<&str> {
+        connection.user.and_then(|uuid| self.users.get(&uuid)).map(|u| u.displayname.as_str())
     }
+    fn is_online(&self, uuid: &Uuid) -> bool {
+        self.users.contains_key(uuid)
+    }
+}
+
+/// Handles to everything the client threads share
+#[derive(Clone)]
+struct Shared{
+    message:        Sender<Message>,
+    server_state:   Arc<Mutex<ServerState>>,
+    db:             Arc<Mutex<Database>>,
+    config:         Config,
+    extensions:     Arc<ExtensionRegistry<ExtensionContext>>,
+    mailer:         Arc<dyn Mailer>,
+}
+
+/// What server-side extension handlers get to act on: the connection the extension arrived on
+struct ExtensionContext{
+    stream:     Arc<TcpStream>,
+    message:    Sender<Message>,
+}
+
+fn server_extensions() -> ExtensionRegistry<ExtensionContext> {
+    let mut registry = ExtensionRegistry::new();
+    registry.register(|ping: Ping, context: &mut ExtensionContext| {
+        let _ = send_to(&context.message, vec![context.stream.clone()], Pong { nonce: ping.nonce }.to_frame());
+    });
+    registry
+}
+
+/// Why the server is going down; passed along to clients in SERVERSHUTDOWN
+#[derive(Clone, Debug)]
+struct ShutdownRequest{
+    reason:         String,
+    restart_eta:    u32,
+}
+type ShutdownFlag = Arc<Mutex<Option<ShutdownRequest>>>;
+
+/// Ask the accept loop to stop; only the first request counts
+fn request_shutdown(shutdown: &ShutdownFlag, request: ShutdownRequest) {
+    shutdown.lock().unwrap().get_or_insert(request);
+    // the accept loop only checks the flag between connections, so give it one
+    let _ = TcpStream::connect(("127.0.0.1", SERVER_PORT));
 }
 
 fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("admin") {
+        return admin::run_cli(&args[2..], &Config::from_env());
+    }
+
     let _ = simple_logging::log_to_file("./server.log", LevelFilter::Info);
+    let config = Config::from_env();
+    let db = Arc::new(Mutex::new(Database::open(DB_NAME)?));
+    fs::create_dir_all(&config.attachment_dir).map_err(|err| {
+        error!("couldn't create attachment directory {}: {}", config.attachment_dir, err);
+    })?;
     let address = format!("{}:{}", SERVER_ADDRESS, SERVER_PORT);
     let listener = TcpListener::bind(&address).map_err(|_err| {
         error!("could not bind to address {address}");
     })?;
     info!("running on socket: {address}");
 
-    let server_state = ServerState::new();
+    let server_state = ServerState::new(load_motd(&config));
     let server_state = Arc::new(Mutex::new(server_state));
+    let shutdown: ShutdownFlag = Arc::new(Mutex::new(None));
 
     let (sender, receiver) = channel();
     let receiver = Arc::new(Mutex::new(receiver));
     thread::spawn(move || handle_mspc_thread_messages(receiver));
 
+    let admin = AdminContext {
+        config: config.clone(),
+        message: sender.clone(),
+        server_state: Arc::clone(&server_state),
+        db: Arc::clone(&db),
+        shutdown: Arc::clone(&shutdown),
+    };
+    thread::spawn(move || admin::listen(admin));
+    let shared = Shared {
+        message: sender.clone(),
+        server_state: Arc::clone(&server_state),
+        db: Arc::clone(&db),
+        config: config.clone(),
+        extensions: Arc::new(server_extensions()),
+        mailer: Arc::from(mail::from_config(&config)),
+    };
+    let signal_shutdown = Arc::clone(&shutdown);
+    let signal_config = config.clone();
+    thread::spawn(move || handle_signals(signal_shutdown, signal_config));
+
     for stream in listener.incoming() {
+        if shutdown.lock().unwrap().is_some() {
+            break;
+        }
         match stream{
             Ok(stream) => {
                 let stream = Arc::new(stream);
-                let sender = sender.clone();
-                let server_state = Arc::clone(&server_state);
-                info!("new connection, spawning thread for client {:?}", stream.peer_addr().unwrap());
-                thread::spawn(move || handle_client(stream, sender, server_state));
+                let shared = shared.clone();
+                info!("new connection, spawning thread for client {:?}", stream.peer_addr());
+                thread::spawn(move || handle_client(stream, shared));
             }
             Err(e) => {
                 error!("error spawning thread for incomming stream: {}", e);
             }
         }
     }
+
+    let request = shutdown.lock().unwrap().clone().unwrap_or(ShutdownRequest {
+        reason: config.shutdown_reason.clone(),
+        restart_eta: config.restart_eta,
+    });
+    shutdown_server(request, &sender, &server_state, &db, &config);
+    Ok(())
+}
+
+/// SIGINT / SIGTERM start a graceful shutdown; a second one while that's underway exits at once
+fn handle_signals(shutdown: ShutdownFlag, config: Config) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM]).map_err(|err| {
+        error!("couldn't install signal handlers: {}", err);
+    })?;
+    for signal in signals.forever() {
+        if shutdown.lock().unwrap().is_some() {
+            error!("got signal {} while already shutting down; exiting now", signal);
+            process::exit(1);
+        }
+        info!("got signal {}; shutting down", signal);
+        request_shutdown(&shutdown, ShutdownRequest {
+            reason: config.shutdown_reason.clone(),
+            restart_eta: config.restart_eta,
+        });
+    }
     Ok(())
 }
 
+/// Tell every client why we're going away, give the writer thread a chance to deliver that,
+/// then disconnect everyone. Whatever isn't done by the configured deadline is abandoned.
+fn shutdown_server(
+    request: ShutdownRequest,
+    message: &Sender<Message>,
+    server_state: &Arc<Mutex<ServerState>>,
+    db: &Arc<Mutex<Database>>,
+    config: &Config) {
+
+    let deadline = Duration::from_secs(config.shutdown_deadline);
+    thread::spawn(move || {
+        thread::sleep(deadline);
+        error!("graceful shutdown took longer than {:?}; exiting anyway", deadline);
+        process::exit(1);
+    });
+
+    let streams: Vec<Arc<TcpStream>> = server_state.lock().unwrap().connections.values()
+        .map(|c| c.stream.clone())
+        .collect();
+    info!("shutting down ({:?}); disconnecting {} client(s)", request, streams.len());
+    let notice = Frame::ServerShutdown { restart_eta: request.restart_eta, reason: request.reason };
+    let _ = send_to(message, streams.clone(), notice);
+    wait_for_writes(deadline);
+    for stream in streams {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    // sqlite commits each statement as it runs, so once we hold the lock nothing is half-written,
+    // and holding it until exit keeps anything new from starting
+    let _db = db.lock().unwrap();
+    let _ = fs::remove_file(&config.admin_socket);
+    info!("shutdown complete");
+}
+
+/// The welcome banner: the MOTD file if there is a usable one, the built-in banner otherwise
+fn load_motd(config: &Config) -> String {
+    match fs::read_to_string(&config.motd_file) {
+        Ok(motd) => match validate_welcome(&motd) {
+            Ok(()) => motd,
+            Err(err) => {
+                error!("MOTD file {} is unusable ({}); using the built-in banner", config.motd_file, err);
+                WELCOME.to_string()
+            }
+        },
+        Err(err) => {
+            info!("couldn't read MOTD file {} ({}); using the built-in banner", config.motd_file, err);
+            WELCOME.to_string()
+        }
+    }
+}
+
 fn handle_mspc_thread_messages(reciever: Arc<Mutex<Receiver<Message>>>) -> Result<()> {
     info!("handling incomming messages from client threads");
     loop{
@@ -103,148 +342,1336 @@ fn handle_mspc_thread_messages(reciever: Arc<Mutex<Receiver<Message>>>) -> Resul
             .map_err(|err| {
                 error!("MPSC handler couldn't receive message, got error: {}", err);
             })?;
-        match message{
-            Message::Version { author, message_type, major_rev, minor_rev, subminor_rev } => {
-                let mut message: Vec<u8> = Vec::new();
-                message.push(message_type);
-                message.extend(major_rev.to_le_bytes());
-                message.extend(minor_rev.to_le_bytes());
-                message.extend(subminor_rev.to_le_bytes());
-                author.as_ref().write_all(&message).map_err(|err| {
-                    error!("MPSC handler couldn't send version message to client, with error: {}", err);
-                })?;
-                author.as_ref().flush();
-            }
-            Message::ChatMsg { author, message_type, sender_id, message_len, message_text } => {
-                debug!("MPSC handler received ChatMsg type");
-            }
-            Message::Join { author, message_type, name_len, username } => {
-                debug!("MPSC handler received Join type");
-            }
-            Message::Leave { author, message_type } => {
-                debug!("MPSC handler received Leave type");
-            }
-            Message::Welcome { author, message_type, message_len, welcome_msg } => {
-                let mut message: Vec<u8> = Vec::new();
-                message.push(message_type);
-                message.extend(message_len.to_le_bytes());
-                message.extend(welcome_msg);
-                author.as_ref().write_all(&message).map_err(|err| {
-                    error!("MPSC couldn't send Welcome message to client, with error {}", err);
-                })?;
-                author.as_ref().flush();
-            }
-            Message::Userjoined { author, message_type, user_id, username_len, username } => {
-                let mut message: Vec<u8> = Vec::new();
-                message.push(message_type);
-                message.extend(user_id.to_bytes_le());      //uuid
-                message.extend(username_len.to_le_bytes()); //u16
-                message.extend(username);
-                author.as_ref().write_all(&message).map_err(|err| {
-                    error!("MPSC couldn't send Userjoined message to client, with error {}", err);
-                })?;
-                author.as_ref().flush();
+        let Message { author, frame } = message;
+        // a failed write means that client is gone; its own thread will clean up
+        match author.as_ref().write_all(&frame.encode()) {
+            Ok(()) => { let _ = author.as_ref().flush(); }
+            Err(err) => error!("MPSC handler couldn't send message type {} to client, with error: {}",
+                frame.message_type(), err),
+        }
+        QUEUED_WRITES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// queue `frame` for delivery to each of `streams`
+fn send_to(message: &Sender<Message>, streams: Vec<Arc<TcpStream>>, frame: Frame) -> Result<()> {
+    for author in streams {
+        QUEUED_WRITES.fetch_add(1, Ordering::SeqCst);
+        message.send(Message { author, frame: frame.clone() }).map_err(|err| {
+            QUEUED_WRITES.fetch_sub(1, Ordering::SeqCst);
+            error!("couldn't send message to MPSC sender. Err was {}", err);
+        })?;
+    }
+    Ok(())
+}
+
+/// Give the writer thread up to `timeout` to deliver everything queued so far
+fn wait_for_writes(timeout: Duration) {
+    let start = std::time::Instant::now();
+    while QUEUED_WRITES.load(Ordering::SeqCst) > 0 && start.elapsed() < timeout {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Send a NOTICE to every joined user, or only to members of `room_id`. Returns how many
+/// connections it was queued for.
+fn send_notice(
+    message: &Sender<Message>,
+    server_state: &Arc<Mutex<ServerState>>,
+    db: &Arc<Mutex<Database>>,
+    severity: u8,
+    room_id: u64,
+    notice_text: &str) -> Result<usize> {
+
+    let streams = if room_id == ALL_ROOMS {
+        server_state.lock().unwrap().joined_streams()
+    } else {
+        room_streams(server_state, db, room_id)?
+    };
+    let count = streams.len();
+    send_to(message, streams, Frame::Notice { severity, room_id, notice_text: String::from(notice_text) })?;
+    Ok(count)
+}
+
+/// streams for every joined connection whose user is a member of `room_id`
+fn room_streams(
+    server_state: &Arc<Mutex<ServerState>>,
+    db: &Arc<Mutex<Database>>,
+    room_id: u64) -> Result<Vec<Arc<TcpStream>>> {
+
+    // everyone is in the lobby; no need to ask the database
+    if room_id == LOBBY_ROOM_ID {
+        return Ok(server_state.lock().unwrap().joined_streams());
+    }
+    let members = db.lock().unwrap().room_members(room_id)?;
+    Ok(server_state.lock().unwrap().connections.values()
+        .filter(|c| c.user.as_ref().is_some_and(|uuid| members.contains(uuid)))
+        .map(|c| c.stream.clone())
+        .collect())
+}
+
+/// A HISTORYBATCH, followed by the current reactions and attachment of each message in it that has any
+fn send_history(stream: &Arc<TcpStream>, shared: &Shared, room_id: u64, entries: Vec<HistoryEntry>) -> Result<()> {
+    let Shared { message, db, .. } = shared;
+    let details = details_for(db, &entries)?;
+    send_to(message, vec![stream.clone()], Frame::HistoryBatch { room_id, entries })?;
+    for frame in details {
+        send_to(message, vec![stream.clone()], frame)?;
+    }
+    Ok(())
+}
+
+// a REACTIONS frame for every message in `entries` that has reactions, an ATTACHMENT for every one that
+// carries a file and a MENTIONS for every one that mentions anyone, leaving out deleted messages
+fn details_for(db: &Arc<Mutex<Database>>, entries: &[HistoryEntry]) -> Result<Vec<Frame>> {
+    let db = db.lock().unwrap();
+    let mut details = Vec::new();
+    for entry in entries.iter().filter(|e| e.flags & MessageFlags::DELETED == 0) {
+        if let Some(attachment) = db.attachment(entry.message_id)? {
+            details.push(attachment_frame(entry.message_id, attachment));
+        }
+        let counts = db.reactions(entry.message_id)?;
+        if !counts.is_empty() {
+            details.push(Frame::Reactions { message_id: entry.message_id, reactions: counts });
+        }
+        let user_ids = db.mentions(entry.message_id)?;
+        if !user_ids.is_empty() {
+            details.push(Frame::Mentions { message_id: entry.message_id, user_ids });
+        }
+    }
+    Ok(details)
+}
+
+fn attachment_frame(message_id: u64, attachment: StoredAttachment) -> Frame {
+    let StoredAttachment { file_name, size, sha256 } = attachment;
+    Frame::Attachment { message_id, size, sha256, file_name }
+}
+
+fn send_warning(message: &Sender<Message>, stream: &Arc<TcpStream>, notice_text: String) -> Result<()> {
+    let notice = Frame::Notice { severity: NoticeSeverity::WARNING, room_id: ALL_ROOMS, notice_text };
+    send_to(message, vec![stream.clone()], notice)
+}
+
+// COMPLETE for an upload that checked out: it becomes a message in its room, acknowledged to the uploader
+// like any other, and followed by its ATTACHMENT
+fn attach(
+    stream: &Arc<TcpStream>,
+    shared: &Shared,
+    session: &Session,
+    transfer_id: u64,
+    room_id: u64,
+    attachment: StoredAttachment) -> Result<()> {
+
+    let Shared { message, server_state, db, .. } = shared;
+    info!("{} uploaded {:?} ({} bytes) to room {}", session.username, attachment.file_name, attachment.size, room_id);
+    let streams = room_streams(server_state, db, room_id)?;
+    // hold the database while queueing, so messages go out in message_id order
+    let db = db.lock().unwrap();
+    let (message_id, timestamp) = db.store_attachment(session.user_id, room_id, &attachment)?;
+    send_to(message, vec![stream.clone()], Frame::SentAck { nonce: transfer_id, message_id, timestamp })?;
+    send_to(message, streams.clone(), Frame::ChatMsg {
+        message_id,
+        timestamp,
+        sender_id: session.uuid,
+        room_id,
+        nonce: 0,
+        parent_id: 0,
+        message_text: Clone::clone(&attachment.file_name),
+    })?;
+    send_to(message, streams, attachment_frame(message_id, attachment))
+}
+
+// REGISTER: take `email` as the user's address, unverified, and mail them a code to prove it's theirs
+fn register(stream: &Arc<TcpStream>, shared: &Shared, session: &Session, email: &str) -> Result<()> {
+    let Shared { message, db, mailer, .. } = shared;
+    if let Err(err) = validate_email(email) {
+        return send_warning(message, stream, format!("couldn't register: {}", err));
+    }
+    let outcome = db.lock().unwrap().start_verification(session.user_id, email, VERIFICATION_LIFETIME)?;
+    let code = match outcome {
+        Ok(code) => code,
+        Err(why) => {
+            info!("refused {}'s registration of {}: {}", session.username, email, why);
+            return send_warning(message, stream, format!("couldn't register: {}", why));
+        }
+    };
+    // no locks held; a slow mail server only holds up this connection
+    let mail = Mail {
+        to: String::from(email),
+        subject: String::from("Your BorkBork verification code"),
+        body: format!(
+            "Someone, hopefully you, registered this address for {} on BorkBork.\n\n\
+             Your verification code is {}. It expires in {} hours.\n",
+            session.username, code, VERIFICATION_LIFETIME / 3600),
+    };
+    match mailer.send(&mail) {
+        Ok(()) => {
+            info!("mailed {} a verification code for {}", session.username, email);
+            let notice = Frame::Notice {
+                severity: NoticeSeverity::INFO,
+                room_id: ALL_ROOMS,
+                notice_text: format!("a verification code is on its way to {}", email),
+            };
+            send_to(message, vec![stream.clone()], notice)
+        }
+        Err(()) => send_warning(message, stream, String::from("couldn't send the verification code; try again later")),
+    }
+}
+
+// VERIFY: the code mailed by REGISTER, typed back in
+fn verify(stream: &Arc<TcpStream>, shared: &Shared, session: &Session, code: &str) -> Result<()> {
+    let Shared { message, db, .. } = shared;
+    match db.lock().unwrap().verify_email(session.user_id, code.trim(), MAX_CODE_ATTEMPTS)? {
+        Ok(email) => {
+            info!("{} verified {}", session.username, email);
+            let notice = Frame::Notice {
+                severity: NoticeSeverity::INFO,
+                room_id: ALL_ROOMS,
+                notice_text: format!("{} is verified", email),
+            };
+            send_to(message, vec![stream.clone()], notice)
+        }
+        Err(why) => {
+            info!("refused {}'s verification code: {}", session.username, why);
+            send_warning(message, stream, format!("couldn't verify: {}", why))
+        }
+    }
+}
+
+// a DIRECTMSG as a client sent it, before the server fills in who it's from and when
+struct DirectMsg{
+    recipient_id:   Uuid,
+    sender_key:     [u8; PUBLIC_KEY_LEN],
+    recipient_key:  [u8; PUBLIC_KEY_LEN],
+    nonce:          [u8; DM_NONCE_LEN],
+    ciphertext:     Vec<u8>,
+}
+
+// DIRECTMSG: keep it for the recipient and pass it on to every session of theirs, and of the sender's so
+// they all show the conversation. Only sent if it was encrypted between the keys both have published now,
+// so that a client holding an old key finds out; returns why not otherwise.
+fn direct_message(shared: &Shared, session: &Session, sent: DirectMsg)
+    -> Result<std::result::Result<(), &'static str>> {
+
+    let Shared { message, server_state, db, .. } = shared;
+    let DirectMsg { recipient_id, sender_key, recipient_key, nonce, ciphertext } = sent;
+    if recipient_id == session.uuid {
+        return Ok(Err("that's you"));
+    }
+    let (message_id, timestamp) = {
+        let db = db.lock().unwrap();
+        let Some((recipient, current_key)) = db.key_holder(&recipient_id)? else {
+            return Ok(Err("there's no such user"));
+        };
+        match current_key {
+            None => return Ok(Err("they haven't published an identity key")),
+            Some(current_key) if current_key != recipient_key => return Ok(Err("their identity key has changed")),
+            Some(_) => (),
+        }
+        if db.key_holder(&session.uuid)?.and_then(|(_, key)| key) != Some(sender_key) {
+            return Ok(Err("publish your identity key first"));
+        }
+        db.store_direct(session.user_id, recipient, (&sender_key, &recipient_key), &nonce, &ciphertext)?
+    };
+    let (online, streams) = {
+        let state = server_state.lock().unwrap();
+        let streams = [state.user_streams(&recipient_id), state.user_streams(&session.uuid)].concat();
+        (state.is_online(&recipient_id), streams)
+    };
+    // otherwise it waits for their next login
+    if online {
+        db.lock().unwrap().mark_delivered(message_id)?;
+    }
+    let relayed = Frame::DirectMsg {
+        message_id,
+        timestamp,
+        sender_id: session.uuid,
+        username: Clone::clone(&session.username),
+        recipient_id,
+        sender_key,
+        recipient_key,
+        nonce,
+        ciphertext,
+    };
+    send_to(message, streams, relayed)?;
+    Ok(Ok(()))
+}
+
+// CHANGEPASSWORD: the old password, if the account has one, proves it's them
+fn change_password(
+    stream: &Arc<TcpStream>,
+    shared: &Shared,
+    session: &Session,
+    old_password: &str,
+    new_password: &str) -> Result<()> {
+
+    let Shared { message, db, .. } = shared;
+    if new_password.chars().count() < MIN_PASSWORD_LEN {
+        let why = format!("couldn't change your password: it needs at least {} characters", MIN_PASSWORD_LEN);
+        return send_warning(message, stream, why);
+    }
+    let current = db.lock().unwrap().password_hash(session.user_id)?;
+    if current.is_some_and(|hash| !password::verify(old_password, &hash)) {
+        info!("refused {}'s password change: wrong old password", session.username);
+        return send_warning(message, stream, String::from("couldn't change your password: the old one is wrong"));
+    }
+    let Ok(hash) = password::hash(new_password) else {
+        return send_warning(message, stream, String::from("couldn't change your password; try again later"));
+    };
+    db.lock().unwrap().set_password(session.user_id, &hash)?;
+    info!("{} changed their password", session.username);
+    let notice_text = String::from("your password has been changed");
+    send_to(message, vec![stream.clone()], Frame::Notice { severity: NoticeSeverity::INFO, room_id: ALL_ROOMS, notice_text })
+}
+
+// RESETREQUEST: mail a reset code to the account's verified address. The answer is the same whether or
+// not there's such an account, or an address to send to, so nobody can use it to find out.
+fn request_reset(stream: &Arc<TcpStream>, shared: &Shared, username: &str) -> Result<()> {
+    let Shared { message, db, mailer, .. } = shared;
+    let reset = {
+        let db = db.lock().unwrap();
+        match db.account(username)? {
+            Some(account) if account.email_verified => {
+                match db.start_reset(account.user_id, RESET_LIFETIME, &RESET_THROTTLE)? {
+                    Some(code) => Some((code, account.email)),
+                    None => {
+                        info!("password reset asked for {} too soon after the last; not mailing", username);
+                        None
+                    }
+                }
             }
             _ => {
-                info!("MPSC handler received unknown mesage type");
+                info!("password reset asked for {}, which has no verified address", username);
+                None
+            }
+        }
+    };
+    if let Some((code, email)) = reset {
+        let mail = Mail {
+            to: email,
+            subject: String::from("Your BorkBork password reset code"),
+            body: format!(
+                "Someone, hopefully you, asked to reset the password for {} on BorkBork.\n\n\
+                 Your reset code is {}. It expires in {} minutes. If it wasn't you, ignore this.\n",
+                username, code, RESET_LIFETIME / 60),
+        };
+        // a failure is only logged; telling the client would give away that the account exists
+        if mailer.send(&mail).is_ok() {
+            info!("mailed a password reset code for {}", username);
+        }
+    }
+    let notice = Frame::Notice {
+        severity: NoticeSeverity::INFO,
+        room_id: ALL_ROOMS,
+        notice_text: format!("if {} has a verified email address, a reset code is on its way to it", username),
+    };
+    send_to(message, vec![stream.clone()], notice)
+}
+
+// RESETPASSWORD: the right code sets the new password, and logs the account out everywhere
+fn reset_password(
+    stream: &Arc<TcpStream>,
+    shared: &Shared,
+    username: &str,
+    code: &str,
+    new_password: &str) -> Result<()> {
+
+    let Shared { message, server_state, db, .. } = shared;
+    if new_password.chars().count() < MIN_PASSWORD_LEN {
+        let why = format!("couldn't reset the password: it needs at least {} characters", MIN_PASSWORD_LEN);
+        return send_warning(message, stream, why);
+    }
+    let Some(account) = db.lock().unwrap().account(username)? else {
+        return send_warning(message, stream, String::from("couldn't reset the password: that isn't the code"));
+    };
+    // the code is checked and used up before hashing, so wrong guesses don't each cost an argon2 hash
+    if let Err(why) = db.lock().unwrap().check_reset(account.user_id, code.trim(), MAX_CODE_ATTEMPTS, &RESET_THROTTLE)? {
+        info!("refused a password reset for {}: {}", username, why);
+        return send_warning(message, stream, format!("couldn't reset the password: {}", why));
+    }
+    let Ok(hash) = password::hash(new_password) else {
+        return send_warning(message, stream, String::from("couldn't reset the password; ask for another code and try again later"));
+    };
+    db.lock().unwrap().reset_password(account.user_id, &hash)?;
+
+    // the database has revoked every session token; connections still logged in go as well, except the
+    // one that asked, which may be logged in as them and is told below instead
+    info!("{}'s password was reset; logging them out everywhere", username);
+    let streams: Vec<_> = server_state.lock().unwrap().user_streams(&account.uuid).into_iter()
+        .filter(|other| !Arc::ptr_eq(other, stream))
+        .collect();
+    let notice = Frame::Notice {
+        severity: NoticeSeverity::CRITICAL,
+        room_id: ALL_ROOMS,
+        notice_text: String::from("your password was reset; log in again with the new one"),
+    };
+    send_to(message, streams.clone(), notice)?;
+    wait_for_writes(Duration::from_secs(1));
+    for stream in &streams {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    let notice_text = String::from("the password has been reset; log in with the new one");
+    send_to(message, vec![stream.clone()], Frame::Notice { severity: NoticeSeverity::INFO, room_id: ALL_ROOMS, notice_text })
+}
+
+// FILEREQUEST: send the file as an OFFER, its CHUNKs and a COMPLETE, to anyone who can see its message
+fn send_file(stream: &Arc<TcpStream>, shared: &Shared, session: &Session, message_id: u64) -> Result<()> {
+    let Shared { message, db, config, .. } = shared;
+    let found = {
+        let db = db.lock().unwrap();
+        match db.stored_message(message_id)? {
+            Some(stored) if !stored.deleted && db.rooms_for_user(session.user_id)?.contains(&stored.room_id) => {
+                db.attachment(message_id)?.map(|attachment| (stored.room_id, attachment))
+            }
+            _ => None,
+        }
+    };
+    let Some((room_id, attachment)) = found else {
+        info!("{} asked for attachment {}, which they can't see", session.username, message_id);
+        return send_warning(message, stream, String::from("couldn't download: there's no such file"));
+    };
+    let path = blob_path(&config.attachment_dir, &attachment.sha256);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) => {
+            error!("couldn't read attachment {} from {}: {}", message_id, path.display(), err);
+            return send_warning(message, stream, String::from("couldn't download: the server has lost that file"));
+        }
+    };
+    let StoredAttachment { file_name, size, sha256 } = attachment;
+    send_to(message, vec![stream.clone()], Frame::Offer { transfer_id: message_id, room_id, size, sha256, file_name })?;
+    for (index, data) in data.chunks(MAX_CHUNK_LEN).enumerate() {
+        let offset = (index * MAX_CHUNK_LEN) as u64;
+        send_to(message, vec![stream.clone()], Frame::Chunk { transfer_id: message_id, offset, data: data.to_vec() })?;
+    }
+    send_to(message, vec![stream.clone()], Frame::Complete { transfer_id: message_id })
+}
+
+// hold a message for whoever it mentions that isn't connected to see it arrive
+fn queue_for_offline(
+    server_state: &Arc<Mutex<ServerState>>,
+    db: &Database,
+    message_id: u64,
+    user_ids: &[Uuid]) -> Result<()> {
+
+    let offline: Vec<Uuid> = {
+        let state = server_state.lock().unwrap();
+        user_ids.iter().filter(|uuid| !state.is_online(uuid)).copied().collect()
+    };
+    if offline.is_empty() {
+        return Ok(());
+    }
+    db.queue_delivery(message_id, &offline)
+}
+
+// CHATMSG: store it, acknowledge it to the sender, and relay it. The client doesn't get to pick who it
+// speaks as; the joined identity is used, and only in rooms it's a member of. A reply goes to its parent's
+// room, and replying to a reply continues the same thread rather than nesting a new one.
+fn chat(
+    stream: &Arc<TcpStream>,
+    shared: &Shared,
+    session: &Session,
+    room_id: u64,
+    nonce: u64,
+    parent_id: u64,
+    message_text: String) -> Result<()> {
+
+    let Shared { message, server_state, db, .. } = shared;
+    let (room_id, parent_id) = if parent_id == 0 {
+        if !db.lock().unwrap().rooms_for_user(session.user_id)?.contains(&room_id) {
+            info!("{} sent a message to room {}, which they aren't in", session.username, room_id);
+            return send_warning(message, stream, String::from("couldn't send message: you aren't in that room"));
+        }
+        (room_id, None)
+    } else {
+        let db = db.lock().unwrap();
+        let rooms = db.rooms_for_user(session.user_id)?;
+        match db.stored_message(parent_id)? {
+            Some(parent) if !parent.deleted && rooms.contains(&parent.room_id) => {
+                (parent.room_id, Some(parent.parent_id.unwrap_or(parent_id)))
+            }
+            _ => {
+                info!("{} replied to message {}, which they can't see", session.username, parent_id);
+                let notice = Frame::Notice {
+                    severity: NoticeSeverity::WARNING,
+                    room_id: ALL_ROOMS,
+                    notice_text: String::from("couldn't send reply: there's no such message"),
+                };
+                return send_to(message, vec![stream.clone()], notice);
+            }
+        }
+    };
+    let streams = room_streams(server_state, db, room_id)?;
+    // hold the database while queueing, so messages go out in message_id order
+    let db = db.lock().unwrap();
+    let (message_id, timestamp) = db.store_message(session.user_id, room_id, parent_id, &message_text)?;
+    let user_ids = db.set_mentions(message_id, room_id, &message_text)?;
+    queue_for_offline(server_state, &db, message_id, &user_ids)?;
+    send_to(message, vec![stream.clone()], Frame::SentAck { nonce, message_id, timestamp })?;
+    send_to(message, streams.clone(), Frame::ChatMsg {
+        message_id,
+        timestamp,
+        sender_id: session.uuid,
+        room_id,
+        nonce: 0,
+        parent_id: parent_id.unwrap_or(0),
+        message_text,
+    })?;
+    if user_ids.is_empty() {
+        return Ok(());
+    }
+    send_to(message, streams, Frame::Mentions { message_id, user_ids })
+}
+
+// REACTION: anyone in the message's room may react to it, unless it's been deleted. Every change
+// sends the room the message's new totals.
+fn react(
+    stream: &Arc<TcpStream>,
+    shared: &Shared,
+    session: &Session,
+    message_id: u64,
+    action: u8,
+    reaction: &str) -> Result<()> {
+
+    let Shared { message, server_state, db, .. } = shared;
+    let outcome = {
+        let db = db.lock().unwrap();
+        match db.stored_message(message_id)? {
+            Some(stored) if !stored.deleted && db.rooms_for_user(session.user_id)?.contains(&stored.room_id) => {
+                let reactions = db.reactions(message_id)?;
+                match action {
+                    ReactionAction::ADD if reactions.len() >= MAX_REACTIONS
+                        && !reactions.iter().any(|r| r.reaction == reaction) => {
+                        Err("that message can't take any more kinds of reaction")
+                    }
+                    ReactionAction::ADD => {
+                        db.add_reaction(message_id, session.user_id, reaction)?;
+                        Ok((stored.room_id, db.reactions(message_id)?))
+                    }
+                    ReactionAction::REMOVE => {
+                        db.remove_reaction(message_id, session.user_id, reaction)?;
+                        Ok((stored.room_id, db.reactions(message_id)?))
+                    }
+                    _ => Err("unknown reaction action"),
+                }
+            }
+            // same answer whether the message is missing or just in someone else's room
+            _ => Err("there's no such message"),
+        }
+    };
+    match outcome {
+        Ok((room_id, reactions)) => {
+            let streams = room_streams(server_state, db, room_id)?;
+            send_to(message, streams, Frame::Reactions { message_id, reactions })
+        }
+        Err(why) => {
+            info!("refused {}'s reaction to message {}: {}", session.username, message_id, why);
+            let notice = Frame::Notice {
+                severity: NoticeSeverity::WARNING,
+                room_id: ALL_ROOMS,
+                notice_text: format!("couldn't react: {}", why),
+            };
+            send_to(message, vec![stream.clone()], notice)
+        }
+    }
+}
+
+// EDITMSG or DELETEMSG. Only the author, or a moderator of the server or of the message's room, may change
+// a message; anyone else, or anyone naming a message that doesn't exist or is already deleted, gets a
+// NOTICE saying why not.
+fn change_message(stream: &Arc<TcpStream>, shared: &Shared, session: &Session, frame: Frame) -> Result<()> {
+    let Shared { message, server_state, db, .. } = shared;
+    let (Frame::EditMsg { message_id, .. } | Frame::DeleteMsg { message_id }) = frame else {
+        return Ok(());
+    };
+    let outcome = {
+        let db = db.lock().unwrap();
+        match db.stored_message(message_id)? {
+            None => Err("there's no such message"),
+            Some(stored) if stored.deleted => Err("that message has been deleted"),
+            Some(stored) if stored.sender_id != session.user_id && !db.can_moderate(session.user_id, stored.room_id)? => {
+                Err("you can only change your own messages")
+            }
+            Some(stored) => {
+                // an edit can change who the message mentions; say so if it mentions anyone, or used to
+                let mut mentions = None;
+                match &frame {
+                    Frame::EditMsg { message_text, .. } => {
+                        let before = db.mentions(message_id)?;
+                        db.edit_message(message_id, session.user_id, message_text)?;
+                        let user_ids = db.set_mentions(message_id, stored.room_id, message_text)?;
+                        queue_for_offline(server_state, &db, message_id, &user_ids)?;
+                        if !before.is_empty() || !user_ids.is_empty() {
+                            mentions = Some(Frame::Mentions { message_id, user_ids });
+                        }
+                    }
+                    _ => db.delete_message(message_id, session.user_id)?,
+                }
+                Ok((stored.room_id, mentions))
             }
         }
+    };
+    match outcome {
+        Ok((room_id, mentions)) => {
+            info!("{} changed message {}: {:?}", session.username, message_id, frame);
+            let streams = room_streams(server_state, db, room_id)?;
+            send_to(message, streams.clone(), frame)?;
+            match mentions {
+                Some(mentions) => send_to(message, streams, mentions),
+                None => Ok(()),
+            }
+        }
+        Err(why) => {
+            info!("refused to let {} change message {}: {}", session.username, message_id, why);
+            let notice = Frame::Notice {
+                severity: NoticeSeverity::WARNING,
+                room_id: ALL_ROOMS,
+                notice_text: format!("couldn't change message: {}", why),
+            };
+            send_to(message, vec![stream.clone()], notice)
+        }
     }
+}
 
+// a ROOMINFO for `room_id`, and the user's ROOMROLE in it
+fn room_frames(db: &Database, session: &Session, room_id: u64) -> Result<Vec<Frame>> {
+    let mut frames: Vec<Frame> = db.room_info(room_id)?.into_iter().collect();
+    if let Some(room_role) = db.room_role(session.user_id, room_id)? {
+        frames.push(Frame::RoomRole { room_id, user_id: session.uuid, username: Clone::clone(&session.username), room_role });
+    }
+    Ok(frames)
+}
+
+// CREATEROOM or JOINROOM: a room the user has just become a member of. Each of their sessions is sent its
+// UNREAD, ROOMINFO and their ROOMROLE, and anyone already in it is told they've arrived.
+fn enter_room(stream: &Arc<TcpStream>, shared: &Shared, session: &Session, frame: Frame) -> Result<()> {
+    let Shared { message, server_state, db, .. } = shared;
+    let (outcome, doing) = {
+        let db = db.lock().unwrap();
+        match &frame {
+            Frame::CreateRoom { room_name, .. } if validate_room_name(room_name).is_err() => {
+                (Err("room names can't be empty or have spaces in them"), "create")
+            }
+            Frame::CreateRoom { room_name, is_public } => (db.create_room(session.user_id, room_name, *is_public)?, "create"),
+            Frame::JoinRoom { room_name } => (db.join_room(session.user_id, room_name)?, "join"),
+            _ => return Ok(()),
+        }
+    };
+    let room_id = match outcome {
+        Ok(room_id) => room_id,
+        Err(why) => {
+            info!("refused to let {} {} a room: {}", session.username, doing, why);
+            return send_warning(message, stream, format!("couldn't {} the room: {}", doing, why));
+        }
+    };
+    let streams = server_state.lock().unwrap().user_streams(&session.uuid);
+    info!("{} {}d room {}: {:?}", session.username, doing, room_id, frame);
+    let frames = {
+        let db = db.lock().unwrap();
+        let mut frames: Vec<Frame> = db.unread(session.user_id)?.into_iter()
+            .filter(|unread| matches!(unread, Frame::Unread { room_id: id, .. } if *id == room_id))
+            .collect();
+        frames.extend(room_frames(&db, session, room_id)?);
+        frames
+    };
+    for frame in frames {
+        send_to(message, streams.clone(), frame)?;
+    }
+    if matches!(frame, Frame::JoinRoom { .. }) {
+        send_notice(message, server_state, db, NoticeSeverity::INFO, room_id, &format!("{} joined the room", session.username))?;
+    }
     Ok(())
 }
 
-fn handle_client(
-    stream: Arc<TcpStream>,
-    message: Sender<Message>,
-    server_state: Arc<Mutex<ServerState>>) -> Result<()> {
+// INVITE: any member may invite someone into a room they're in, private or not. The invitee's sessions
+// are sent the INVITE now, and again each time they log in until they use it.
+fn invite(stream: &Arc<TcpStream>, shared: &Shared, session: &Session, room_id: u64, username: String) -> Result<()> {
+    let Shared { message, server_state, db, .. } = shared;
+    let outcome = {
+        let db = db.lock().unwrap();
+        match db.room_info(room_id)? {
+            Some(Frame::RoomInfo { room_name, .. }) if db.room_role(session.user_id, room_id)?.is_some() => {
+                db.invite(room_id, session.user_id, &username)?.map(|uuid| (uuid, room_name))
+            }
+            _ => Err("there's no such room"),
+        }
+    };
+    let (uuid, room_name) = match outcome {
+        Ok(invited) => invited,
+        Err(why) => {
+            info!("refused to let {} invite {} to room {}: {}", session.username, username, room_id, why);
+            return send_warning(message, stream, format!("couldn't invite {}: {}", username, why));
+        }
+    };
+    info!("{} invited {} to room {}", session.username, username, room_id);
+    let streams = server_state.lock().unwrap().user_streams(&uuid);
+    let invitation = Frame::Invite { room_id, room_name: Clone::clone(&room_name), username: Clone::clone(&session.username) };
+    send_to(message, streams, invitation)?;
+    let notice_text = format!("invited {} to {}", username, room_name);
+    send_to(message, vec![stream.clone()], Frame::Notice { severity: NoticeSeverity::INFO, room_id, notice_text })
+}
+
+// SETTOPIC: a room's owner and moderators, and the server's moderators, may say what it's about. Everyone
+// in it is sent the new ROOMINFO.
+fn set_topic(
+    stream: &Arc<TcpStream>,
+    shared: &Shared,
+    session: &Session,
+    room_id: u64,
+    topic: &str,
+    description: &str) -> Result<()> {
 
-    if stream.peer_addr().is_err() {
-        error!("couldn't get client's peer address.");
-        return Err(());
+    let Shared { message, server_state, db, .. } = shared;
+    let outcome = {
+        let db = db.lock().unwrap();
+        match db.room_role(session.user_id, room_id)? {
+            None => Err("there's no such room"),
+            Some(_) if !db.can_moderate(session.user_id, room_id)? => Err("only the room's owner and moderators can"),
+            Some(_) => {
+                db.set_topic(room_id, topic, description)?;
+                Ok(db.room_info(room_id)?)
+            }
+        }
+    };
+    match outcome {
+        Ok(info) => {
+            info!("{} set the topic of room {} to {:?}", session.username, room_id, topic);
+            let streams = room_streams(server_state, db, room_id)?;
+            match info {
+                Some(info) => send_to(message, streams, info),
+                None => Ok(()),
+            }
+        }
+        Err(why) => {
+            info!("refused to let {} set the topic of room {}: {}", session.username, room_id, why);
+            send_warning(message, stream, format!("couldn't set the topic: {}", why))
+        }
     }
-    else {
-        info!("new connection from {:?}", stream.peer_addr().unwrap());
+}
+
+// ROOMROLE: only a room's owner may make its members moderators, or members again. Everyone in the room
+// is told.
+fn set_room_role(
+    stream: &Arc<TcpStream>,
+    shared: &Shared,
+    session: &Session,
+    room_id: u64,
+    username: String,
+    room_role: u8) -> Result<()> {
+
+    let Shared { message, server_state, db, .. } = shared;
+    let outcome = {
+        let db = db.lock().unwrap();
+        match db.room_role(session.user_id, room_id)? {
+            None => Err("there's no such room"),
+            Some(role) if role != RoomRole::OWNER => Err("only the room's owner can"),
+            Some(_) if room_role == RoomRole::OWNER => Err("a room only has the one owner"),
+            Some(_) => db.set_room_role(room_id, &username, room_role)?,
+        }
+    };
+    match outcome {
+        Ok(user_id) => {
+            info!("{} gave {} role {} in room {}", session.username, username, room_role, room_id);
+            let streams = room_streams(server_state, db, room_id)?;
+            send_to(message, streams, Frame::RoomRole { room_id, user_id, username, room_role })
+        }
+        Err(why) => {
+            info!("refused to let {} change {}'s role in room {}: {}", session.username, username, room_id, why);
+            send_warning(message, stream, format!("couldn't change {}'s role: {}", username, why))
+        }
     }
+}
+
+// who this connection is, once it has JOINed
+struct Session{
+    user_id:    i64,
+    uuid:       Uuid,
+    username:   String,
+    token:      String, // the session token it logged in with; revoked on LEAVE
+}
+
+// a KEYLOGIN waiting for the CHALLENGERESPONSE that finishes it
+struct Challenge{
+    username:   String,
+    public_key: [u8; PUBLIC_KEY_LEN],
+    nonce:      [u8; NONCE_LEN],
+}
+
+// a new login, by JOIN, LOGIN or KEYLOGIN: a SESSION token to come back with, then everything join sends
+fn log_in(
+    stream: &Arc<TcpStream>,
+    peer: SocketAddr,
+    shared: &Shared,
+    user_id: i64,
+    uuid: Uuid,
+    username: String) -> Result<Session> {
+
+    let Shared { message, db, config, .. } = shared;
+    let (token, expires) = db.lock().unwrap().create_session(user_id, config.session_lifetime)?;
+    send_to(message, vec![stream.clone()], Frame::Session { token: Clone::clone(&token), expires })?;
+    let session = Session { user_id, uuid, username, token };
+    join(stream, peer, shared, &session)?;
+    Ok(session)
+}
+
+/// Everything that follows a successful login, by JOIN or RESUME: the account gets this connection as
+/// a session, learns who else is here and where it left off, and everyone else learns about it
+fn join(stream: &Arc<TcpStream>, peer: SocketAddr, shared: &Shared, session: &Session) -> Result<()> {
+    let Shared { message, server_state, db, config, .. } = shared;
+    let Session { user_id, uuid, ref username, .. } = *session;
+    let rooms = db.lock().unwrap().rooms_for_user(user_id)?;
+    let (others, first, changed, streams, presence) = {
+        let mut state = server_state.lock().unwrap();
+        let first = !state.users.contains_key(&uuid);
+        if !first {
+            info!("User with name {} is already connected; adding a session", username);
+        }
+        let user = state.users.entry(uuid).or_insert_with(|| User::new(Clone::clone(username), uuid));
+        let before = user.status();
+        user.sessions.insert(peer, PresenceStatus::ONLINE);
+        let changed = before != user.status();
+        let presence = user.presence();
+        if let Some(c) = state.connections.get_mut(&peer) {
+            c.user = Some(uuid);
+        }
+        info!("Current users are: {:?}", state.users.values().map(|u| &u.displayname).collect::<Vec<_>>());
+        let others: Vec<Frame> = state.users.values()
+            .filter(|u| u.uuid != uuid)
+            .flat_map(|u| [
+                Frame::Userjoined { user_id: u.uuid, username: Clone::clone(&u.displayname) },
+                u.presence(),
+            ])
+            .collect();
+        (others, first, changed, state.joined_streams(), presence)
+    };
+    for other in others {
+        send_to(message, vec![stream.clone()], other)?;
+    }
+    // everyone else already knows about an account that was online before this session
+    let joined = Frame::Userjoined { user_id: uuid, username: Clone::clone(username) };
+    match first {
+        true => send_to(message, streams.clone(), joined)?,
+        false => send_to(message, vec![stream.clone()], joined)?,
+    }
+    match first || changed {
+        true => send_to(message, streams, presence)?,
+        false => send_to(message, vec![stream.clone()], presence)?,
+    }
+
+    // where they left off and what each room is, then what they missed
+    let unread = {
+        let db = db.lock().unwrap();
+        let mut frames = db.unread(user_id)?;
+        for &room_id in &rooms {
+            frames.extend(room_frames(&db, session, room_id)?);
+        }
+        frames
+    };
+    for frame in unread {
+        send_to(message, vec![stream.clone()], frame)?;
+    }
+    let queued = db.lock().unwrap().take_queued(user_id)?;
+    for entries in queued.chunks(MAX_HISTORY_BATCH) {
+        send_to(message, vec![stream.clone()], Frame::Queued { entries: entries.to_vec() })?;
+    }
+    if config.history_on_join > 0 {
+        for room_id in rooms {
+            let entries = db.lock().unwrap().history(room_id, 0, config.history_on_join)?;
+            send_history(stream, shared, room_id, entries)?;
+        }
+    }
+    // last, so the history replay doesn't push them out of view
+    let direct = {
+        let db = db.lock().unwrap();
+        let mut frames = db.take_direct(user_id)?;
+        frames.extend(db.invites(user_id)?);
+        frames
+    };
+    for frame in direct {
+        send_to(message, vec![stream.clone()], frame)?;
+    }
+    Ok(())
+}
+
+fn handle_client(stream: Arc<TcpStream>, shared: Shared) -> Result<()> {
+    let Shared { message, server_state, .. } = &shared;
+
+    let peer = stream.peer_addr().map_err(|_err| {
+        error!("couldn't get client's peer address.");
+    })?;
+    info!("new connection from {:?}", peer);
+    server_state.lock().unwrap().connections.insert(peer, Connection {
+        stream: stream.clone(),
+        user: None,
+        extensions: Vec::new(),
+    });
+
+    let result = serve_client(&stream, peer, &shared);
+
+    let _ = stream.as_ref().shutdown(Shutdown::Both);
+    // only the account's last session leaving takes the user offline; until then, losing this one
+    // may still change how available they are
+    let left = {
+        let mut state = server_state.lock().unwrap();
+        let uuid = state.connections.remove(&peer).and_then(|c| c.user);
+        let remaining = uuid.and_then(|uuid| state.users.get_mut(&uuid)).map(|user| {
+            let before = user.status();
+            user.sessions.remove(&peer);
+            (user.sessions.is_empty(), before != user.status(), user.presence())
+        });
+        match (uuid, remaining) {
+            (Some(user_id), Some((true, _, _))) => {
+                state.users.remove(&user_id);
+                Some((Frame::Userleft { user_id }, state.joined_streams()))
+            }
+            (_, Some((false, true, presence))) => Some((presence, state.joined_streams())),
+            _ => None,
+        }
+    };
+    if let Some((frame, streams)) = left {
+        send_to(message, streams, frame)?;
+    }
+    info!("connection from {:?} closed", peer);
+    result
+}
+
+fn serve_client(stream: &Arc<TcpStream>, peer: SocketAddr, shared: &Shared) -> Result<()> {
+    let Shared { message, server_state, db, config, extensions, .. } = shared;
 
     /****< Connection preamble: send sever version & welcome to each client>***/
-    let server_version = Message::Version{
-        author: stream.clone(),
-        message_type: MessageType::VERSION,
+    let server_version = Frame::Version {
         major_rev: 0,
         minor_rev: 2,
         subminor_rev: 0,
     };
-    message.send(server_version).map_err(|err| {
-        error!("couldn't send version message to client. Err was: {}", err);
-    })?;
-    let welcome = Message::Welcome{
-        author: stream.clone(),
-        message_type: MessageType::WELCOME,
-        message_len: WELCOME.len() as u16,
-        welcome_msg: WELCOME.as_bytes().to_vec(),
-    };
-    message.send(welcome).map_err(|err|{
-        error!("couldn't send welcome message to MPSC sender. Err was {}",err);
-    })?;
+    send_to(message, vec![stream.clone()], server_version)?;
+    let capabilities = Frame::Capabilities { extensions: extensions.capabilities() };
+    send_to(message, vec![stream.clone()], capabilities)?;
+    let welcome = Frame::Welcome { welcome_msg: server_state.lock().unwrap().motd.clone() };
+    send_to(message, vec![stream.clone()], welcome)?;
     /*********************</connection preamble>******************************/
 
-    let mut isalive = true;
+    let mut session: Option<Session> = None;
+    let mut challenge: Option<Challenge> = None;
+    let mut extension_context = ExtensionContext { stream: stream.clone(), message: message.clone() };
+    let mut uploads = Uploads::new(&config.attachment_dir, config.max_attachment_size, peer);
     let mut reader = BufReader::new(stream.as_ref());
-    let mut message_type = [0u8];
-    let mut bufr:Vec<u8> = Vec::new();
     loop{
-        reader.read_exact(&mut message_type).map_err(|err| {
-            error!("couldn't receive message; assuming client disconnect. Error was: {}", err);
-            stream.as_ref().shutdown(Shutdown::Both);
-            isalive = false;
-        });
-        if !isalive { break; }
-
-        match message_type[0]{
-            MessageType::JOIN => {
-                let mut len = [0u8, 0u8];
-                match reader.read_exact(&mut len){
-                    Err(e) => error!("couldn't read username length from JOIN message. Err was: {}", e),
-                    _ => (),
-                }
-                let len:u16 = u16::from_le_bytes(len);
-                let mut uname_buf = vec![0; len as usize];
-                match reader.read_exact(&mut uname_buf) {
-                    Err(e) => error!("couldn't read {} bytes (expected for Username length)", len),
-                    _ => (),
-                }
-                let uname = String::from_utf8(uname_buf.clone()).unwrap();
-                match server_state.lock().unwrap().user_map.get(&uname) {
-                    Some(u) => {
-                        let mut u : User = User::new(Clone::clone(&uname));
-                        server_state.lock().unwrap().add_user(&mut u);
-                    },
-                    None => {
-                        info!("User with name {} already exists on the server; nothing to do", uname);
-                        info!("Current users are: {:?}", server_state.lock().unwrap().user_map.keys());
-                    },
+        let frame = match read_frame(&mut reader) {
+            Ok(frame) => frame,
+            Err(FrameError::Io(err)) => {
+                info!("couldn't receive message; assuming client {:?} disconnected. Error was: {}", peer, err);
+                return Ok(());
+            }
+            Err(err) => {
+                error!("protocol violation from {:?}: {}; closing connection", peer, err);
+                return Ok(());
+            }
+        };
+
+        match frame{
+            Frame::Join { username } => {
+                if session.is_some() {
+                    info!("client {:?} sent JOIN twice; ignoring", peer);
+                    continue;
                 }
-                let userjoin = Message::Userjoined {
-                    author: stream.clone(),
-                    message_type: MessageType::USERJOINED,
-                    user_id: (server_state.lock().unwrap().user_map.get(&uname).unwrap() as &User).uuid,
-                    username_len: len,
-                    username: uname_buf.clone(),
+                let account = {
+                    let db = db.lock().unwrap();
+                    match db.account(&username)? {
+                        // an account with a password or keys is only for whoever holds them
+                        Some(account) if !account.is_guest() => None,
+                        _ => Some(db.user_for_name(&username)?),
+                    }
                 };
-                message.send(userjoin).map_err(|err|{
-                    error!("couldn't send USERJOINED message to MPSC sender. Err was {}",err);
-                })?;
-
+                let Some((user_id, uuid)) = account else {
+                    info!("client {:?} tried to JOIN as {}, who has a password or keys", peer, username);
+                    send_warning(message, stream, format!("{} is protected; log in with a password or key", username))?;
+                    continue;
+                };
+                session = Some(log_in(stream, peer, shared, user_id, uuid, username)?);
+                // a KEYLOGIN still waiting on its answer mustn't log this connection in a second time
+                challenge = None;
             }
-            _ => {
-                info!(
-                    "the client sent an unknown message type, with ID: {}; ignoring message contents",
-                    message_type[0]
+            Frame::Login { username, password } => {
+                if session.is_some() {
+                    info!("client {:?} sent LOGIN after joining; ignoring", peer);
+                    continue;
+                }
+                let address = peer.ip().to_string();
+                if db.lock().unwrap().login_throttled(&username, &address, &LOGIN_THROTTLE)? {
+                    info!("client {:?} tried to log in as {} too often; refusing", peer, username);
+                    send_warning(message, stream, String::from("couldn't log in: too many failed attempts; try again later"))?;
+                    continue;
+                }
+                let account = db.lock().unwrap().account(&username)?;
+                // checking is slow on purpose, so it's done without the database, and just as slow for an
+                // account that doesn't exist or has no password
+                let hash = account.as_ref().and_then(|account| account.password_hash.as_deref());
+                let right = password::verify_stored(&password, hash);
+                let Some(account) = account.filter(|_| right) else {
+                    info!("client {:?} failed to log in as {}", peer, username);
+                    db.lock().unwrap().login_failed(&username, &address, LOGIN_THROTTLE.window)?;
+                    send_warning(message, stream, String::from("couldn't log in: wrong username or password"))?;
+                    continue;
+                };
+                db.lock().unwrap().login_succeeded(&username)?;
+                session = Some(log_in(stream, peer, shared, account.user_id, account.uuid, username)?);
+                challenge = None;
+            }
+            Frame::KeyLogin { username, public_key } => {
+                if session.is_some() {
+                    info!("client {:?} sent KEYLOGIN after joining; ignoring", peer);
+                    continue;
+                }
+                // challenged whether or not the key is theirs, so the answer doesn't say which keys are
+                let nonce = keys::new_nonce();
+                send_to(message, vec![stream.clone()], Frame::Challenge { nonce })?;
+                challenge = Some(Challenge { username, public_key, nonce });
+            }
+            Frame::ChallengeResponse { signature } => {
+                if session.is_some() {
+                    info!("client {:?} answered a challenge after joining; ignoring", peer);
+                    challenge = None;
+                    continue;
+                }
+                let Some(Challenge { username, public_key, nonce }) = challenge.take() else {
+                    info!("client {:?} answered a challenge it wasn't sent; ignoring", peer);
+                    continue;
+                };
+                let account = {
+                    let db = db.lock().unwrap();
+                    match db.account(&username)? {
+                        Some(account) if db.has_key(account.user_id, &public_key)? => Some(account),
+                        _ => None,
+                    }
+                };
+                let Some(account) = account.filter(|_| keys::verify(&public_key, &nonce, &signature)) else {
+                    info!("client {:?} failed to log in as {} with a key", peer, username);
+                    send_warning(message, stream, String::from("couldn't log in: that key isn't one of theirs"))?;
+                    continue;
+                };
+                session = Some(log_in(stream, peer, shared, account.user_id, account.uuid, username)?);
+            }
+            Frame::AddKey { public_key } => {
+                let Some(session) = &session else {
+                    info!("client {:?} tried to add a key before joining; ignoring", peer);
+                    continue;
+                };
+                if !keys::is_valid(&public_key) {
+                    send_warning(message, stream, String::from("couldn't add the key: it isn't an ed25519 key"))?;
+                    continue;
+                }
+                if let Err(why) = db.lock().unwrap().add_key(session.user_id, &public_key)? {
+                    info!("refused {}'s new key: {}", session.username, why);
+                    send_warning(message, stream, format!("couldn't add the key: {}", why))?;
+                    continue;
+                }
+                info!("{} added a key", session.username);
+                let notice_text = String::from("key added; you can log in with it now");
+                send_to(message, vec![stream.clone()], Frame::Notice { severity: NoticeSeverity::INFO, room_id: ALL_ROOMS, notice_text })?;
+            }
+            Frame::RemoveKey { public_key } => {
+                let Some(session) = &session else {
+                    info!("client {:?} tried to remove a key before joining; ignoring", peer);
+                    continue;
+                };
+                if !db.lock().unwrap().remove_key(session.user_id, &public_key)? {
+                    send_warning(message, stream, String::from("couldn't remove the key: it isn't one of yours"))?;
+                    continue;
+                }
+                info!("{} removed a key", session.username);
+                let notice_text = String::from("key removed");
+                send_to(message, vec![stream.clone()], Frame::Notice { severity: NoticeSeverity::INFO, room_id: ALL_ROOMS, notice_text })?;
+            }
+            Frame::PublishKey { public_key } => {
+                let Some(session) = &session else {
+                    info!("client {:?} tried to publish an identity key before joining; ignoring", peer);
+                    continue;
+                };
+                db.lock().unwrap().set_identity_key(session.user_id, &public_key)?;
+                info!("{} published an identity key", session.username);
+            }
+            Frame::KeyRequest { username } => {
+                if session.is_none() {
+                    info!("client {:?} asked for an identity key before joining; ignoring", peer);
+                    continue;
+                }
+                let (user_id, public_key) = db.lock().unwrap().identity_key(&username)?.unwrap_or((Uuid::nil(), None));
+                send_to(message, vec![stream.clone()], Frame::IdentityKey { user_id, username, public_key })?;
+            }
+            Frame::DirectMsg { recipient_id, sender_key, recipient_key, nonce, ciphertext, .. } => {
+                let Some(session) = &session else {
+                    info!("client {:?} sent a direct message before joining; ignoring", peer);
+                    continue;
+                };
+                let sent = DirectMsg { recipient_id, sender_key, recipient_key, nonce, ciphertext };
+                if let Err(why) = direct_message(shared, session, sent)? {
+                    info!("refused {}'s direct message: {}", session.username, why);
+                    send_warning(message, stream, format!("couldn't send the direct message: {}", why))?;
+                }
+            }
+            Frame::Resume { token } => {
+                if session.is_some() {
+                    info!("client {:?} sent RESUME after joining; ignoring", peer);
+                    continue;
+                }
+                let resumed = db.lock().unwrap().resume_session(&token, config.session_lifetime)?;
+                let Some((user_id, uuid, username, expires)) = resumed else {
+                    info!("client {:?} tried to resume an unknown or expired session", peer);
+                    send_to(message, vec![stream.clone()], Frame::Session { token: String::new(), expires: 0 })?;
+                    continue;
+                };
+                send_to(message, vec![stream.clone()], Frame::Session { token: Clone::clone(&token), expires })?;
+                let resumed = Session { user_id, uuid, username, token };
+                join(stream, peer, shared, &resumed)?;
+                session = Some(resumed);
+                challenge = None;
+            }
+            Frame::ChatMsg { room_id, nonce, parent_id, message_text, .. } => {
+                let Some(session) = &session else {
+                    info!("client {:?} sent a chat message before joining; ignoring", peer);
+                    continue;
+                };
+                if message_text.trim().is_empty() {
+                    continue;
+                }
+                chat(stream, shared, session, room_id, nonce, parent_id, message_text)?;
+            }
+            frame @ (Frame::CreateRoom { .. } | Frame::JoinRoom { .. }) => {
+                let Some(session) = &session else {
+                    info!("client {:?} tried to create or join a room before joining; ignoring", peer);
+                    continue;
+                };
+                enter_room(stream, shared, session, frame)?;
+            }
+            Frame::Invite { room_id, username, .. } => {
+                let Some(session) = &session else {
+                    info!("client {:?} sent an invitation before joining; ignoring", peer);
+                    continue;
+                };
+                invite(stream, shared, session, room_id, username)?;
+            }
+            Frame::SetTopic { room_id, topic, description } => {
+                let Some(session) = &session else {
+                    info!("client {:?} set a topic before joining; ignoring", peer);
+                    continue;
+                };
+                set_topic(stream, shared, session, room_id, &topic, &description)?;
+            }
+            Frame::RoomRole { room_id, username, room_role, .. } => {
+                let Some(session) = &session else {
+                    info!("client {:?} set a room role before joining; ignoring", peer);
+                    continue;
+                };
+                set_room_role(stream, shared, session, room_id, username, room_role)?;
+            }
+            Frame::History { room_id, before_id, limit } => {
+                let Some(session) = &session else {
+                    info!("client {:?} asked for history before joining; ignoring", peer);
+                    continue;
+                };
+                let limit = limit.min(MAX_HISTORY_BATCH as u16);
+                let entries = {
+                    let db = db.lock().unwrap();
+                    if db.rooms_for_user(session.user_id)?.contains(&room_id) {
+                        db.history(room_id, before_id, limit)?
+                    } else {
+                        // same answer as an empty room, so membership doesn't leak
+                        info!("{} asked for history of room {} without being a member", session.username, room_id);
+                        Vec::new()
+                    }
+                };
+                send_history(stream, shared, room_id, entries)?;
+            }
+            Frame::Thread { parent_id } => {
+                let Some(session) = &session else {
+                    info!("client {:?} asked for a thread before joining; ignoring", peer);
+                    continue;
+                };
+                let entries = {
+                    let db = db.lock().unwrap();
+                    let rooms = db.rooms_for_user(session.user_id)?;
+                    match db.stored_message(parent_id)? {
+                        Some(parent) if rooms.contains(&parent.room_id) => db.thread(parent_id, MAX_HISTORY_BATCH as u16)?,
+                        // as with HISTORY, a thread you can't see looks like one with no replies
+                        _ => Vec::new(),
+                    }
+                };
+                let details = details_for(db, &entries)?;
+                send_to(message, vec![stream.clone()], Frame::ThreadBatch { parent_id, entries })?;
+                for frame in details {
+                    send_to(message, vec![stream.clone()], frame)?;
+                }
+            }
+            Frame::Typing { room_id, typing, .. } => {
+                let Some(session) = &session else {
+                    continue;
+                };
+                // relayed as is and never stored; receivers time it out themselves
+                if !db.lock().unwrap().rooms_for_user(session.user_id)?.contains(&room_id) {
+                    continue;
+                }
+                let streams = room_streams(server_state, db, room_id)?.into_iter()
+                    .filter(|other| !Arc::ptr_eq(other, stream))
+                    .collect();
+                send_to(message, streams, Frame::Typing { room_id, user_id: session.uuid, typing })?;
+            }
+            Frame::Presence { status, status_text, .. } => {
+                let Some(session) = &session else {
+                    info!("client {:?} set its presence before joining; ignoring", peer);
+                    continue;
+                };
+                let (presence, streams) = {
+                    let mut state = server_state.lock().unwrap();
+                    let Some(user) = state.users.get_mut(&session.uuid) else {
+                        continue;
+                    };
+                    user.sessions.insert(peer, status);
+                    user.status_text = status_text;
+                    (user.presence(), state.joined_streams())
+                };
+                // everyone, the sender included, so all of the account's sessions agree
+                send_to(message, streams, presence)?;
+            }
+            frame @ (Frame::EditMsg { .. } | Frame::DeleteMsg { .. }) => {
+                let Some(session) = &session else {
+                    info!("client {:?} tried to change a message before joining; ignoring", peer);
+                    continue;
+                };
+                if matches!(&frame, Frame::EditMsg { message_text, .. } if message_text.trim().is_empty()) {
+                    // deleting is its own operation, with its own tombstone
+                    continue;
+                }
+                change_message(stream, shared, session, frame)?;
+            }
+            Frame::Reaction { message_id, action, reaction } => {
+                let Some(session) = &session else {
+                    info!("client {:?} reacted before joining; ignoring", peer);
+                    continue;
+                };
+                react(stream, shared, session, message_id, action, &reaction)?;
+            }
+            Frame::Offer { transfer_id, room_id, size, sha256, file_name } => {
+                let Some(session) = &session else {
+                    info!("client {:?} offered a file before joining; ignoring", peer);
+                    continue;
+                };
+                let offered = StoredAttachment { file_name, size, sha256 };
+                let outcome = match db.lock().unwrap().rooms_for_user(session.user_id)?.contains(&room_id) {
+                    true => uploads.offer(transfer_id, room_id, offered),
+                    false => Err("there's no such room"),
+                };
+                if let Err(why) = outcome {
+                    info!("refused {}'s upload {}: {}", session.username, transfer_id, why);
+                    send_warning(message, stream, format!("couldn't upload: {}", why))?;
+                }
+            }
+            Frame::Chunk { transfer_id, offset, data } => {
+                if let Err(why) = uploads.chunk(transfer_id, offset, &data) {
+                    info!("abandoned upload {} from {:?}: {}", transfer_id, peer, why);
+                    send_warning(message, stream, format!("couldn't upload: {}", why))?;
+                }
+            }
+            Frame::Complete { transfer_id } => {
+                let Some(session) = &session else {
+                    continue;
+                };
+                match uploads.complete(transfer_id) {
+                    Ok(Some((room_id, attachment))) => attach(stream, shared, session, transfer_id, room_id, attachment)?,
+                    Ok(None) => (),
+                    Err(why) => {
+                        info!("refused {}'s upload {}: {}", session.username, transfer_id, why);
+                        send_warning(message, stream, format!("couldn't upload: {}", why))?;
+                    }
+                }
+            }
+            Frame::ChangePassword { old_password, new_password } => {
+                let Some(session) = &session else {
+                    info!("client {:?} tried to change a password before joining; ignoring", peer);
+                    continue;
+                };
+                change_password(stream, shared, session, &old_password, &new_password)?;
+            }
+            Frame::ResetRequest { username } => request_reset(stream, shared, &username)?,
+            Frame::ResetPassword { username, code, new_password } => {
+                reset_password(stream, shared, &username, &code, &new_password)?;
+            }
+            Frame::Register { email } => {
+                let Some(session) = &session else {
+                    info!("client {:?} registered before joining; ignoring", peer);
+                    continue;
+                };
+                register(stream, shared, session, &email)?;
+            }
+            Frame::Verify { code } => {
+                let Some(session) = &session else {
+                    info!("client {:?} sent a verification code before joining; ignoring", peer);
+                    continue;
+                };
+                verify(stream, shared, session, &code)?;
+            }
+            Frame::MarkRead { room_id, message_id } => {
+                let Some(session) = &session else {
+                    continue;
+                };
+                if !db.lock().unwrap().mark_read(session.user_id, room_id, message_id)? {
+                    continue;
+                }
+                // the user's other connections catch up too
+                let streams: Vec<Arc<TcpStream>> = server_state.lock().unwrap().user_streams(&session.uuid).into_iter()
+                    .filter(|other| !Arc::ptr_eq(other, stream))
+                    .collect();
+                send_to(message, streams, Frame::MarkRead { room_id, message_id })?;
+            }
+            Frame::Search { search_id, room_id, username, since, until, before_id, limit, query } => {
+                let Some(session) = &session else {
+                    info!("client {:?} searched before joining; ignoring", peer);
+                    continue;
+                };
+                let filter = SearchFilter { room_id, username, since, until, before_id };
+                let limit = limit.min(MAX_HISTORY_BATCH as u16);
+                let hits = db.lock().unwrap().search(session.user_id, &filter, &query, limit)?;
+                send_to(message, vec![stream.clone()], Frame::SearchResults { search_id, hits })?;
+            }
+            Frame::FileRequest { message_id } => {
+                let Some(session) = &session else {
+                    info!("client {:?} asked for a file before joining; ignoring", peer);
+                    continue;
+                };
+                send_file(stream, shared, session, message_id)?;
+            }
+            Frame::Capabilities { extensions } => {
+                info!("client {:?} understands extended types {:?}", peer, extensions);
+                if let Some(c) = server_state.lock().unwrap().connections.get_mut(&peer) {
+                    c.extensions = extensions;
+                }
+            }
+            Frame::Extended { extended_type, payload } => {
+                match extensions.dispatch(extended_type, &payload, &mut extension_context) {
+                    Ok(true) => (),
+                    // unknown extensions are skipped; the length prefix tells us where the next frame starts
+                    Ok(false) => info!("client {:?} sent unknown extended type {}; skipping it", peer, extended_type),
+                    Err(err) => {
+                        error!("protocol violation from {:?}: {}; closing connection", peer, err);
+                        return Ok(());
+                    }
+                }
+            }
+            Frame::Leave => {
+                info!("client {:?} sent LEAVE", peer);
+                // logging out; the token goes with it
+                if let Some(session) = &session {
+                    db.lock().unwrap().end_session(&session.token)?;
+                }
+                return Ok(());
+            }
+            other => {
+                error!(
+                    "protocol violation from {:?}: clients may not send message type {}; closing connection",
+                    peer, other.message_type()
                 );
+                return Ok(());
             }
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,47 @@
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use log::error;
+use rand::rngs::OsRng;
+
+use crate::Result;
+
+// shorter than this and it's not worth hashing
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Hash `password` with argon2 and a fresh salt, as a PHC string (which records the algorithm and its
+/// parameters, so a later change of defaults still verifies old hashes). Slow on purpose; don't hold
+/// any locks.
+pub fn hash(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default().hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| {
+            error!("couldn't hash a password: {}", err);
+        })
+}
+
+// checked against when an account has no hash, so refusing it takes as long as a wrong password
+const DUMMY_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$EpllpzJYTGC6aUnxHgFuLg$qd6pCs2+ow7gKGhMxZssMrlcvPXs1CErSPha/CkD5Fk";
+
+/// Like verify, for an account that may not exist or have a password: that's never a match, but takes
+/// just as long to find out
+pub fn verify_stored(password: &str, hash: Option<&str>) -> bool {
+    match hash {
+        Some(hash) => verify(password, hash),
+        None => {
+            verify(password, DUMMY_HASH);
+            false
+        }
+    }
+}
+
+/// Whether `password` is the one `hash` was made from. Also slow on purpose.
+pub fn verify(password: &str, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(err) => {
+            error!("stored password hash is malformed: {}", err);
+            false
+        }
+    }
+}
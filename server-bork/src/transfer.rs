@@ -0,0 +1,291 @@
+use log::error;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::db::StoredAttachment;
+
+// uploads one connection may have in flight at once
+const MAX_UPLOADS: usize = 4;
+
+/// A file a client is part way through sending, spooled to disk as it arrives
+struct Upload{
+    room_id:    u64,
+    offered:    StoredAttachment,
+    received:   u64,
+    hasher:     Sha256,
+    file:       File,
+    path:       PathBuf,
+}
+
+/// One connection's uploads in progress. Whatever hasn't been completed when it's dropped is thrown away.
+pub struct Uploads{
+    dir:        String,
+    max_size:   u64,
+    peer:       SocketAddr, // keeps different connections' part files apart
+    uploads:    BTreeMap<u64, Upload>,
+}
+
+impl Uploads{
+    pub fn new(dir: &str, max_size: u64, peer: SocketAddr) -> Uploads{
+        Uploads{
+            dir: String::from(dir),
+            max_size,
+            peer,
+            uploads: BTreeMap::new(),
+        }
+    }
+
+    /// Start receiving a file; Err says why we won't
+    pub fn offer(&mut self, transfer_id: u64, room_id: u64, offered: StoredAttachment) -> Result<(), &'static str> {
+        if offered.size > self.max_size {
+            return Err("the file is bigger than this server accepts");
+        }
+        if self.uploads.contains_key(&transfer_id) {
+            return Err("that transfer ID is already in use");
+        }
+        if self.uploads.len() >= MAX_UPLOADS {
+            return Err("too many uploads at once");
+        }
+        let path = PathBuf::from(&self.dir).join(format!("{}-{}.part", self.peer, transfer_id));
+        let file = File::create(&path).map_err(|err| {
+            error!("couldn't create {}: {}", path.display(), err);
+            "the server couldn't store it"
+        })?;
+        self.uploads.insert(transfer_id, Upload { room_id, offered, received: 0, hasher: Sha256::new(), file, path });
+        Ok(())
+    }
+
+    /// Append the next piece of a file. Chunks of transfers we aren't receiving (refused ones, say) are
+    /// dropped quietly; an Err means the transfer has been abandoned.
+    pub fn chunk(&mut self, transfer_id: u64, offset: u64, data: &[u8]) -> Result<(), &'static str> {
+        let Some(upload) = self.uploads.get_mut(&transfer_id) else {
+            return Ok(());
+        };
+        let outcome = if offset != upload.received {
+            Err("its chunks arrived out of order")
+        } else if upload.received + data.len() as u64 > upload.offered.size {
+            Err("it's bigger than offered")
+        } else {
+            upload.file.write_all(data).map_err(|err| {
+                error!("couldn't write {}: {}", upload.path.display(), err);
+                "the server couldn't store it"
+            })
+        };
+        match outcome {
+            Ok(()) => {
+                upload.hasher.update(data);
+                upload.received += data.len() as u64;
+                Ok(())
+            }
+            Err(why) => {
+                self.abandon(transfer_id);
+                Err(why)
+            }
+        }
+    }
+
+    /// Check a finished file against its offer and move it into place, named for its SHA-256. Returns
+    /// its room and description, or None for transfers we aren't receiving.
+    pub fn complete(&mut self, transfer_id: u64) -> Result<Option<(u64, StoredAttachment)>, &'static str> {
+        let Some(upload) = self.uploads.remove(&transfer_id) else {
+            return Ok(None);
+        };
+        let Upload { room_id, offered, received, hasher, file, path } = upload;
+        drop(file);
+        let outcome = if received != offered.size {
+            Err("it's smaller than offered")
+        } else if hasher.finalize()[..] != offered.sha256 {
+            Err("its SHA-256 doesn't match")
+        } else {
+            fs::rename(&path, blob_path(&self.dir, &offered.sha256)).map_err(|err| {
+                error!("couldn't move {} into place: {}", path.display(), err);
+                "the server couldn't store it"
+            })
+        };
+        match outcome {
+            Ok(()) => Ok(Some((room_id, offered))),
+            Err(why) => {
+                let _ = fs::remove_file(&path);
+                Err(why)
+            }
+        }
+    }
+
+    fn abandon(&mut self, transfer_id: u64) {
+        if let Some(upload) = self.uploads.remove(&transfer_id) {
+            let _ = fs::remove_file(&upload.path);
+        }
+    }
+}
+
+impl Drop for Uploads{
+    fn drop(&mut self) {
+        let transfers: Vec<u64> = self.uploads.keys().copied().collect();
+        for transfer_id in transfers {
+            self.abandon(transfer_id);
+        }
+    }
+}
+
+/// Where a stored file lives. Files are named for their content, so the same file uploaded twice is
+/// kept once.
+pub fn blob_path(dir: &str, sha256: &[u8; 32]) -> PathBuf {
+    let name: String = sha256.iter().map(|byte| format!("{:02x}", byte)).collect();
+    PathBuf::from(dir).join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    // a fresh, empty directory to spool into; removed when dropped
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new() -> Dir {
+            let dir = std::env::temp_dir().join(format!("borkbork-transfer-{}", Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            Dir(dir)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+
+        fn files(&self) -> Vec<String> {
+            let mut files: Vec<String> = fs::read_dir(&self.0).unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn uploads(dir: &Dir) -> Uploads {
+        Uploads::new(dir.path(), 64, "127.0.0.1:6556".parse().unwrap())
+    }
+
+    fn attachment(content: &[u8]) -> StoredAttachment {
+        StoredAttachment {
+            file_name: String::from("bone.txt"),
+            size: content.len() as u64,
+            sha256: Sha256::digest(content).into(),
+        }
+    }
+
+    fn part_files(dir: &Dir) -> usize {
+        dir.files().iter().filter(|name| name.ends_with(".part")).count()
+    }
+
+    #[test]
+    fn a_file_sent_in_order_is_stored_under_its_sha256() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        let offered = attachment(b"hello world");
+        uploads.offer(1, 5, attachment(b"hello world")).unwrap();
+        assert_eq!(part_files(&dir), 1);
+        uploads.chunk(1, 0, b"hello ").unwrap();
+        uploads.chunk(1, 6, b"world").unwrap();
+        let (room_id, stored) = uploads.complete(1).unwrap().unwrap();
+        assert_eq!((room_id, stored.sha256, stored.size), (5, offered.sha256, 11));
+        assert_eq!(fs::read(blob_path(dir.path(), &offered.sha256)).unwrap(), b"hello world");
+        assert_eq!(part_files(&dir), 0);
+    }
+
+    #[test]
+    fn offers_over_the_limits_are_refused() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        assert_eq!(uploads.offer(1, 1, attachment(&[0; 65])), Err("the file is bigger than this server accepts"));
+        uploads.offer(1, 1, attachment(&[0; 64])).unwrap();
+        assert_eq!(uploads.offer(1, 1, attachment(b"again")), Err("that transfer ID is already in use"));
+        assert_eq!(part_files(&dir), 1);
+    }
+
+    #[test]
+    fn only_so_many_uploads_at_once() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        for transfer_id in 0..MAX_UPLOADS as u64 {
+            uploads.offer(transfer_id, 1, attachment(b"")).unwrap();
+        }
+        assert_eq!(uploads.offer(99, 1, attachment(b"")), Err("too many uploads at once"));
+        assert_eq!(part_files(&dir), MAX_UPLOADS);
+        assert!(uploads.complete(0).unwrap().is_some());
+        uploads.offer(99, 1, attachment(b"")).unwrap();
+    }
+
+    #[test]
+    fn chunks_out_of_order_abandon_the_upload() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        uploads.offer(1, 1, attachment(b"hello world")).unwrap();
+        uploads.chunk(1, 0, b"hello ").unwrap();
+        assert_eq!(uploads.chunk(1, 5, b" world"), Err("its chunks arrived out of order"));
+        assert_eq!(part_files(&dir), 0);
+        // anything more for it is ignored
+        assert_eq!(uploads.chunk(1, 6, b"world"), Ok(()));
+        assert!(matches!(uploads.complete(1), Ok(None)));
+        assert!(dir.files().is_empty());
+    }
+
+    #[test]
+    fn chunks_past_the_offered_size_abandon_the_upload() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        uploads.offer(1, 1, attachment(b"hello")).unwrap();
+        assert_eq!(uploads.chunk(1, 0, b"hello!"), Err("it's bigger than offered"));
+        assert!(dir.files().is_empty());
+    }
+
+    #[test]
+    fn a_short_file_is_refused() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        uploads.offer(1, 1, attachment(b"hello")).unwrap();
+        uploads.chunk(1, 0, b"hell").unwrap();
+        assert_eq!(uploads.complete(1).err(), Some("it's smaller than offered"));
+        assert!(dir.files().is_empty());
+    }
+
+    #[test]
+    fn a_sha256_mismatch_is_refused() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        uploads.offer(1, 1, attachment(b"hello")).unwrap();
+        uploads.chunk(1, 0, b"jello").unwrap();
+        assert_eq!(uploads.complete(1).err(), Some("its SHA-256 doesn't match"));
+        assert!(dir.files().is_empty());
+    }
+
+    #[test]
+    fn unfinished_uploads_are_cleaned_up_when_dropped() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        uploads.offer(1, 1, attachment(b"hello")).unwrap();
+        uploads.offer(2, 1, attachment(b"world")).unwrap();
+        uploads.chunk(2, 0, b"wor").unwrap();
+        assert_eq!(part_files(&dir), 2);
+        drop(uploads);
+        assert!(dir.files().is_empty());
+    }
+
+    #[test]
+    fn transfers_nobody_offered_are_ignored() {
+        let dir = Dir::new();
+        let mut uploads = uploads(&dir);
+        assert_eq!(uploads.chunk(7, 0, b"hello"), Ok(()));
+        assert!(matches!(uploads.complete(7), Ok(None)));
+    }
+}